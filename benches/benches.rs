@@ -0,0 +1,431 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Benchmarks for the hot paths: scheduling work with `find_work` and reading and
+// hashing files. `fddup` is a binary, so the modules under test are included
+// directly, and not everything in them is used here. Run with `cargo bench`; pass
+// a name to only run matching benchmarks, e.g. `cargo bench -- find_work`.
+
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/algo.rs"]
+mod algo;
+#[path = "../src/platform.rs"]
+mod platform;
+#[path = "../src/possdupe.rs"]
+mod possdupe;
+#[path = "../src/stages.rs"]
+mod stages;
+#[path = "../src/text.rs"]
+mod text;
+
+use algo::{find_work, GetKey};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use possdupe::PossDupe;
+use stages::Stages;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::hint::black_box;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Files written for a group of benchmarks, removed again when dropped, even if a
+// benchmark panics.
+struct Files {
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl Files {
+    // Write `count` files, with the contents of each from `data(i)`.
+    fn new(name: &str, count: usize, data: impl Fn(usize) -> Vec<u8>) -> Files {
+        let root =
+            std::env::temp_dir().join(format!("fddup-bench-{}-{}", name, std::process::id()));
+        create_dir_all(&root).unwrap();
+
+        let paths = (0..count)
+            .map(|i| {
+                let path = root.join(i.to_string());
+                write(&path, data(i)).unwrap();
+                path
+            })
+            .collect();
+
+        Files { root, paths }
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
+
+// Time `iters` runs of `f`, which returns the time it spent on the work being
+// measured, so per-run setup can be excluded.
+fn timed(iters: u64, mut f: impl FnMut() -> Duration) -> Duration {
+    (0..iters).map(|_| f()).sum()
+}
+
+#[derive(Clone)]
+struct Item {
+    key: u64,
+    bytes_remaining: u64,
+}
+
+impl GetKey<u64> for Item {
+    fn key(&self) -> u64 {
+        self.key
+    }
+
+    fn bytes_remaining(&self) -> u64 {
+        self.bytes_remaining
+    }
+}
+
+// Schedule all items as the main loop does, a batch of `desired` at a time,
+// marking every scheduled item as fully read so each is scheduled once.
+fn schedule_all(mut items: Vec<Item>, desired: usize) -> Duration {
+    let start = Instant::now();
+
+    while !items.is_empty() {
+        let w = find_work(&mut items, desired);
+        black_box(&w.uniques);
+
+        let mut done: Vec<Item> = w
+            .work
+            .into_iter()
+            .map(|mut i| {
+                i.bytes_remaining = 0;
+                i
+            })
+            .collect();
+        black_box(find_work(&mut done, usize::MAX));
+    }
+
+    start.elapsed()
+}
+
+fn find_work_benches(c: &mut Criterion) {
+    const COUNT: u64 = 100_000;
+    const DESIRED: usize = 64 * 4;
+
+    let items = |key: &dyn Fn(u64) -> u64| -> Vec<Item> {
+        (0..COUNT)
+            .map(|i| Item {
+                key: key(i),
+                bytes_remaining: 1,
+            })
+            .collect()
+    };
+
+    let mut group = c.benchmark_group("find_work");
+
+    for (name, items) in [
+        ("all_unique", items(&|i| i)),
+        ("all_same_size", items(&|_| 1)),
+        ("clustered", items(&|i| i / 8)),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| timed(iters, || schedule_all(items.clone(), DESIRED)))
+        });
+    }
+
+    group.finish();
+}
+
+// Read every file in turn, one read of `read_size` bytes at a time, hashing as
+// `read_poss_dupe` does, until all files have been read in their entirety.
+fn read_all(paths: &[PathBuf], len: u64, read_size: usize) -> Duration {
+    let mut poss_dupes: Vec<PossDupe> = paths.iter().map(|p| PossDupe::new(p, len)).collect();
+    let mut buffer = vec![0; read_size];
+    let start = Instant::now();
+
+    while poss_dupes.iter().any(|pd| pd.bytes_remaining() > 0) {
+        for pd in poss_dupes.iter_mut() {
+            pd.open().unwrap();
+
+            let (_, to_read) = pd.next_read(read_size as u64);
+            let to_read = to_read as usize;
+
+            if let Some(file) = &mut pd.file {
+                file.read_exact(&mut buffer[..to_read]).unwrap();
+                pd.bytes_read += to_read as u64;
+                pd.update_digest(&buffer[..to_read]);
+            }
+        }
+    }
+
+    black_box(
+        poss_dupes
+            .iter()
+            .map(|pd| pd.digest_hex())
+            .collect::<Vec<_>>(),
+    );
+    start.elapsed()
+}
+
+fn read_benches(c: &mut Criterion) {
+    const FILES: usize = 16;
+    const LEN: usize = 4 * 1024 * 1024;
+
+    // Files are only written once a benchmark which reads them runs, so filtering
+    // out every read benchmark doesn't write them at all.
+    let mut files = None;
+    let mut group = c.benchmark_group(format!("read/{}x{}MiB", FILES, LEN >> 20));
+    group.sample_size(10);
+
+    for read_size in [64 * 1024, 512 * 1024, 4 * 1024 * 1024] {
+        let id = BenchmarkId::from_parameter(format!("{}KiB", read_size >> 10));
+
+        group.bench_function(id, |b| {
+            let files = files.get_or_insert_with(|| {
+                Files::new("read", FILES, |_| {
+                    (0..LEN).map(|i| (i % 251) as u8).collect()
+                })
+            });
+
+            b.iter_custom(|iters| timed(iters, || read_all(&files.paths, LEN as u64, read_size)))
+        });
+    }
+
+    group.finish();
+}
+
+// Hash the same buffer into a fresh digest per file, in updates of `block_size`
+// bytes, as `--hash-block-size` does for each read.
+fn hash_blocks(data: &[u8], files: usize, block_size: usize) {
+    for _ in 0..files {
+        let mut pd = PossDupe::new("a", data.len() as u64);
+        pd.update_digest_in_blocks(data, block_size);
+        black_box(pd.digest_hex());
+    }
+}
+
+fn hash_block_benches(c: &mut Criterion) {
+    const FILES: usize = 16;
+    const READ_SIZE: usize = 4 * 1024 * 1024;
+
+    let data: Vec<u8> = (0..READ_SIZE).map(|i| (i % 251) as u8).collect();
+    let mut group = c.benchmark_group(format!("hash_block/{}x{}MiB", FILES, READ_SIZE >> 20));
+    group.sample_size(10);
+
+    for block_size in [64, 4 * 1024, 64 * 1024, READ_SIZE] {
+        let id = BenchmarkId::from_parameter(format!("{}B", block_size));
+        group.bench_function(id, |b| b.iter(|| hash_blocks(&data, FILES, block_size)));
+    }
+
+    group.finish();
+}
+
+// Read files in rounds as the main loop does, each round reading the next chunk of
+// every file still possibly a duplicate, and dropping files once their digests
+// are unique. The first read is `first_read_size` bytes and later ones
+// `read_size`.
+fn read_rounds(paths: &[PathBuf], len: u64, first_read_size: u64, read_size: u64) -> Duration {
+    let mut pending: Vec<PossDupe> = paths.iter().map(|p| PossDupe::new(p, len)).collect();
+    let mut buffer = vec![0; first_read_size.max(read_size) as usize];
+    let start = Instant::now();
+
+    loop {
+        pending.sort_by_key(|pd| pd.key.digest_snapshot);
+        pending = find_work(&mut pending, usize::MAX).work;
+
+        if pending.is_empty() {
+            break;
+        }
+
+        for pd in pending.iter_mut() {
+            pd.open().unwrap();
+
+            let size = if pd.bytes_read == 0 {
+                first_read_size
+            } else {
+                read_size
+            };
+            let (_, to_read) = pd.next_read(size);
+            let to_read = to_read as usize;
+
+            if let Some(file) = &mut pd.file {
+                file.read_exact(&mut buffer[..to_read]).unwrap();
+                pd.bytes_read += to_read as u64;
+                pd.update_digest(&buffer[..to_read]);
+            }
+        }
+    }
+
+    start.elapsed()
+}
+
+// Many files of the same size which differ in their first byte, where a small
+// first read is enough to tell them all apart.
+fn first_read_benches(c: &mut Criterion) {
+    const FILES: usize = 64;
+    const LEN: usize = 1024 * 1024;
+    const READ_SIZE: u64 = 512 * 1024;
+
+    let mut files = None;
+    let mut group = c.benchmark_group(format!("first_read/{}x{}MiB", FILES, LEN >> 20));
+    group.sample_size(10);
+
+    for first_read_size in [4 * 1024, READ_SIZE] {
+        let id = BenchmarkId::from_parameter(format!("{}KiB", first_read_size >> 10));
+
+        group.bench_function(id, |b| {
+            let files = files.get_or_insert_with(|| {
+                Files::new("first", FILES, |i| {
+                    let mut data = vec![0u8; LEN];
+                    data[0] = i as u8;
+                    data
+                })
+            });
+
+            b.iter_custom(|iters| {
+                timed(iters, || {
+                    read_rounds(&files.paths, LEN as u64, first_read_size, READ_SIZE)
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// Read every file to the end on a runtime with `io_threads` worker threads, a task
+// per file, either hashing each chunk on the thread which read it, as `-j` does,
+// or on `hash_threads` threads of its own, as `--threads-io` and `--threads-hash`
+// do. Each read first waits for `latency`, standing in for storage which is slow
+// to respond, such as a network filesystem.
+fn read_in_tasks(
+    paths: &[PathBuf],
+    len: u64,
+    read_size: usize,
+    io_threads: usize,
+    hash_threads: Option<usize>,
+    latency: Duration,
+) -> Duration {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(io_threads)
+        .build()
+        .unwrap();
+    let stages = hash_threads.map(|hash_threads| Stages::new(io_threads, hash_threads));
+    let start = Instant::now();
+
+    runtime.block_on(async {
+        let tasks: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let mut pd = PossDupe::new(path, len);
+                let stages = stages.clone();
+
+                tokio::spawn(async move {
+                    let mut buffer = Vec::new();
+                    let read = |pd: &mut PossDupe, buffer: &mut Vec<u8>| {
+                        std::thread::sleep(latency);
+                        pd.open()?;
+                        pd.read_chunk(read_size, buffer)
+                    };
+
+                    while pd.bytes_remaining() > 0 {
+                        match &stages {
+                            Some(stages) => {
+                                let (next, result) = stages.read_next(pd, None, read).await;
+                                result.unwrap();
+                                pd = next;
+                            }
+                            None => {
+                                let n = read(&mut pd, &mut buffer).unwrap();
+                                pd.update_digest(&buffer[..n]);
+                            }
+                        }
+                    }
+
+                    black_box(pd.digest_hex());
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+
+    start.elapsed()
+}
+
+fn stages_benches(c: &mut Criterion) {
+    const FILES: usize = 32;
+    const LEN: usize = 1024 * 1024;
+    const READ_SIZE: usize = 256 * 1024;
+
+    let mut files = None;
+
+    for latency_ms in [0, 2] {
+        let mut group = c.benchmark_group(format!(
+            "stages/{}x{}MiB/latency-{}ms",
+            FILES,
+            LEN >> 20,
+            latency_ms
+        ));
+        group.sample_size(10);
+
+        // (I/O threads, hashing threads), with no hashing threads for a single stage.
+        for (io, hash) in [
+            (1, None),
+            (4, None),
+            (16, None),
+            (4, Some(1)),
+            (16, Some(1)),
+        ] {
+            let id = match hash {
+                None => format!("j{}", io),
+                Some(hash) => format!("io{}-hash{}", io, hash),
+            };
+
+            group.bench_function(BenchmarkId::from_parameter(id), |b| {
+                let files = files.get_or_insert_with(|| {
+                    Files::new("stages", FILES, |_| {
+                        (0..LEN).map(|i| (i % 251) as u8).collect()
+                    })
+                });
+                let latency = Duration::from_millis(latency_ms);
+
+                b.iter_custom(|iters| {
+                    timed(iters, || {
+                        read_in_tasks(&files.paths, LEN as u64, READ_SIZE, io, hash, latency)
+                    })
+                })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(
+    benches,
+    find_work_benches,
+    read_benches,
+    hash_block_benches,
+    first_read_benches,
+    stages_benches
+);
+criterion_main!(benches);
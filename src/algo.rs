@@ -30,16 +30,44 @@ pub struct Work<T> {
     pub uniques: Vec<T>,
 }
 
+// Take items from the end of `possible`, which must be sorted by key, and sort
+// them into work which still needs reading, confirmed duplicates, and uniques,
+// stopping once about `desired` items of work have been found. All items sharing
+// a key are always taken together, so a group is never split across batches.
+//
+// Correctness rests on an invariant of the keys: items with equal keys have had
+// the same number of bytes read, since items of the same length follow the same
+// schedule of reads and are always read together. Digests are computed
+// incrementally from the start of each item, so equal keys mean equal prefixes,
+// barring a collision in the digest itself. An item is only a duplicate once
+// nothing remains to be read and its key still matches another's. Items which
+// differ only in their final read stay in `work` until that read, after which
+// their keys differ and they're unique.
 pub fn find_work<T, K>(possible: &mut Vec<T>, desired: usize) -> Work<T>
 where
     T: GetKey<K>,
     K: PartialEq + Clone,
+{
+    let mut uniques = Vec::new();
+    let mut w = find_work_with(possible, desired, |unique| uniques.push(unique));
+    w.uniques = uniques;
+    w
+}
+
+// Like `find_work`, but each unique item is passed to `on_unique` as soon as it's
+// found rather than collected, and the returned `uniques` is always empty. When
+// uniques only need to be counted, they can be dropped right away instead of
+// being held until the whole batch has been scheduled.
+pub fn find_work_with<T, K, F>(possible: &mut Vec<T>, desired: usize, mut on_unique: F) -> Work<T>
+where
+    T: GetKey<K>,
+    K: PartialEq + Clone,
+    F: FnMut(T),
 {
     let mut last_key: Option<K> = None;
     let mut remaining = desired;
     let mut work = Vec::new();
     let mut duplicates = Vec::new();
-    let mut uniques = Vec::new();
 
     while let Some(item) = possible.pop() {
         let last_key_matches = match &last_key {
@@ -70,20 +98,22 @@ where
                 remaining = remaining.saturating_sub(1);
             }
         } else {
-            uniques.push(item);
+            on_unique(item);
         }
     }
 
     Work {
         work,
         duplicates,
-        uniques,
+        uniques: Vec::new(),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
 
     #[derive(Debug, Eq, PartialEq)]
     struct TestWork {
@@ -186,6 +216,19 @@ mod tests {
         assert_eq!(w.uniques, vec![w_10(5), w_10(4), w_10(2), w_10(1)]);
     }
 
+    #[test]
+    fn find_work_with_streams_uniques() {
+        let mut input = vec![w_10(1), w_10(2), w_10(3), w_10(3), w_0(4), w_0(4), w_10(5)];
+        let mut uniques = Vec::new();
+        let w = find_work_with(&mut input, 4, |unique| uniques.push(unique));
+
+        assert_eq!(input, vec![]);
+        assert_eq!(w.work, vec![w_10(3), w_10(3)]);
+        assert_eq!(w.duplicates, vec![w_0(4), w_0(4)]);
+        assert_eq!(w.uniques, vec![]);
+        assert_eq!(uniques, vec![w_10(5), w_10(2), w_10(1)]);
+    }
+
     #[test]
     fn find_work_some_work_and_some_duplicates_found() {
         let mut input = vec![w_10(1), w_10(2), w_10(3), w_10(3), w_0(4), w_0(4), w_10(5)];
@@ -254,4 +297,152 @@ mod tests {
         assert_eq!(w.duplicates, vec![]);
         assert_eq!(w.uniques, vec![w_10(2)]);
     }
+
+    // An item for property tests. Its serial number tells apart items sharing a
+    // key, so it can be checked that each item comes out exactly once.
+    #[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+    struct Item {
+        key: u32,
+        bytes_remaining: u64,
+        serial: usize,
+    }
+
+    impl GetKey<u32> for Item {
+        fn key(&self) -> u32 {
+            self.key
+        }
+
+        fn bytes_remaining(&self) -> u64 {
+            self.bytes_remaining
+        }
+    }
+
+    // Generate items sorted by key. Items sharing a key have had the same number of
+    // bytes read, so they also share `bytes_remaining`, as `find_work` requires.
+    fn items() -> impl Strategy<Value = Vec<Item>> {
+        (0..40u32)
+            .prop_flat_map(|len| vec(0..len / 2 + 1, len as usize))
+            .prop_map(|mut keys| {
+                keys.sort_unstable();
+
+                keys.into_iter()
+                    .enumerate()
+                    .map(|(serial, key)| Item {
+                        key,
+                        bytes_remaining: if key % 3 == 0 { 0 } else { 10 },
+                        serial,
+                    })
+                    .collect()
+            })
+    }
+
+    // Batch sizes to ask for, in turn, until nothing is left.
+    fn batch_sizes() -> impl Strategy<Value = Vec<usize>> {
+        vec(1..=10usize, 1..10)
+    }
+
+    // Sort items by key as `sort_poss_dupes` does: stably, so items sharing a key
+    // keep their order.
+    fn sort_by_key(items: &mut [Item]) {
+        items.sort_by_key(|item| item.key);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn find_work_properties(input in items(), batch_sizes in batch_sizes()) {
+            let count = |key| input.iter().filter(|i| i.key == key).count();
+            let mut possible = input.clone();
+            let mut seen = Vec::new();
+
+            // Take batches until nothing is left, as `run_impl` does.
+            for &desired in batch_sizes.iter().cycle() {
+                if possible.is_empty() {
+                    break;
+                }
+
+                let before = possible.len();
+                let w = find_work(&mut possible, desired);
+
+                prop_assert!(possible.len() < before, "no progress");
+                prop_assert!(possible.is_empty() || w.work.len() >= desired);
+
+                for item in &w.work {
+                    prop_assert!(item.bytes_remaining > 0);
+                    prop_assert!(count(item.key) > 1, "work without a match: {:?}", item);
+                }
+
+                for item in &w.duplicates {
+                    prop_assert_eq!(item.bytes_remaining, 0);
+                    prop_assert!(count(item.key) > 1, "duplicate without a match: {:?}", item);
+                }
+
+                for item in &w.uniques {
+                    prop_assert_eq!(count(item.key), 1, "unique with a match: {:?}", item);
+                }
+
+                // Groups are never split, so nothing taken shares a key with
+                // anything left behind.
+                let taken = w.work.iter().chain(&w.duplicates).chain(&w.uniques);
+
+                for item in taken {
+                    prop_assert!(possible.iter().all(|left| left.key != item.key));
+                    seen.push(*item);
+                }
+            }
+
+            seen.sort_unstable();
+            prop_assert_eq!(seen, input);
+        }
+
+        #[test]
+        fn leftovers_stay_sorted(input in items(), batch_sizes in batch_sizes()) {
+            let mut possible = input;
+
+            for &desired in batch_sizes.iter().cycle() {
+                if possible.is_empty() {
+                    break;
+                }
+
+                find_work(&mut possible, desired);
+
+                // What's left is still sorted, so sorting it again changes nothing
+                // and it can be fed straight back in.
+                let mut resorted = possible.clone();
+                sort_by_key(&mut resorted);
+                prop_assert_eq!(resorted, possible.clone());
+            }
+        }
+
+        // Pausing after a batch, before its work is read, and then resuming with
+        // the unread work put back must classify every item as an uninterrupted
+        // run would, losing or repeating none.
+        #[test]
+        fn pausing_and_resuming_loses_nothing(input in items(), desired in 1..=10usize) {
+            let mut uninterrupted = input.clone();
+            let expected = find_work(&mut uninterrupted, usize::MAX);
+
+            let mut possible = input;
+            let before_pause = find_work(&mut possible, desired);
+
+            possible.extend(before_pause.work);
+            sort_by_key(&mut possible);
+            let after_resume = find_work(&mut possible, usize::MAX);
+            prop_assert!(possible.is_empty());
+
+            let sorted = |items: Vec<Item>| {
+                let mut items = items;
+                items.sort_unstable();
+                items
+            };
+
+            let duplicates = [before_pause.duplicates, after_resume.duplicates].concat();
+            let uniques = [before_pause.uniques, after_resume.uniques].concat();
+
+            prop_assert_eq!(sorted(after_resume.work), sorted(expected.work));
+            prop_assert_eq!(sorted(duplicates), sorted(expected.duplicates));
+            prop_assert_eq!(sorted(uniques), sorted(expected.uniques));
+        }
+    }
 }
@@ -0,0 +1,362 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A Bloom filter of the files seen in a run, for `--bloom FILE`, so the next run
+// can skip reading files which were unique then and still must be: those whose
+// size, and name with `--same-name`, no new or changed file has since. A file's
+// contents aren't known without reading it, so files are known by their path,
+// size, modification time and file id, which change whenever the contents do.
+// False positives can make a new or changed file look like one seen before,
+// hiding a duplicate, so the filter is sized to make them very unlikely.
+
+use crate::cli::Options;
+use crate::possdupe::PossDupe;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Bits per key and number of hash functions, for a false positive rate of about
+// one in a million for each file looked up
+const BITS_PER_KEY: usize = 29;
+const NUM_HASHES: u32 = 20;
+
+// Fewest keys a filter has room for. A filter of only a few words is filled by
+// the first few keys, and then mistakes files it has never seen for ones it has
+// far more often than the rate above.
+const MIN_KEYS: usize = 64;
+
+// First line of a filter file, followed by the number of hash functions, the
+// number of 64-bit words of bits, and the comparison settings
+const HEADER: &str = "fddup bloom 1";
+
+pub struct Bloom {
+    // Options which change which files are unique; a filter written with other
+    // settings says nothing about this run
+    settings: String,
+    num_hashes: u32,
+    words: Vec<u64>,
+}
+
+// The comparison settings of a run: every option which can change whether a
+// file is found to be unique.
+pub fn comparison_settings(options: &Options) -> String {
+    format!(
+        "digest-bits={} skip-header={} same-name={} symlink-mode={:?} sample={:?} normalize-text={} normalize-max-size={} decompress={}",
+        options.digest_bits,
+        options.skip_header,
+        options.same_name,
+        options.symlink_mode,
+        options.sample,
+        options.normalize_text.join(","),
+        options.normalize_max_size,
+        options.decompress
+    )
+    .replace('\n', " ")
+}
+
+// How a file is known: what it is and whether it was unique, then its path, size,
+// modification time and file id.
+fn file_key(pd: &PossDupe, unique: bool) -> Vec<u8> {
+    let mut key = Vec::new();
+    key.extend_from_slice(if unique { b"unique\0" } else { b"seen\0" });
+    key.extend_from_slice(&crate::platform::path_bytes(&pd.path));
+    key.push(0);
+    key.extend_from_slice(&pd.file_len.to_le_bytes());
+
+    if let Some(since) = pd.mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        key.extend_from_slice(&since.as_secs().to_le_bytes());
+        key.extend_from_slice(&since.subsec_nanos().to_le_bytes());
+    }
+
+    if let Some((dev, ino)) = pd.file_id {
+        key.extend_from_slice(&dev.to_le_bytes());
+        key.extend_from_slice(&ino.to_le_bytes());
+    }
+
+    key
+}
+
+impl Bloom {
+    // An empty filter with room for `files` files. A file takes two keys if it was
+    // unique, one for being seen and one for being unique.
+    pub fn new(files: usize, settings: String) -> Bloom {
+        let keys = files.saturating_mul(2).max(MIN_KEYS);
+        let bits = keys.saturating_mul(BITS_PER_KEY);
+
+        Bloom {
+            settings,
+            num_hashes: NUM_HASHES,
+            words: vec![0; bits.div_ceil(64)],
+        }
+    }
+
+    // Bits set for a key, by double hashing two halves of its SHA-256 digest.
+    fn bits(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Sha256::digest(key);
+        let half = |i: usize| u64::from_le_bytes(digest[i..i + 8].try_into().unwrap());
+        let (h1, h2) = (half(0), half(8) | 1);
+        let num_bits = self.words.len() as u64 * 64;
+
+        (0..u64::from(self.num_hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for bit in self.bits(key).collect::<Vec<_>>() {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.bits(key)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // Record that a file was seen, found to be unique or a duplicate, or with
+    // `unique`, that it was unique.
+    pub fn insert_file(&mut self, pd: &PossDupe, unique: bool) {
+        self.insert(&file_key(pd, unique));
+    }
+
+    // Split files sharing their size with another file, ordered by size as
+    // `bucket_by_size` returns them, into those which must still be unique and
+    // the rest. A bucket is only unchanged if every file in it was seen before,
+    // in which case the files unique then are unique now. A file left on its own
+    // once they're removed can't have a duplicate either.
+    pub fn split_known_uniques(&self, shared: Vec<PossDupe>) -> (Vec<PossDupe>, Vec<PossDupe>) {
+        let mut buckets: Vec<Vec<PossDupe>> = Vec::new();
+
+        for pd in shared {
+            match buckets.last_mut() {
+                Some(bucket) if bucket[0].key.bucket() == pd.key.bucket() => bucket.push(pd),
+                _ => buckets.push(vec![pd]),
+            }
+        }
+
+        let mut uniques = Vec::new();
+        let mut rest = Vec::new();
+
+        for bucket in buckets {
+            if !bucket.iter().all(|pd| self.contains(&file_key(pd, false))) {
+                rest.extend(bucket);
+                continue;
+            }
+
+            let (known, others): (Vec<_>, Vec<_>) = bucket
+                .into_iter()
+                .partition(|pd| self.contains(&file_key(pd, true)));
+            uniques.extend(known);
+
+            if others.len() == 1 {
+                uniques.extend(others);
+            } else {
+                rest.extend(others);
+            }
+        }
+
+        (uniques, rest)
+    }
+
+    // Read the filter written by an earlier run, if there is one. A filter written
+    // with other comparison settings is of no use, so it's as if there were none.
+    pub fn load(path: &Path, settings: &str) -> Result<Option<Bloom>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("couldn't open bloom filter {}", path.display()))
+            }
+        };
+        let invalid = || anyhow!("{} isn't a bloom filter written by fddup", path.display());
+
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader
+            .read_line(&mut header)
+            .with_context(|| format!("couldn't read bloom filter {}", path.display()))?;
+
+        let mut fields = header
+            .trim_end_matches('\n')
+            .strip_prefix(HEADER)
+            .and_then(|rest| rest.strip_prefix(' '))
+            .ok_or_else(invalid)?
+            .splitn(3, ' ');
+        let mut number = || -> Result<usize> {
+            match fields.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => Ok(n),
+                _ => Err(invalid()),
+            }
+        };
+        let num_hashes = number()? as u32;
+        let num_words = number()?;
+
+        if fields.next() != Some(settings) {
+            return Ok(None);
+        }
+
+        let mut bytes = vec![0; num_words.checked_mul(8).ok_or_else(invalid)?];
+        reader
+            .read_exact(&mut bytes)
+            .with_context(|| format!("couldn't read bloom filter {}", path.display()))?;
+
+        Ok(Some(Bloom {
+            settings: String::from(settings),
+            num_hashes,
+            words: bytes
+                .chunks_exact(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+        }))
+    }
+
+    // Write the filter for the next run, replacing the last one atomically, so an
+    // interrupted write never leaves a partial filter behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut contents = format!(
+            "{} {} {} {}\n",
+            HEADER,
+            self.num_hashes,
+            self.words.len(),
+            self.settings
+        )
+        .into_bytes();
+
+        for word in self.words.iter() {
+            contents.extend_from_slice(&word.to_le_bytes());
+        }
+
+        File::create(&temp_path)
+            .and_then(|mut file| file.write_all(&contents))
+            .and_then(|_| fs::rename(&temp_path, path))
+            .with_context(|| format!("couldn't write bloom filter {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_pd(path: &str, len: u64) -> PossDupe {
+        let mut pd = PossDupe::new(path, len);
+        pd.file_id = Some((1, len));
+        pd
+    }
+
+    fn paths(pds: &[PossDupe]) -> Vec<&str> {
+        pds.iter().map(|pd| pd.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn unchanged_buckets_skip_their_uniques() {
+        let mut bloom = Bloom::new(5, String::new());
+
+        // Last time, a and b were duplicates and c was unique; d and e were unique.
+        for (path, len, unique) in [
+            ("a", 10, false),
+            ("b", 10, false),
+            ("c", 10, true),
+            ("d", 20, true),
+            ("e", 20, true),
+        ] {
+            bloom.insert_file(&mk_pd(path, len), false);
+
+            if unique {
+                bloom.insert_file(&mk_pd(path, len), true);
+            }
+        }
+
+        // Now, f is new and shares a size with d and e.
+        let shared = vec![
+            mk_pd("a", 10),
+            mk_pd("b", 10),
+            mk_pd("c", 10),
+            mk_pd("d", 20),
+            mk_pd("e", 20),
+            mk_pd("f", 20),
+        ];
+        let (uniques, rest) = bloom.split_known_uniques(shared);
+
+        assert_eq!(paths(&uniques), ["c"]);
+        assert_eq!(paths(&rest), ["a", "b", "d", "e", "f"]);
+
+        // A file changed since isn't the file seen before.
+        let mut changed = mk_pd("a", 10);
+        changed.file_id = Some((1, 11));
+        let (uniques, rest) = bloom.split_known_uniques(vec![changed, mk_pd("c", 10)]);
+        assert!(uniques.is_empty());
+        assert_eq!(rest.len(), 2);
+
+        // Without c's duplicate, a is left on its own.
+        let (uniques, rest) = bloom.split_known_uniques(vec![mk_pd("a", 10), mk_pd("c", 10)]);
+        assert_eq!(paths(&uniques), ["c", "a"]);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn small_filters_keep_their_false_positive_rate() {
+        let mut bloom = Bloom::new(4, String::new());
+
+        for path in ["a", "b", "c", "d"] {
+            bloom.insert_file(&mk_pd(path, 4), false);
+            bloom.insert_file(&mk_pd(path, 4), true);
+        }
+
+        let mistaken = (0..10_000)
+            .filter(|i| bloom.contains(&file_key(&mk_pd(&format!("new{}", i), 4), true)))
+            .count();
+        assert_eq!(mistaken, 0);
+    }
+
+    #[test]
+    fn filters_are_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let path = root.join("bloom");
+
+        assert!(Bloom::load(&path, "s").unwrap().is_none());
+
+        let mut bloom = Bloom::new(100, String::from("s"));
+        bloom.insert_file(&mk_pd("a", 10), true);
+        bloom.save(&path).unwrap();
+
+        let loaded = Bloom::load(&path, "s").unwrap().unwrap();
+        assert!(loaded.contains(&file_key(&mk_pd("a", 10), true)));
+        assert!(!loaded.contains(&file_key(&mk_pd("b", 10), true)));
+        assert_eq!(loaded.words, bloom.words);
+
+        // Other settings make for other results, so the filter isn't used.
+        assert!(Bloom::load(&path, "t").unwrap().is_none());
+
+        fs::write(&path, "not a filter\n").unwrap();
+        assert!(Bloom::load(&path, "s").is_err());
+    }
+}
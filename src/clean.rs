@@ -0,0 +1,252 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Cleaning up duplicates with `fddup clean`: every duplicate but the file kept,
+// listed first in its group, is deleted or replaced with a hard link to the file
+// kept. Groups are always verified byte for byte before this, and each file is
+// checked again just before it's touched, so a file which changed after being
+// read is left alone.
+
+use crate::possdupe::PossDupe;
+
+use humansize::{file_size_opts, FileSize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// What to do with each duplicate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Delete,
+    Hardlink,
+}
+
+// Counts of files cleaned up, for a summary at the end.
+pub struct Cleaner {
+    action: Action,
+    dry_run: bool,
+    files_cleaned: usize,
+    bytes_reclaimed: u64,
+    files_changed: usize,
+    files_failed: usize,
+}
+
+impl Cleaner {
+    pub fn new(action: Action, dry_run: bool) -> Cleaner {
+        Cleaner {
+            action,
+            dry_run,
+            files_cleaned: 0,
+            bytes_reclaimed: 0,
+            files_changed: 0,
+            files_failed: 0,
+        }
+    }
+
+    // Clean up every file in a group but the file to keep, listed first. Failures
+    // are reported and counted, and the remaining files are still tried. Links and
+    // archive members have no contents of their own, so groups with them are
+    // left alone.
+    pub fn clean_group(&mut self, group: &[PossDupe]) {
+        let keep = match group.first() {
+            Some(keep) if keep.link_target.is_none() && keep.archive.is_none() => keep,
+            _ => return,
+        };
+
+        if !unchanged(keep) {
+            self.files_changed += group.len() - 1;
+            eprintln!(
+                "warning: not cleaned, file to keep changed since being read: {}",
+                keep.path.display()
+            );
+            return;
+        }
+
+        for pd in group[1..].iter() {
+            if pd.link_target.is_some() || pd.archive.is_some() {
+                continue;
+            }
+
+            if !unchanged(pd) {
+                self.files_changed += 1;
+                eprintln!(
+                    "warning: not cleaned, contents changed since being read: {}",
+                    pd.path.display()
+                );
+                continue;
+            }
+
+            if self.dry_run {
+                match self.action {
+                    Action::Delete => eprintln!("would delete {}", pd.path.display()),
+                    Action::Hardlink => eprintln!(
+                        "would hard link {} to {}",
+                        pd.path.display(),
+                        keep.path.display()
+                    ),
+                }
+            } else {
+                let result = match self.action {
+                    Action::Delete => fs::remove_file(crate::platform::long_path(&pd.path)),
+                    Action::Hardlink => replace_with_link(&keep.path, &pd.path),
+                };
+
+                if let Err(e) = result {
+                    self.files_failed += 1;
+                    eprintln!("warning: couldn't clean up {}: {}", pd.path.display(), e);
+                    continue;
+                }
+            }
+
+            self.files_cleaned += 1;
+            self.bytes_reclaimed += pd.allocated_len.unwrap_or(pd.file_len);
+        }
+    }
+
+    // Number of files which couldn't be deleted or replaced with a link.
+    pub fn num_failed(&self) -> usize {
+        self.files_failed
+    }
+
+    pub fn summary(&self) -> String {
+        let bytes = self
+            .bytes_reclaimed
+            .file_size(file_size_opts::BINARY)
+            .unwrap_or_else(|_| format!("{} B", self.bytes_reclaimed));
+        let verb = match (self.action, self.dry_run) {
+            (Action::Delete, false) => "deleted",
+            (Action::Delete, true) => "would delete",
+            (Action::Hardlink, false) => "hard linked",
+            (Action::Hardlink, true) => "would hard link",
+        };
+
+        format!(
+            "{} {} files reclaiming {}; {} changed, {} failed",
+            verb, self.files_cleaned, bytes, self.files_changed, self.files_failed
+        )
+    }
+}
+
+// Return true if a file still has the length and modification time it had when
+// it was read. Contents are compared when verifying, just before cleaning up.
+fn unchanged(pd: &PossDupe) -> bool {
+    match fs::symlink_metadata(crate::platform::long_path(&pd.path)) {
+        Ok(attr) => attr.len() == pd.file_len && attr.modified().ok() == pd.mtime,
+        Err(_) => false,
+    }
+}
+
+// Replace `dest` with a hard link to `src`. The link is made under a temporary
+// name beside `dest` and renamed over it, so `dest` is never missing, even if
+// linking fails, such as across filesystems.
+fn replace_with_link(src: &Path, dest: &Path) -> io::Result<()> {
+    let temp = temp_path(dest);
+    fs::hard_link(
+        crate::platform::long_path(src),
+        crate::platform::long_path(&temp),
+    )?;
+
+    fs::rename(
+        crate::platform::long_path(&temp),
+        crate::platform::long_path(dest),
+    )
+    .inspect_err(|_| {
+        let _ = fs::remove_file(crate::platform::long_path(&temp));
+    })
+}
+
+// Temporary name beside `path` for a link which is about to replace it.
+fn temp_path(path: &Path) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let mut name = std::ffi::OsString::from(".fddup-");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.{}", std::process::id(), nanos));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write};
+
+    fn stat(path: PathBuf) -> PossDupe {
+        let attr = fs::metadata(&path).unwrap();
+        let mut pd = PossDupe::new(path, attr.len());
+        pd.mtime = attr.modified().ok();
+        pd
+    }
+
+    #[test]
+    fn deletes_all_but_the_file_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        for name in ["a", "b", "c"] {
+            write(root.join(name), "same").unwrap();
+        }
+
+        let group: Vec<_> = ["a", "b", "c"].iter().map(|n| stat(root.join(n))).collect();
+
+        let mut dry_run = Cleaner::new(Action::Delete, true);
+        dry_run.clean_group(&group);
+        assert_eq!(dry_run.files_cleaned, 2);
+        assert!(root.join("b").exists());
+
+        let mut cleaner = Cleaner::new(Action::Delete, false);
+        cleaner.clean_group(&group);
+        assert_eq!(
+            cleaner.summary(),
+            "deleted 2 files reclaiming 8 B; 0 changed, 0 failed"
+        );
+        assert!(root.join("a").exists());
+        assert!(!root.join("b").exists());
+        assert!(!root.join("c").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_duplicates_which_are_unchanged() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        for name in ["a", "b", "c"] {
+            write(root.join(name), "same").unwrap();
+        }
+
+        let group: Vec<_> = ["a", "b", "c"].iter().map(|n| stat(root.join(n))).collect();
+        write(root.join("c"), "changed").unwrap();
+
+        let mut cleaner = Cleaner::new(Action::Hardlink, false);
+        cleaner.clean_group(&group);
+
+        let ino = |name| fs::metadata(root.join(name)).unwrap().ino();
+        assert_eq!(ino("a"), ino("b"));
+        assert_ne!(ino("a"), ino("c"));
+        assert_eq!(read(root.join("c")).unwrap(), b"changed");
+        assert_eq!(cleaner.files_changed, 1);
+        assert_eq!(fs::read_dir(root).unwrap().count(), 3);
+    }
+}
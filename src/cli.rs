@@ -19,152 +19,1881 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use anyhow::{Context, Result};
-use clap::{App, Arg};
-use std::fs::File;
+use anyhow::{bail, Context, Result};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, ErrorKind, Shell, SubCommand};
+use std::ffi::OsString;
+use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-pub const MAX_READ_BUFFER_SIZE: usize = 512 * 1024;
+pub const DEFAULT_READ_SIZE: usize = 512 * 1024;
+
+// Largest read size allowed; each thread allocates a buffer of the read size.
+pub const MAX_READ_SIZE: usize = 64 * 1024 * 1024;
+
+// Formats of the list of files, for --input-format.
+const INPUT_FORMATS: [&str; 3] = ["lines", "json", "ls"];
+
+// Actions a script written with --emit-script can take.
+const SCRIPT_ACTIONS: [&str; 3] = ["hardlink", "delete", "symlink"];
+
+// Ways duplicates can be listed: by digest and path, the default, or as the options
+// which change that.
+const OUTPUT_FORMATS: [&str; 7] = [
+    "digests",
+    "group",
+    "report",
+    "print-redundant",
+    "count-only",
+    "size-only",
+    "print0",
+];
+
+// When to colorize output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+pub enum Accounting {
+    Logical,
+    Allocated,
+}
+
+// What to do when a file can't be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorAction {
+    Skip,
+    Abort,
+}
+
+pub enum InputFormat {
+    Lines,
+    Json,
+    Listing,
+}
+
+// What to do with symlinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkMode {
+    Skip,
+    Follow,
+    Target,
+}
 
 pub struct Options {
     pub files: Option<String>,
+    pub input_format: InputFormat,
     pub output: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub output_mode: Option<u32>,
+    pub append: bool,
     pub skip_empty: bool,
-    pub verbose: bool,
+    pub verbosity: usize,
     pub show_size: bool,
+    pub show_bytes_read: bool,
+    pub show_file_id: bool,
+    pub group_id: bool,
+    pub field_sep: String,
     pub read_size: usize,
+    pub first_read_size: usize,
+    pub hash_block_size: Option<usize>,
+    pub skip_header: u64,
+    pub max_read_bytes: Option<u64>,
+    pub deadline: Option<Duration>,
+    pub large_file_threshold: Option<u64>,
     pub num_threads: usize,
+    pub single_thread: bool,
+    pub hash_threads: Option<usize>,
+    pub keep: Vec<crate::keep::Rule>,
+    pub recursive: bool,
+    pub symlink_mode: SymlinkMode,
+    pub follow_input_symlinks: bool,
+    pub prune_dirs: Vec<OsString>,
+    pub no_hidden: bool,
+    pub one_filesystem: bool,
+    pub expand_tar: bool,
+    pub digest_bits: usize,
+    pub verify: bool,
+    pub full_hash: bool,
+    pub collision_estimate: bool,
+    pub strip_prefixes: Vec<PathBuf>,
+    pub first_listed: bool,
+    pub roots: Vec<crate::roots::Root>,
+    pub batch_multiplier: usize,
+    pub list_skipped: Option<String>,
+    pub report_incomplete: Option<String>,
+    pub error_log: Option<String>,
+    pub write_manifest: Option<String>,
+    pub bloom: Option<PathBuf>,
+    pub size_index: Option<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub plan: bool,
+    pub size_only: bool,
+    pub reflink: bool,
+    pub clean: Option<crate::clean::Action>,
+    pub dry_run: bool,
+    pub emit_script: Option<(crate::script::ScriptAction, String)>,
+    pub size_tolerance: Option<f64>,
+    pub group: bool,
+    pub mark_keep: bool,
+    pub max_group_size: Option<usize>,
+    pub min_copies: usize,
+    pub exclude_same_dir: bool,
+    pub same_name: bool,
+    pub color: Color,
+    pub sample: Option<u64>,
+    pub normalize_text: Vec<String>,
+    pub decompress: bool,
+    pub magic: Vec<crate::magic::Kind>,
+    pub filter_cmd: Option<String>,
+    pub normalize_max_size: u64,
+    pub count_only: bool,
+    pub print_redundant: bool,
+    pub report: bool,
+    pub print0: bool,
+    pub accounting: Accounting,
+    pub on_permission_denied: ErrorAction,
+    pub on_not_found: ErrorAction,
+    pub on_invalid: ErrorAction,
+    pub separate_empty: bool,
+    pub stream: bool,
+    pub timing: bool,
+    pub newer_than: Option<SystemTime>,
+    pub older_than: Option<SystemTime>,
 }
 
+const ENV_THREADS: &str = "FDDUP_THREADS";
+
 const OPTION_FILES: &str = "files";
+const OPTION_INPUT_FORMAT: &str = "input-format";
 const OPTION_OUTPUT: &str = "output";
+const OPTION_OUTPUT_DIR: &str = "output-dir";
+const OPTION_OUTPUT_MODE: &str = "output-mode";
+const OPTION_APPEND: &str = "append";
 const OPTION_SKIP_EMPTY: &str = "skip-empty";
 const OPTION_VERBOSE: &str = "verbose";
 const OPTION_SHOW_SIZE: &str = "show-size";
+const OPTION_SHOW_BYTES_READ: &str = "show-bytes-read";
+const OPTION_SHOW_FILE_ID: &str = "show-file-id";
+const OPTION_GROUP_ID: &str = "group-id";
+const OPTION_FIELD_SEP: &str = "field-sep";
 const OPTION_READ_SIZE: &str = "read-size";
+const OPTION_FIRST_READ_SIZE: &str = "first-read-size";
+const OPTION_HASH_BLOCK_SIZE: &str = "hash-block-size";
+const OPTION_SKIP_HEADER: &str = "skip-header";
+const OPTION_MAX_READ_BYTES: &str = "max-read-bytes";
+const OPTION_DEADLINE: &str = "deadline";
+const OPTION_LARGE_FILE_THRESHOLD: &str = "large-file-threshold";
 const OPTION_THREADS: &str = "threads";
+const OPTION_SINGLE_THREAD: &str = "single-thread";
+const OPTION_THREADS_IO: &str = "threads-io";
+const OPTION_THREADS_HASH: &str = "threads-hash";
+const OPTION_KEEP: &str = "keep";
+const OPTION_RECURSIVE: &str = "recursive";
+const OPTION_PRUNE_DIR: &str = "prune-dir";
+const OPTION_NO_HIDDEN: &str = "no-hidden";
+const OPTION_ONE_FILESYSTEM: &str = "one-filesystem";
+const OPTION_EXPAND_TAR: &str = "expand-tar";
+const OPTION_SYMLINK_MODE: &str = "symlink-mode";
+const OPTION_FOLLOW_INPUT_SYMLINKS: &str = "follow-input-symlinks";
+const OPTION_DIGEST_BITS: &str = "digest-bits";
+const OPTION_VERIFY: &str = "verify";
+const OPTION_FULL_HASH: &str = "full-hash";
+const OPTION_COLLISION_ESTIMATE: &str = "collision-estimate";
+const OPTION_STRIP_PREFIX: &str = "strip-prefix";
+const OPTION_FIRST_LISTED: &str = "first-listed";
+const OPTION_ROOT: &str = "root";
+const OPTION_BATCH_MULTIPLIER: &str = "batch-multiplier";
+const OPTION_LIST_SKIPPED: &str = "list-skipped";
+const OPTION_REPORT_INCOMPLETE: &str = "report-incomplete";
+const OPTION_ERROR_LOG: &str = "error-log";
+const OPTION_EMIT_SCRIPT: &str = "emit-script";
+const OPTION_WRITE_MANIFEST: &str = "write-manifest";
+const OPTION_BLOOM: &str = "bloom";
+const OPTION_SIZE_INDEX: &str = "size-index";
+const OPTION_STATUS_FILE: &str = "status-file";
+const OPTION_PLAN: &str = "plan";
+const OPTION_SIZE_ONLY: &str = "size-only";
+const OPTION_REFLINK: &str = "reflink";
+const OPTION_SIZE_TOLERANCE: &str = "size-tolerance";
+const OPTION_GROUP: &str = "group";
+const OPTION_MARK_KEEP: &str = "mark-keep";
+const OPTION_MAX_GROUP_SIZE: &str = "max-group-size";
+const OPTION_MIN_COPIES: &str = "min-copies";
+const OPTION_EXCLUDE_SAME_DIR: &str = "exclude-same-dir";
+const OPTION_SAME_NAME: &str = "same-name";
+const OPTION_COLOR: &str = "color";
+const OPTION_SAMPLE: &str = "sample";
+const OPTION_NORMALIZE_TEXT: &str = "normalize-text";
+const OPTION_DECOMPRESS: &str = "decompress";
+const OPTION_MAGIC: &str = "magic";
+const OPTION_FILTER_CMD: &str = "filter-cmd";
+const OPTION_NORMALIZE_MAX_SIZE: &str = "normalize-max-size";
+const OPTION_COUNT_ONLY: &str = "count-only";
+const OPTION_PRINT_REDUNDANT: &str = "print-redundant";
+const OPTION_PRINT0: &str = "print0";
+const OPTION_REPORT: &str = "report";
+const OPTION_ACCOUNTING: &str = "accounting";
+const OPTION_ON_PERMISSION_DENIED: &str = "on-permission-denied";
+const OPTION_ON_NOT_FOUND: &str = "on-not-found";
+const OPTION_ON_INVALID: &str = "on-invalid";
+const OPTION_SKIP_ERRORS: &str = "skip-errors";
+const OPTION_SEPARATE_EMPTY: &str = "separate-empty";
+const OPTION_STREAM: &str = "stream";
+const OPTION_TIMING: &str = "timing";
+const OPTION_NEWER_THAN: &str = "newer-than";
+const OPTION_OLDER_THAN: &str = "older-than";
+const OPTION_VERSION_JSON: &str = "version-json";
 
-impl Options {
-    pub fn parse() -> Options {
-        let default_threads = num_cpus::get().to_string();
-        let default_read_size = MAX_READ_BUFFER_SIZE.to_string();
-
-        let matches = App::new("fddup")
-            .version("1.0.3")
-            .author("Hammock Sunburn <hammocksunburn@gmail.com>")
-            .about("Find duplicate files")
-            .arg(
-                Arg::with_name(OPTION_FILES)
-                    .short("f")
-                    .long("files")
-                    .value_name("FILENAME")
-                    .help("List of files to be checked for duplicates; if not specified, filenames are read from STDIN")
-                    .takes_value(true),
-            )
-            .arg(
-                Arg::with_name(OPTION_OUTPUT)
-                .short("o")
-                .long("output")
-                .value_name("FILE")
-                .help("Output duplicate filenames and hashes to this file; if not specified, outputs to STDOUT")
-                .takes_value(true)
-            )
-            .arg(
-                Arg::with_name(OPTION_SKIP_EMPTY)
-                .short("e")
-                .long("skip-empty")
-                .help("skip empty (0-length) files")
-            )
-            .arg(
-                Arg::with_name(OPTION_VERBOSE)
-                .short("v")
-                .long("verbose")
-                .help("show extra information (#of files, bytes read, etc.)")
-            )
-            .arg(
-                Arg::with_name(OPTION_SHOW_SIZE)
-                .short("z")
-                .long("show-size")
-                .help("show size of duplicate files in addition to hash and filename")
-            )
-            .arg(
-                Arg::with_name(OPTION_READ_SIZE)
-                .short("s")
-                .long("read-size")
-                .value_name("BYTES")
-                .help("Size of file read operations, in bytes")
-                .default_value(&default_read_size)
-                .takes_value(true)
-            )
-            .arg(
-                Arg::with_name(OPTION_THREADS)
-                .short("j")
-                .long("threads")
-                .value_name("NUM")
-                .help("Number of threads to use for performing work")
-                .default_value(default_threads.as_str())
-                .takes_value(true)
-            )
-            .get_matches();
+const SUBCOMMAND_SCAN: &str = "scan";
+const SUBCOMMAND_CLEAN: &str = "clean";
+const SUBCOMMAND_VERIFY: &str = "verify";
+const SUBCOMMAND_COMPLETIONS: &str = "completions";
+const ARG_SHELL: &str = "SHELL";
+const ARG_MANIFEST: &str = "MANIFEST";
+
+const OPTION_DELETE: &str = "delete";
+const OPTION_HARDLINK: &str = "hardlink";
+const OPTION_DRY_RUN: &str = "dry-run";
+const GROUP_CLEAN_ACTION: &str = "action";
+const GROUP_PARTIAL_COMPARISON: &str = "partial-comparison";
+
+// Build the command line definition. Scanning is the default, so its options are
+// accepted at the top level as well as by `scan` and `clean`. `default_read_size`
+// is borrowed for the read size's default value, and times given relative to now
+// are relative to `now`.
+fn app(default_read_size: &str, now: SystemTime) -> App<'_, '_> {
+    let app = App::new("fddup")
+        .version(env!("FDDUP_VERSION"))
+        .author("Hammock Sunburn <hammocksunburn@gmail.com>")
+        .about("Find duplicate files")
+        .after_help("With no subcommand, scans for duplicates as `fddup scan` does.")
+        .arg(
+            Arg::with_name(OPTION_VERSION_JSON)
+                .long("version-json")
+                .help(
+                    "Prints version information and what this build supports as JSON, then exits",
+                ),
+        );
+
+    with_scan_args(app, default_read_size, now)
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommand(with_scan_args(
+            SubCommand::with_name(SUBCOMMAND_SCAN)
+                .about("Find duplicate files and list them; the default"),
+            default_read_size,
+            now,
+        ))
+        .subcommand(with_clean_args(with_scan_args(
+            SubCommand::with_name(SUBCOMMAND_CLEAN)
+                .about("Find duplicate files and delete or hard link all but the file kept from each group, after verifying them byte for byte"),
+            default_read_size,
+            now,
+        )))
+        .subcommand(
+            SubCommand::with_name(SUBCOMMAND_VERIFY)
+                .about("Check files against a manifest written with --write-manifest, listing any which changed or are missing; exits with status 1 if any did")
+                .arg(
+                    Arg::with_name(ARG_MANIFEST)
+                        .value_name("MANIFEST")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCOMMAND_COMPLETIONS)
+                .about("Write a completion script for the given shell to STDOUT")
+                .arg(
+                    Arg::with_name(ARG_SHELL)
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
+
+// Add the options for scanning for duplicates to `app`.
+fn with_scan_args<'a>(
+    app: App<'a, 'a>,
+    default_read_size: &'a str,
+    now: SystemTime,
+) -> App<'a, 'a> {
+    app
+        .arg(
+            Arg::with_name(OPTION_FILES)
+                .short("f")
+                .long("files")
+                .value_name("FILENAME")
+                .help("List of files to be checked for duplicates; if not specified, filenames are read from STDIN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(OPTION_INPUT_FORMAT)
+            .long("input-format")
+            .value_name("FORMAT")
+            .help("Format of the list of files: one filename per line, a JSON array of strings, or the output of `ls -l` or `find -ls`")
+            .possible_values(&INPUT_FORMATS)
+            .default_value("lines")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_OUTPUT)
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("Output duplicate filenames and hashes to this file; if not specified, outputs to STDOUT")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_APPEND)
+            .long("append")
+            .help("Append to the --output file rather than overwriting it")
+            .requires(OPTION_OUTPUT)
+            .conflicts_with_all(&[OPTION_GROUP, OPTION_GROUP_ID, OPTION_REPORT])
+        )
+        .arg(
+            Arg::with_name(OPTION_OUTPUT_DIR)
+            .long("output-dir")
+            .value_name("DIR")
+            .help("Write the paths of each group of duplicates to its own file in this directory, named by digest; the directory is created if needed")
+            .conflicts_with(OPTION_OUTPUT)
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_OUTPUT_MODE)
+            .long("output-mode")
+            .value_name("OCTAL")
+            .help("Permissions, such as 600, for files created by fddup which list paths, less the umask; Unix only")
+            .validator(|s| parse_mode(&s).map(|_| ()))
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SKIP_EMPTY)
+            .short("e")
+            .long("skip-empty")
+            .help("skip empty (0-length) files")
+        )
+        .arg(
+            Arg::with_name(OPTION_VERBOSE)
+            .short("v")
+            .long("verbose")
+            .help("show extra information (#of files, bytes read, etc.); given twice, also trace why each file was or wasn't grouped")
+            .multiple(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SHOW_SIZE)
+            .short("z")
+            .long("show-size")
+            .help("show size of duplicate files in addition to hash and filename")
+        )
+        .arg(
+            Arg::with_name(OPTION_SHOW_BYTES_READ)
+            .long("show-bytes-read")
+            .help("show how many bytes of each duplicate file were read before it was known to be a duplicate, after the size if shown")
+        )
+        .arg(
+            Arg::with_name(OPTION_SHOW_FILE_ID)
+            .long("show-file-id")
+            .help("show the device and inode of each duplicate file as dev:ino, after any other columns, or - where there are none, such as on Windows")
+        )
+        .arg(
+            Arg::with_name(OPTION_GROUP_ID)
+            .long("group-id")
+            .help("start each line with the number of its group of duplicates, counting from 1")
+        )
+        .arg(
+            Arg::with_name(OPTION_FIELD_SEP)
+            .long("field-sep")
+            .value_name("SEP")
+            .help("Separator between the digest, size, and path in output; \\t may be used for a tab")
+            .default_value("  ")
+            .hide_default_value(true)
+            .validator(|s| match s.is_empty() {
+                true => Err(String::from("must not be empty")),
+                false => Ok(()),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_READ_SIZE)
+            .short("s")
+            .long("read-size")
+            .value_name("BYTES")
+            .help("Size of file read operations, in bytes; may have a K, M, or G suffix, up to 64M")
+            .default_value(default_read_size)
+            .validator(|s| match parse_size(&s) {
+                Some(n) if n > 0 && n <= MAX_READ_SIZE as u64 => Ok(()),
+                _ => Err(String::from("must be a size from 1 byte to 64M")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_FIRST_READ_SIZE)
+            .long("first-read-size")
+            .value_name("BYTES")
+            .help("Size of the first read from each file, in bytes, with --read-size used for later reads; may have a K, M, or G suffix, up to 64M [default: the read size]")
+            .validator(|s| match parse_size(&s) {
+                Some(n) if n > 0 && n <= MAX_READ_SIZE as u64 => Ok(()),
+                _ => Err(String::from("must be a size from 1 byte to 64M")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_HASH_BLOCK_SIZE)
+            .long("hash-block-size")
+            .value_name("BYTES")
+            .help("Hash each read in updates of at most this many bytes; may have a K, M, or G suffix, up to 64M [default: the whole read]")
+            .validator(|s| match parse_size(&s) {
+                Some(n) if n > 0 && n <= MAX_READ_SIZE as u64 => Ok(()),
+                _ => Err(String::from("must be a size from 1 byte to 64M")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SKIP_HEADER)
+            .long("skip-header")
+            .value_name("BYTES")
+            .help("Ignore this many bytes at the start of each file when comparing contents; files must still have the same size; may have a K, M, or G suffix")
+            .validator(|s| match parse_size(&s) {
+                Some(_) => Ok(()),
+                None => Err(String::from("must be a size in bytes, optionally with a K, M, or G suffix")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_MAX_READ_BYTES)
+            .long("max-read-bytes")
+            .value_name("BYTES")
+            .help("Stop reading once this many bytes have been read, leaving the remaining files undetermined; may have a K, M, or G suffix")
+            .validator(|s| match parse_size(&s) {
+                Some(_) => Ok(()),
+                None => Err(String::from("must be a size in bytes, optionally with a K, M, or G suffix")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_DEADLINE)
+            .long("deadline")
+            .value_name("DURATION")
+            .help("Stop reading once this long has passed since starting, such as 30m or 2h, leaving the remaining files undetermined")
+            .validator(|s| match crate::timespec::parse_duration(&s) {
+                Some(_) => Ok(()),
+                None => Err(String::from("must be a duration such as 30s, 15m, 12h or 1d")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_LARGE_FILE_THRESHOLD)
+            .long("large-file-threshold")
+            .value_name("BYTES")
+            .help("Read files larger than this in chunks of at most 512K, whatever --read-size, and never compare them as normalized text, so no read of a huge file needs a huge buffer; may have a K, M, or G suffix")
+            .validator(|s| match parse_size(&s) {
+                Some(_) => Ok(()),
+                None => Err(String::from("must be a size in bytes, optionally with a K, M, or G suffix")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_THREADS)
+            .short("j")
+            .long("threads")
+            .value_name("NUM")
+            .help("Number of threads to use for performing work, or auto for the number of logical CPUs; if not specified, uses the FDDUP_THREADS environment variable or else auto")
+            .env(ENV_THREADS)
+            .default_value("auto")
+            .validator(|s| match s.as_str() {
+                "auto" => Ok(()),
+                _ => match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive integer or auto")),
+                },
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SINGLE_THREAD)
+            .long("single-thread")
+            .help("Read files one at a time on a single thread, in a fixed order, so that runs are reproducible and easier to trace; overrides --threads")
+        )
+        .arg(
+            Arg::with_name(OPTION_THREADS_IO)
+            .long("threads-io")
+            .value_name("NUM")
+            .help("Read with this many threads, and hash what they read on a separate pool of threads; replaces --threads for reading")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("must be a positive integer")),
+            })
+            .conflicts_with(OPTION_SINGLE_THREAD)
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_THREADS_HASH)
+            .long("threads-hash")
+            .value_name("NUM")
+            .help("Hash with this many threads, separately from the threads reading; without --threads-io, reads use --threads")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("must be a positive integer")),
+            })
+            .conflicts_with(OPTION_SINGLE_THREAD)
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_KEEP)
+            .short("k")
+            .long("keep")
+            .value_name("RULES")
+            .help("Comma-separated rules choosing which file of a duplicate group to keep, in order of precedence: glob:PATTERN, oldest, newest, shortest; remaining ties go to the smallest path. The kept file is listed first in its group")
+            .validator(|s| crate::keep::parse(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_RECURSIVE)
+            .short("r")
+            .long("recursive")
+            .help("recurse into directories named in the list of files rather than skipping them")
+        )
+        .arg(
+            Arg::with_name(OPTION_PRUNE_DIR)
+            .long("prune-dir")
+            .value_name("NAME")
+            .help("When recursing, don't descend into directories with this name, wherever they appear; may be given more than once")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name(OPTION_NO_HIDDEN)
+            .long("no-hidden")
+            .help("Skip hidden files and directories: those whose name starts with a dot, or on Windows, those with the hidden attribute")
+        )
+        .arg(
+            Arg::with_name(OPTION_SYMLINK_MODE)
+            .long("symlink-mode")
+            .value_name("MODE")
+            .help("What to do with symlinks: skip them, follow links to files and compare their contents, or compare links by their target paths alone")
+            .possible_values(&["skip", "follow", "target"])
+            .default_value("skip")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_FOLLOW_INPUT_SYMLINKS)
+            .long("follow-input-symlinks")
+            .help("when recursing, walk the directory a link named in the list of files points to; links found while walking are still handled by --symlink-mode")
+            .requires(OPTION_RECURSIVE)
+        )
+        .arg(
+            Arg::with_name(OPTION_ONE_FILESYSTEM)
+            .long("one-filesystem")
+            .help("when recursing, don't descend into directories on other filesystems")
+            .requires(OPTION_RECURSIVE)
+        )
+        .arg(
+            Arg::with_name(OPTION_EXPAND_TAR)
+            .long("expand-tar")
+            .help("compare the files stored in uncompressed tar archives (*.tar) rather than the archives themselves, without extracting them")
+        )
+        .arg(
+            Arg::with_name(OPTION_DIGEST_BITS)
+            .long("digest-bits")
+            .value_name("BITS")
+            .help("Number of digest bits compared when grouping files, a multiple of 8 up to 256; fewer than 256 implies --verify")
+            .default_value("256")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(bits) if bits > 0 && bits <= crate::possdupe::DIGEST_LEN * 8 && bits % 8 == 0 => Ok(()),
+                _ => Err(format!("must be a multiple of 8 from 8 to {}", crate::possdupe::DIGEST_LEN * 8)),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_VERIFY)
+            .long("verify")
+            .help("compare the contents of duplicate files byte for byte before reporting them")
+        )
+        .arg(
+            Arg::with_name(OPTION_FULL_HASH)
+            .long("full-hash")
+            .help("read every file to the end, even once it's known to be unique, so every file has a full digest for --write-manifest; reads far more than usual")
+            .conflicts_with_all(&[OPTION_PLAN, OPTION_SIZE_ONLY, OPTION_SAMPLE])
+        )
+        .arg(
+            Arg::with_name(OPTION_COLLISION_ESTIMATE)
+            .long("collision-estimate")
+            .help("show the approximate chance that different files of the same size share a digest, given the number of such files and --digest-bits")
+            .conflicts_with(OPTION_SIZE_ONLY)
+        )
+        .arg(
+            Arg::with_name(OPTION_STRIP_PREFIX)
+            .long("strip-prefix")
+            .value_name("PREFIX")
+            .help("Treat input paths which are the same after removing this prefix as the same file, listing it once; may be given more than once. Output paths are unchanged")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name(OPTION_FIRST_LISTED)
+            .long("first-listed")
+            .help("When the same input is listed more than once, keep the path listed first rather than the smallest")
+        )
+        .arg(
+            Arg::with_name(OPTION_ROOT)
+            .long("root")
+            .value_name("LABEL:PATH")
+            .help("Label files under PATH and start each group of duplicates with a line listing the labels it spans; may be given more than once")
+            .validator(|s| crate::roots::parse(&s).map(|_| ()))
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name(OPTION_BATCH_MULTIPLIER)
+            .long("batch-multiplier")
+            .value_name("NUM")
+            .help("Number of files read per round for each thread; larger values keep threads busier with many small files")
+            .default_value("1")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("must be a positive integer")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_LIST_SKIPPED)
+            .long("list-skipped")
+            .value_name("FILE")
+            .help("Write the paths of files which were never read because their size was unique, --bloom knew them to be unique, or they were other paths to a file already listed, to this file")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_REPORT_INCOMPLETE)
+            .long("report-incomplete")
+            .value_name("FILE")
+            .help("Write the paths of files which could be duplicates but couldn't be told either way, with the reason, to this file; files which shrink while being read are listed rather than stopping the run")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_ERROR_LOG)
+            .long("error-log")
+            .value_name("FILE")
+            .help("Write each file skipped after an error, as the kind of error, the message and the path separated by tabs, to this file; see --on-permission-denied and --on-not-found")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_WRITE_MANIFEST)
+            .long("write-manifest")
+            .value_name("FILE")
+            .help("Write the digest, size and path of every file scanned, not just duplicates, to this file")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_BLOOM)
+            .long("bloom")
+            .value_name("FILE")
+            .help("Skip reading files which were unique in the last run given this FILE and which no file since can be a duplicate of; FILE is rewritten for the next run")
+            .takes_value(true)
+            .conflicts_with_all(&[OPTION_PLAN, OPTION_SIZE_ONLY])
+        )
+        .arg(
+            Arg::with_name(OPTION_SIZE_INDEX)
+            .long("size-index")
+            .value_name("FILE")
+            .help("Take the size, modification time and file id of files listed in this index rather than stat'ing them; each line is SIZE, MTIME, DEV:INO and PATH separated by tabs, as written by find -printf '%s\\t%T@\\t%D:%i\\t%p\\n'")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_STATUS_FILE)
+            .long("status-file")
+            .value_name("FILE")
+            .help("Keep this file up to date with a JSON object of progress counts, rewritten at most once a second, for other tools to poll")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_PLAN)
+            .long("plan")
+            .help("only report how many files share a size and how much would be read at most, without reading any files")
+        )
+        .arg(
+            Arg::with_name(OPTION_SIZE_ONLY)
+            .long("size-only")
+            .help("list groups of files with the same size, without reading any files; they're not necessarily duplicates")
+            .conflicts_with_all(&[
+                OPTION_PLAN,
+                OPTION_VERIFY,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+                OPTION_DIGEST_BITS,
+                OPTION_FIRST_READ_SIZE,
+                OPTION_MAX_READ_BYTES,
+                OPTION_DEADLINE,
+                OPTION_SHOW_SIZE,
+                OPTION_SHOW_BYTES_READ,
+                OPTION_COUNT_ONLY,
+                OPTION_OUTPUT_DIR,
+                OPTION_PRINT_REDUNDANT,
+                OPTION_WRITE_MANIFEST,
+            ])
+        )
+        .arg(
+            Arg::with_name(OPTION_REFLINK)
+            .long("reflink")
+            .help("make each duplicate share storage with the file kept, on filesystems with copy-on-write support such as Btrfs and XFS (Linux only)")
+            .conflicts_with_all(&[
+                OPTION_PLAN,
+                OPTION_SIZE_ONLY,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+            ])
+        )
+        .arg(
+            Arg::with_name(OPTION_EMIT_SCRIPT)
+            .long("emit-script")
+            .value_names(&["ACTION", "FILE"])
+            .help("Write a shell script to FILE which, when run, does ACTION, one of hardlink, delete, or symlink, to every duplicate but the file kept from each group; groups are verified byte for byte first")
+            .number_of_values(2)
+            .conflicts_with_all(&[
+                OPTION_PLAN,
+                OPTION_SIZE_ONLY,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+            ])
+        )
+        .arg(
+            Arg::with_name(OPTION_SIZE_TOLERANCE)
+            .long("size-tolerance")
+            .value_name("PCT")
+            .help("Also list near duplicates for review: files which aren't duplicates, but whose sizes are within this percentage of each other and which share their first or last 16 KiB")
+            .validator(|s| match s.parse::<f64>() {
+                Ok(pct) if pct > 0.0 && pct <= 100.0 => Ok(()),
+                _ => Err(String::from("must be a percentage above 0 and at most 100")),
+            })
+            .conflicts_with_all(&[
+                OPTION_PLAN,
+                OPTION_SIZE_ONLY,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+                OPTION_COUNT_ONLY,
+                OPTION_OUTPUT_DIR,
+                OPTION_PRINT_REDUNDANT,
+            ])
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_GROUP)
+            .short("g")
+            .long("group")
+            .help("separate groups of duplicate files with a blank line")
+        )
+        .arg(
+            Arg::with_name(OPTION_MARK_KEEP)
+            .long("mark-keep")
+            .help("separate groups and mark the file which would be kept with `*`; nothing is deleted")
+        )
+        .arg(
+            Arg::with_name(OPTION_MAX_GROUP_SIZE)
+            .long("max-group-size")
+            .value_name("NUM")
+            .help("Warn on STDERR about any group of duplicates with more than this many files")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("must be a positive integer")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_MIN_COPIES)
+            .long("min-copies")
+            .value_name("NUM")
+            .help("Only report groups of duplicates with at least this many files")
+            .default_value("2")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n >= 2 => Ok(()),
+                _ => Err(String::from("must be an integer of at least 2")),
+            })
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_EXCLUDE_SAME_DIR)
+            .long("exclude-same-dir")
+            .help("Only report groups of duplicates spread across more than one directory")
+        )
+        .arg(
+            Arg::with_name(OPTION_SAME_NAME)
+            .long("same-name")
+            .help("Only group files which have the same name as well as the same contents")
+        )
+        .arg(
+            Arg::with_name(OPTION_COLOR)
+            .long("color")
+            .value_name("WHEN")
+            .help("When to colorize groups written with --group; auto only colorizes when writing to a terminal")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SAMPLE)
+            .long("sample")
+            .value_name("BLOCKS")
+            .help("Only hash this many evenly spaced blocks of --read-size bytes from each file; results are likely, not certain, duplicates")
+            .validator(|s| match s.parse::<u64>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("must be a positive integer")),
+            })
+            .conflicts_with(OPTION_VERIFY)
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_NORMALIZE_TEXT)
+            .long("normalize-text")
+            .value_name("GLOB")
+            .help("Compare files whose name matches this glob as text, ignoring line endings and trailing whitespace; may be given more than once")
+            .conflicts_with(OPTION_VERIFY)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name(OPTION_DECOMPRESS)
+            .long("decompress")
+            .help("Compare gzip compressed files (*.gz, *.tgz) by their decompressed contents, decompressing them in parallel before grouping files; see --on-invalid for files which fail to decompress")
+            .conflicts_with_all(&[OPTION_VERIFY, OPTION_NORMALIZE_TEXT, OPTION_SKIP_HEADER])
+        )
+        .arg(
+            Arg::with_name(OPTION_MAGIC)
+            .long("magic")
+            .value_name("TYPES")
+            .help("Only consider files whose first bytes show they're one of these comma-separated types, whatever their names: image (jpeg or jpg, png, gif, webp, tiff, bmp, heic, avif), video (mp4, mov, mkv, avi), pdf, zip, gzip, or any single type")
+            .validator(|s| crate::magic::parse(&s).map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_FILTER_CMD)
+            .long("filter-cmd")
+            .value_name("CMD")
+            .help("Run this shell command once with the path of every file found written to its STDIN, one per line; only the paths it writes back to STDOUT are considered. The run stops unless it exits with status 0")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_NORMALIZE_MAX_SIZE)
+            .long("normalize-max-size")
+            .value_name("BYTES")
+            .help("Largest file compared as text with --normalize-text; larger files are compared as is")
+            .default_value("1048576")
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_COUNT_ONLY)
+            .short("c")
+            .long("count-only")
+            .help("don't list duplicate files; only output the number of duplicate groups and files and the bytes reclaimable")
+        )
+        .arg(
+            Arg::with_name(OPTION_PRINT_REDUNDANT)
+            .long("print-redundant")
+            .help("only output the paths of duplicates which could be removed, one per line, leaving out the file to keep from each group")
+            .conflicts_with_all(&[
+                OPTION_COUNT_ONLY,
+                OPTION_OUTPUT_DIR,
+                OPTION_GROUP,
+                OPTION_MARK_KEEP,
+                OPTION_SHOW_SIZE,
+                OPTION_SHOW_BYTES_READ,
+                OPTION_SHOW_FILE_ID,
+                OPTION_GROUP_ID,
+                OPTION_ROOT,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+            ])
+        )
+        .arg(
+            Arg::with_name(OPTION_PRINT0)
+            .long("print0")
+            .help("end each path output by --print-redundant with a NUL byte rather than a newline, for xargs -0")
+            .requires(OPTION_PRINT_REDUNDANT)
+        )
+        .arg(
+            Arg::with_name(OPTION_REPORT)
+            .long("report")
+            .help("once every group is known, list them largest files first, each under a header with the size of its files, how many copies there are and the space reclaimable")
+            .conflicts_with_all(&[
+                OPTION_COUNT_ONLY,
+                OPTION_PRINT_REDUNDANT,
+                OPTION_OUTPUT_DIR,
+                OPTION_GROUP,
+                OPTION_MARK_KEEP,
+                OPTION_SHOW_SIZE,
+                OPTION_SHOW_BYTES_READ,
+                OPTION_SHOW_FILE_ID,
+                OPTION_GROUP_ID,
+                OPTION_STREAM,
+            ])
+        )
+        .arg(
+            Arg::with_name(OPTION_ACCOUNTING)
+            .long("accounting")
+            .value_name("SIZE")
+            .help("Count reclaimable space by the space allocated on disk, which is smaller for sparse files, or by file length; allocated falls back to length where unsupported")
+            .possible_values(&["allocated", "logical"])
+            .default_value("allocated")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_ON_PERMISSION_DENIED)
+            .long("on-permission-denied")
+            .value_name("ACTION")
+            .help("Whether to skip files which can't be read for lack of permission or abort; skipped files are counted with -v")
+            .possible_values(&["skip", "abort"])
+            .default_value("abort")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_ON_NOT_FOUND)
+            .long("on-not-found")
+            .value_name("ACTION")
+            .help("Whether to skip files which don't exist, such as those removed since being listed, or abort; skipped files are counted with -v")
+            .possible_values(&["skip", "abort"])
+            .default_value("abort")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_ON_INVALID)
+            .long("on-invalid")
+            .value_name("ACTION")
+            .help("Whether to skip files whose contents aren't what they should be, such as gzip files which fail to decompress with --decompress, or abort; skipped files are counted with -v")
+            .possible_values(&["skip", "abort"])
+            .default_value("abort")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_SKIP_ERRORS)
+            .long("skip-errors")
+            .help("Skip files after any error which can be skipped, as if --on-permission-denied, --on-not-found and --on-invalid were each skip unless given")
+        )
+        .arg(
+            Arg::with_name(OPTION_SEPARATE_EMPTY)
+            .long("separate-empty")
+            .help("report the number of empty (0-length) files on STDERR rather than listing them as duplicates")
+        )
+        .arg(
+            Arg::with_name(OPTION_STREAM)
+            .long("stream")
+            .help("write each group of duplicates as soon as it's confirmed rather than buffering output")
+        )
+        .arg(
+            Arg::with_name(OPTION_TIMING)
+            .long("timing")
+            .help("show how long was spent finding files, reading and hashing them, and writing output")
+        )
+        .arg(
+            Arg::with_name(OPTION_NEWER_THAN)
+            .long("newer-than")
+            .value_name("TIME")
+            .help("Only consider files modified after this time: a duration ago such as 7d, or a UTC date such as 2021-09-30 or 2021-09-30T12:00:00")
+            .validator(move |s| crate::timespec::parse(&s, now).map(|_| ()))
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name(OPTION_OLDER_THAN)
+            .long("older-than")
+            .value_name("TIME")
+            .help("Only consider files modified before this time, in the same form as --newer-than")
+            .validator(move |s| crate::timespec::parse(&s, now).map(|_| ()))
+            .takes_value(true)
+        )
+}
+
+// Add the options choosing what `clean` does with duplicates to `app`. Cleaning
+// always verifies groups byte for byte, so options which compare anything less
+// than a file's whole contents can't be used with it.
+fn with_clean_args<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app.arg(
+        Arg::with_name(OPTION_DELETE)
+            .long("delete")
+            .help("Delete every duplicate but the file kept from each group"),
+    )
+    .arg(
+        Arg::with_name(OPTION_HARDLINK).long("hardlink").help(
+            "Replace every duplicate but the file kept from each group with a hard link to it",
+        ),
+    )
+    .group(
+        ArgGroup::with_name(GROUP_CLEAN_ACTION)
+            .args(&[OPTION_DELETE, OPTION_HARDLINK])
+            .required(true),
+    )
+    .arg(Arg::with_name(OPTION_DRY_RUN).long("dry-run").help(
+        "Only list what would be deleted or hard linked, on STDERR, without changing anything",
+    ))
+    .group(
+        ArgGroup::with_name(GROUP_PARTIAL_COMPARISON)
+            .args(&[
+                OPTION_PLAN,
+                OPTION_SIZE_ONLY,
+                OPTION_SAMPLE,
+                OPTION_NORMALIZE_TEXT,
+                OPTION_DECOMPRESS,
+                OPTION_SKIP_HEADER,
+                OPTION_REFLINK,
+                OPTION_MAX_READ_BYTES,
+                OPTION_DEADLINE,
+            ])
+            .multiple(true)
+            .conflicts_with(GROUP_CLEAN_ACTION),
+    )
+}
+
+// A JSON array of strings which need no escaping.
+fn json_list(values: &[&str]) -> String {
+    let quoted: Vec<_> = values.iter().map(|v| format!("\"{}\"", v)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+// Version information and what this build supports, for scripts which adapt to
+// the version installed. Lists only grow, so a script can check for what it needs.
+fn version_json() -> String {
+    let shells: Vec<&str> = Shell::variants().to_vec();
+
+    format!(
+        concat!(
+            "{{\"name\": \"fddup\", \"version\": \"{}\", \"build\": \"{}\", ",
+            "\"hashes\": {}, \"input_formats\": {}, \"output_formats\": {}, ",
+            "\"compression\": {}, \"script_actions\": {}, \"shells\": {}, ",
+            "\"features\": {{\"reflink\": {}, \"graceful_interrupt\": {}, \"raw_filenames\": {}}}}}\n"
+        ),
+        env!("CARGO_PKG_VERSION"),
+        env!("FDDUP_VERSION"),
+        json_list(&["sha256"]),
+        json_list(&INPUT_FORMATS),
+        json_list(&OUTPUT_FORMATS),
+        json_list(&["gzip"]),
+        json_list(&SCRIPT_ACTIONS),
+        json_list(&shells),
+        crate::reflink::SUPPORTED,
+        cfg!(unix),
+        cfg!(unix)
+    )
+}
+
+// Write a completion script for the named shell.
+fn write_completions<W: Write>(shell: &str, writer: &mut W) {
+    let shell = shell.parse::<Shell>().unwrap();
+    let default_read_size = DEFAULT_READ_SIZE.to_string();
+
+    app(&default_read_size, SystemTime::now()).gen_completions_to("fddup", shell, writer);
+}
+
+// What to do, as chosen on the command line.
+pub enum Command {
+    // Scan for duplicates, and with `clean`, clean them up
+    Scan(Box<Options>),
+
+    // Check files against the manifest with this path
+    Verify(PathBuf),
+}
+
+// Parse the command line. Completion scripts are written straight away.
+pub fn parse() -> Command {
+    let default_read_size = DEFAULT_READ_SIZE.to_string();
+    let now = SystemTime::now();
+
+    let matches = app(&default_read_size, now).get_matches();
+
+    if matches.is_present(OPTION_VERSION_JSON) {
+        print!("{}", version_json());
+        std::process::exit(0);
+    }
+
+    match matches.subcommand() {
+        (SUBCOMMAND_COMPLETIONS, Some(completions)) => {
+            write_completions(completions.value_of(ARG_SHELL).unwrap(), &mut io::stdout());
+            std::process::exit(0);
+        }
+        (SUBCOMMAND_VERIFY, Some(verify)) => {
+            Command::Verify(PathBuf::from(verify.value_of_os(ARG_MANIFEST).unwrap()))
+        }
+        (SUBCOMMAND_SCAN, Some(scan)) => Command::Scan(Box::new(Options::from_matches(scan, now))),
+        (SUBCOMMAND_CLEAN, Some(clean)) => {
+            Command::Scan(Box::new(Options::from_matches(clean, now)))
+        }
+        _ => Command::Scan(Box::new(Options::from_matches(&matches, now))),
+    }
+}
+
+// Options parsed from these arguments as if given on the command line.
+#[cfg(test)]
+pub fn parse_from(args: &[&str]) -> Options {
+    let default_read_size = DEFAULT_READ_SIZE.to_string();
+    let now = SystemTime::now();
+    let args = std::iter::once("fddup").chain(args.iter().copied());
+
+    Options::from_matches(&app(&default_read_size, now).get_matches_from(args), now)
+}
 
+impl Options {
+    // Options for scanning, from the top level or the `scan` or `clean` subcommand.
+    fn from_matches(matches: &ArgMatches, now: SystemTime) -> Options {
         let files = matches.value_of(OPTION_FILES).map(String::from);
+        let input_format = match matches.value_of(OPTION_INPUT_FORMAT).unwrap() {
+            "json" => InputFormat::Json,
+            "ls" => InputFormat::Listing,
+            _ => InputFormat::Lines,
+        };
         let output = matches.value_of(OPTION_OUTPUT).map(String::from);
+        let output_dir = matches.value_of(OPTION_OUTPUT_DIR).map(PathBuf::from);
+        let output_mode = matches
+            .value_of(OPTION_OUTPUT_MODE)
+            .map(|s| parse_mode(s).unwrap());
+        let append = matches.is_present(OPTION_APPEND);
+        let list_skipped = matches.value_of(OPTION_LIST_SKIPPED).map(String::from);
+        let report_incomplete = matches.value_of(OPTION_REPORT_INCOMPLETE).map(String::from);
+        let error_log = matches.value_of(OPTION_ERROR_LOG).map(String::from);
+        let write_manifest = matches.value_of(OPTION_WRITE_MANIFEST).map(String::from);
+        let bloom = matches.value_of_os(OPTION_BLOOM).map(PathBuf::from);
+        let size_index = matches.value_of_os(OPTION_SIZE_INDEX).map(PathBuf::from);
+        let status_file = matches.value_of_os(OPTION_STATUS_FILE).map(PathBuf::from);
         let skip_empty = matches.is_present(OPTION_SKIP_EMPTY);
-        let verbose = matches.is_present(OPTION_VERBOSE);
+        let verbosity = matches.occurrences_of(OPTION_VERBOSE) as usize;
         let show_size = matches.is_present(OPTION_SHOW_SIZE);
+        let show_bytes_read = matches.is_present(OPTION_SHOW_BYTES_READ);
+        let show_file_id = matches.is_present(OPTION_SHOW_FILE_ID);
+        let group_id = matches.is_present(OPTION_GROUP_ID);
+        let field_sep = matches
+            .value_of(OPTION_FIELD_SEP)
+            .unwrap()
+            .replace("\\t", "\t");
+        let recursive = matches.is_present(OPTION_RECURSIVE);
+        let symlink_mode = match matches.value_of(OPTION_SYMLINK_MODE).unwrap() {
+            "follow" => SymlinkMode::Follow,
+            "target" => SymlinkMode::Target,
+            _ => SymlinkMode::Skip,
+        };
+        let one_filesystem = matches.is_present(OPTION_ONE_FILESYSTEM);
+        let expand_tar = matches.is_present(OPTION_EXPAND_TAR);
+        let plan = matches.is_present(OPTION_PLAN);
+        let size_only = matches.is_present(OPTION_SIZE_ONLY);
+        let reflink = matches.is_present(OPTION_REFLINK);
+        let size_tolerance = matches
+            .value_of(OPTION_SIZE_TOLERANCE)
+            .map(|s| s.parse::<f64>().unwrap());
+        let mark_keep = matches.is_present(OPTION_MARK_KEEP);
+        let group = matches.is_present(OPTION_GROUP) || mark_keep;
+        let max_group_size = matches
+            .value_of(OPTION_MAX_GROUP_SIZE)
+            .map(|s| s.parse::<usize>().unwrap());
+        let min_copies = matches
+            .value_of(OPTION_MIN_COPIES)
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let exclude_same_dir = matches.is_present(OPTION_EXCLUDE_SAME_DIR);
+        let same_name = matches.is_present(OPTION_SAME_NAME);
+        let count_only = matches.is_present(OPTION_COUNT_ONLY);
+        let print_redundant = matches.is_present(OPTION_PRINT_REDUNDANT);
+        let print0 = matches.is_present(OPTION_PRINT0);
+        let report = matches.is_present(OPTION_REPORT);
+        let stream = matches.is_present(OPTION_STREAM);
+        let timing = matches.is_present(OPTION_TIMING);
+        let skip_errors = matches.is_present(OPTION_SKIP_ERRORS);
+        let error_action = |option| match matches.value_of(option).unwrap() {
+            _ if skip_errors && matches.occurrences_of(option) == 0 => ErrorAction::Skip,
+            "skip" => ErrorAction::Skip,
+            _ => ErrorAction::Abort,
+        };
+        let on_permission_denied = error_action(OPTION_ON_PERMISSION_DENIED);
+        let on_not_found = error_action(OPTION_ON_NOT_FOUND);
+        let on_invalid = error_action(OPTION_ON_INVALID);
+        let accounting = match matches.value_of(OPTION_ACCOUNTING).unwrap() {
+            "logical" => Accounting::Logical,
+            _ => Accounting::Allocated,
+        };
+        let separate_empty = matches.is_present(OPTION_SEPARATE_EMPTY);
+        let color = match matches.value_of(OPTION_COLOR).unwrap() {
+            "always" => Color::Always,
+            "never" => Color::Never,
+            _ => Color::Auto,
+        };
+        let prune_dirs = matches
+            .values_of_os(OPTION_PRUNE_DIR)
+            .map(|v| v.map(OsString::from).collect())
+            .unwrap_or_default();
+        let no_hidden = matches.is_present(OPTION_NO_HIDDEN);
+
+        let read_size = parse_size(matches.value_of(OPTION_READ_SIZE).unwrap()).unwrap() as usize;
+        let first_read_size = matches
+            .value_of(OPTION_FIRST_READ_SIZE)
+            .map_or(read_size, |s| parse_size(s).unwrap() as usize);
+        let hash_block_size = matches
+            .value_of(OPTION_HASH_BLOCK_SIZE)
+            .map(|s| parse_size(s).unwrap() as usize);
+        let skip_header = matches
+            .value_of(OPTION_SKIP_HEADER)
+            .map_or(0, |s| parse_size(s).unwrap());
+        let max_read_bytes = matches
+            .value_of(OPTION_MAX_READ_BYTES)
+            .map(|s| parse_size(s).unwrap());
+        let deadline = matches
+            .value_of(OPTION_DEADLINE)
+            .map(|s| crate::timespec::parse_duration(s).unwrap());
+        let large_file_threshold = matches
+            .value_of(OPTION_LARGE_FILE_THRESHOLD)
+            .map(|s| parse_size(s).unwrap());
+
+        let single_thread = matches.is_present(OPTION_SINGLE_THREAD);
+        let num_threads = match matches.value_of(OPTION_THREADS).unwrap() {
+            _ if single_thread => 1,
+            "auto" => num_cpus::get(),
+            n => n.parse::<usize>().unwrap(),
+        };
+
+        // Reading and hashing in separate stages. The runtime's threads then only
+        // read, and hashing gets threads of its own; either number may be left at
+        // --threads.
+        let threads_of = |name| matches.value_of(name).map(|n| n.parse::<usize>().unwrap());
+        let hash_threads = match (
+            threads_of(OPTION_THREADS_IO),
+            threads_of(OPTION_THREADS_HASH),
+        ) {
+            (None, None) => None,
+            (_, hash) => Some(hash.unwrap_or(num_threads)),
+        };
+        let num_threads = threads_of(OPTION_THREADS_IO).unwrap_or(num_threads);
 
-        let read_size = matches
-            .value_of(OPTION_READ_SIZE)
+        let batch_multiplier = matches
+            .value_of(OPTION_BATCH_MULTIPLIER)
             .unwrap()
             .parse::<usize>()
             .unwrap();
 
-        if read_size > MAX_READ_BUFFER_SIZE {}
+        let strip_prefixes = matches
+            .values_of_os(OPTION_STRIP_PREFIX)
+            .map(|v| v.map(PathBuf::from).collect())
+            .unwrap_or_default();
+        let first_listed = matches.is_present(OPTION_FIRST_LISTED);
 
-        let num_threads = matches
-            .value_of(OPTION_THREADS)
+        let roots = matches
+            .values_of(OPTION_ROOT)
+            .map(|v| v.map(|r| crate::roots::parse(r).unwrap()).collect())
+            .unwrap_or_default();
+
+        let digest_bits = matches
+            .value_of(OPTION_DIGEST_BITS)
             .unwrap()
             .parse::<usize>()
             .unwrap();
 
+        let normalize_text = matches
+            .values_of(OPTION_NORMALIZE_TEXT)
+            .map(|v| v.map(String::from).collect())
+            .unwrap_or_default();
+        let decompress = matches.is_present(OPTION_DECOMPRESS);
+        let magic = matches
+            .value_of(OPTION_MAGIC)
+            .map(|m| crate::magic::parse(m).unwrap())
+            .unwrap_or_default();
+        let filter_cmd = matches.value_of(OPTION_FILTER_CMD).map(String::from);
+
+        let normalize_max_size = matches
+            .value_of(OPTION_NORMALIZE_MAX_SIZE)
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+
+        let sample = matches
+            .value_of(OPTION_SAMPLE)
+            .map(|s| s.parse::<u64>().unwrap());
+
+        let newer_than = matches
+            .value_of(OPTION_NEWER_THAN)
+            .map(|s| crate::timespec::parse(s, now).unwrap());
+        let older_than = matches
+            .value_of(OPTION_OLDER_THAN)
+            .map(|s| crate::timespec::parse(s, now).unwrap());
+
+        // Truncated digests make collisions far more likely, so always verify. That
+        // can't be done when sampling, since sampled files are never fully compared,
+        // nor when normalized or decompressed contents are compared rather than bytes.
+        if digest_bits < 256 {
+            let unverifiable = [
+                (OPTION_SAMPLE, "--sample"),
+                (OPTION_NORMALIZE_TEXT, "--normalize-text"),
+                (OPTION_DECOMPRESS, "--decompress"),
+            ];
+
+            if let Some((_, name)) = unverifiable
+                .iter()
+                .find(|(option, _)| matches.is_present(option))
+            {
+                clap::Error::with_description(
+                    &format!(
+                        "--digest-bits below 256 requires verification, which can't be used with {}",
+                        name
+                    ),
+                    ErrorKind::ArgumentConflict,
+                )
+                .exit();
+            }
+        }
+
+        if reflink && !crate::reflink::SUPPORTED {
+            clap::Error::with_description(
+                "--reflink is only supported on Linux",
+                ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+
+        let clean = if matches.is_present(OPTION_DELETE) {
+            Some(crate::clean::Action::Delete)
+        } else if matches.is_present(OPTION_HARDLINK) {
+            Some(crate::clean::Action::Hardlink)
+        } else {
+            None
+        };
+        let dry_run = matches.is_present(OPTION_DRY_RUN);
+
+        let emit_script = matches.values_of(OPTION_EMIT_SCRIPT).map(|mut values| {
+            let action = values.next().unwrap();
+            let filename = values.next().unwrap().to_string();
+
+            match crate::script::ScriptAction::parse(action) {
+                Some(action) => (action, filename),
+                None => clap::Error::with_description(
+                    &format!(
+                        "--emit-script action must be hardlink, delete, or symlink, not {}",
+                        action
+                    ),
+                    ErrorKind::InvalidValue,
+                )
+                .exit(),
+            }
+        });
+
+        // Nothing is cleaned up without being compared byte for byte first.
+        let verify = matches.is_present(OPTION_VERIFY)
+            || digest_bits < 256
+            || clean.is_some()
+            || emit_script.is_some();
+        let full_hash = matches.is_present(OPTION_FULL_HASH);
+        let collision_estimate = matches.is_present(OPTION_COLLISION_ESTIMATE);
+
+        let keep = matches
+            .value_of(OPTION_KEEP)
+            .map(|k| crate::keep::parse(k).unwrap())
+            .unwrap_or_default();
+
         Options {
             files,
+            input_format,
             output,
+            output_dir,
+            output_mode,
+            append,
             skip_empty,
-            verbose,
+            verbosity,
             show_size,
+            show_bytes_read,
+            show_file_id,
+            group_id,
+            field_sep,
             read_size,
+            first_read_size,
+            hash_block_size,
+            skip_header,
+            max_read_bytes,
+            deadline,
+            large_file_threshold,
             num_threads,
+            single_thread,
+            hash_threads,
+            keep,
+            recursive,
+            symlink_mode,
+            follow_input_symlinks: matches.is_present(OPTION_FOLLOW_INPUT_SYMLINKS),
+            prune_dirs,
+            no_hidden,
+            one_filesystem,
+            expand_tar,
+            digest_bits,
+            verify,
+            full_hash,
+            collision_estimate,
+            strip_prefixes,
+            first_listed,
+            roots,
+            batch_multiplier,
+            list_skipped,
+            report_incomplete,
+            error_log,
+            write_manifest,
+            bloom,
+            size_index,
+            status_file,
+            plan,
+            size_only,
+            reflink,
+            clean,
+            dry_run,
+            emit_script,
+            size_tolerance,
+            group,
+            mark_keep,
+            max_group_size,
+            min_copies,
+            exclude_same_dir,
+            same_name,
+            color,
+            sample,
+            normalize_text,
+            decompress,
+            magic,
+            filter_cmd,
+            normalize_max_size,
+            count_only,
+            print_redundant,
+            report,
+            print0,
+            accounting,
+            on_permission_denied,
+            on_not_found,
+            on_invalid,
+            separate_empty,
+            stream,
+            timing,
+            newer_than,
+            older_than,
+        }
+    }
+
+    // One line echoing the settings which affect how a run performs and what it
+    // finds, shown with -v so runs can be compared and reproduced.
+    pub fn summary(&self) -> String {
+        let mut modes = Vec::new();
+
+        let flags = [
+            (self.single_thread, "single-thread"),
+            (self.recursive, "recursive"),
+            (self.verify, "verify"),
+            (self.full_hash, "full-hash"),
+            (self.same_name, "same-name"),
+            (self.expand_tar, "expand-tar"),
+            (self.size_only, "size-only"),
+            (self.reflink, "reflink"),
+            (self.stream, "stream"),
+        ];
+
+        for (on, name) in flags.iter() {
+            if *on {
+                modes.push(name.to_string());
+            }
+        }
+
+        match self.symlink_mode {
+            SymlinkMode::Skip => (),
+            SymlinkMode::Follow => modes.push(String::from("symlink-mode follow")),
+            SymlinkMode::Target => modes.push(String::from("symlink-mode target")),
+        }
+
+        if let Some(blocks) = self.sample {
+            modes.push(format!("sample {}", blocks));
+        }
+
+        match self.clean {
+            Some(crate::clean::Action::Delete) => modes.push(String::from("clean delete")),
+            Some(crate::clean::Action::Hardlink) => modes.push(String::from("clean hardlink")),
+            None => (),
         }
+
+        if self.dry_run {
+            modes.push(String::from("dry-run"));
+        }
+
+        if self.skip_header > 0 {
+            modes.push(format!("skip-header {}", self.skip_header));
+        }
+
+        if let Some(hash_threads) = self.hash_threads {
+            modes.push(format!("threads-hash {}", hash_threads));
+        }
+
+        if self.decompress {
+            modes.push(String::from("decompress"));
+        }
+
+        if !self.normalize_text.is_empty() {
+            modes.push(format!("normalize-text {}", self.normalize_text.join(",")));
+        }
+
+        if let Some(max) = self.max_read_bytes {
+            modes.push(format!("max-read-bytes {}", max));
+        }
+
+        if let Some(deadline) = self.deadline {
+            modes.push(format!("deadline {}s", deadline.as_secs()));
+        }
+
+        if let Some(threshold) = self.large_file_threshold {
+            modes.push(format!("large-file-threshold {}", threshold));
+        }
+
+        if let Some(block_size) = self.hash_block_size {
+            modes.push(format!("hash-block-size {}", block_size));
+        }
+
+        if modes.is_empty() {
+            modes.push(String::from("none"));
+        }
+
+        format!(
+            "config: read size {} (first {}), {} threads, batch multiplier {}, sha256 ({} bits compared), modes: {}",
+            self.read_size,
+            self.first_read_size,
+            self.num_threads,
+            self.batch_multiplier,
+            self.digest_bits,
+            modes.join(", ")
+        )
     }
 }
 
+// Parse a size in bytes with an optional binary suffix: K, M, or G.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
 // Return an input stream from a file or from stdin, depending on the specified command
-// line arguments.
+// line arguments. Gzip compressed input is decompressed as it's read.
 pub fn input_stream(options: &Options) -> Result<Box<dyn BufRead>> {
-    let reader: Box<dyn BufRead> = match &options.files {
-        None => Box::new(BufReader::new(io::stdin())),
+    match &options.files {
+        None => crate::compress::decompressed(BufReader::new(io::stdin()), false),
         Some(filename) => {
-            Box::new(BufReader::new(File::open(filename).with_context(|| {
-                format!("failed to read input file {}", filename)
-            })?))
+            let file = File::open(filename)
+                .with_context(|| format!("failed to read input file {}", filename))?;
+            crate::compress::decompressed(BufReader::new(file), filename.ends_with(".gz"))
         }
+    }
+}
+
+// Parse permissions given in octal, as for `chmod`.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "expected octal permissions such as 600, got '{}'",
+            s
+        )),
+    }
+}
+
+// Fail if any file to be written is the list of files being read. Output files are
+// created before the list is read, so the list would be truncated and lost, or
+// with `--append`, have duplicates added to it for the next run to read as paths.
+pub fn check_outputs(options: &Options) -> Result<()> {
+    let files = match &options.files {
+        Some(files) => files,
+        None => return Ok(()),
+    };
+
+    let output_fate = match options.append {
+        true => "appended to",
+        false => "overwritten",
     };
 
-    Ok(reader)
+    let outputs = [
+        (OPTION_OUTPUT, &options.output, output_fate),
+        (OPTION_LIST_SKIPPED, &options.list_skipped, "overwritten"),
+        (
+            OPTION_REPORT_INCOMPLETE,
+            &options.report_incomplete,
+            "overwritten",
+        ),
+        (OPTION_ERROR_LOG, &options.error_log, "overwritten"),
+        (
+            OPTION_EMIT_SCRIPT,
+            &options.emit_script.as_ref().map(|(_, f)| f.clone()),
+            "overwritten",
+        ),
+        (
+            OPTION_WRITE_MANIFEST,
+            &options.write_manifest,
+            "overwritten",
+        ),
+        (
+            OPTION_BLOOM,
+            &options
+                .bloom
+                .as_ref()
+                .map(|f| f.to_string_lossy().into_owned()),
+            "overwritten",
+        ),
+    ];
+
+    for (option, output, fate) in outputs.iter() {
+        if let Some(output) = output {
+            if same_file(Path::new(files), Path::new(output)) {
+                bail!(
+                    "--{} {} is the list of files being read and would be {}",
+                    option,
+                    output,
+                    fate
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Return true if both paths exist and refer to the same file, however they're
+// spelled. Files are compared by identity where the platform provides one, which
+// also catches hard links, and otherwise by their canonical paths.
+fn same_file(a: &Path, b: &Path) -> bool {
+    let (attr_a, attr_b) = match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(attr_a), Ok(attr_b)) => (attr_a, attr_b),
+        _ => return false,
+    };
+
+    match (
+        crate::platform::file_id(&attr_a),
+        crate::platform::file_id(&attr_b),
+    ) {
+        (Some(id_a), Some(id_b)) => id_a == id_b,
+        _ => match (fs::canonicalize(a), fs::canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        },
+    }
 }
 
 // Return a buffered output writer to a file or to stdout, depending on the specified
 // command line arguments.
-pub fn output_writer(options: &Options) -> Result<BufWriter<Box<dyn Write>>> {
-    let write: Box<dyn Write> = match options.output {
-        Some(ref output) => Box::new(
-            File::create(&Path::new(output))
-                .with_context(|| format!("couldn't create output file {}", output))?,
-        ),
-        None => Box::new(io::stdout()),
-    };
+pub fn output_writer(options: &Options) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    match options.output {
+        Some(ref output) if options.append => append_writer(output, options.output_mode),
+        Some(ref output) => file_writer(output, options.output_mode),
+        None => Ok(BufWriter::new(Box::new(io::stdout()))),
+    }
+}
+
+// Return a buffered output writer to the named file, created with permissions
+// `mode` on Unix if given.
+pub fn file_writer<P: AsRef<Path>>(
+    filename: P,
+    mode: Option<u32>,
+) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    let filename = filename.as_ref();
+    let write: Box<dyn Write + Send> = Box::new(
+        crate::platform::create_file(filename, mode)
+            .with_context(|| format!("couldn't create output file {}", filename.display()))?,
+    );
+
+    Ok(BufWriter::new(write))
+}
+
+// Return a buffered output writer appending to the named file, which is created
+// with permissions `mode` on Unix if it doesn't exist.
+fn append_writer<P: AsRef<Path>>(
+    filename: P,
+    mode: Option<u32>,
+) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    let filename = filename.as_ref();
+    let write: Box<dyn Write + Send> = Box::new(
+        crate::platform::append_file(filename, mode)
+            .with_context(|| format!("couldn't open output file {}", filename.display()))?,
+    );
 
     Ok(BufWriter::new(write))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_json_lists_what_is_supported() {
+        let json = version_json();
+
+        assert!(json.starts_with(&format!(
+            "{{\"name\": \"fddup\", \"version\": \"{}\", ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(json.contains("\"input_formats\": [\"lines\", \"json\", \"ls\"]"));
+        assert!(json.ends_with("}}\n"));
+
+        for action in SCRIPT_ACTIONS.iter() {
+            assert!(crate::script::ScriptAction::parse(action).is_some());
+        }
+    }
+
+    #[test]
+    fn completions_for_every_shell() {
+        for shell in Shell::variants().iter() {
+            let mut script = Vec::new();
+            write_completions(shell, &mut script);
+
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("skip-header"), "{}", shell);
+        }
+    }
+
+    #[test]
+    fn scan_options_before_a_subcommand_are_refused() {
+        let default_read_size = DEFAULT_READ_SIZE.to_string();
+        let parse = |args: &[&str]| {
+            let args = std::iter::once("fddup").chain(args.iter().copied());
+            app(&default_read_size, SystemTime::now()).get_matches_from_safe(args)
+        };
+
+        assert!(parse(&["--keep", "glob:**/b", "clean", "--delete"]).is_err());
+        assert!(parse(&["-f", "list", "clean", "--delete"]).is_err());
+        assert!(parse(&["clean", "--keep", "glob:**/b", "--delete"]).is_ok());
+        assert!(parse(&["-f", "list"]).is_ok());
+    }
+
+    // Group separators and ids would restart in each appended run.
+    #[test]
+    fn append_refuses_numbered_or_separated_output() {
+        let default_read_size = DEFAULT_READ_SIZE.to_string();
+        let parse = |args: &[&str]| {
+            let args = ["fddup", "-o", "out", "--append"]
+                .iter()
+                .chain(args)
+                .copied();
+            app(&default_read_size, SystemTime::now()).get_matches_from_safe(args)
+        };
+
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--group"]).is_err());
+        assert!(parse(&["--group-id"]).is_err());
+        assert!(parse(&["--report"]).is_err());
+    }
+
+    #[test]
+    fn digest_bits_fit_in_a_digest() {
+        let default_read_size = DEFAULT_READ_SIZE.to_string();
+        let parse = |bits: &str| {
+            app(&default_read_size, SystemTime::now()).get_matches_from_safe(vec![
+                "fddup",
+                "--digest-bits",
+                bits,
+            ])
+        };
+
+        assert!(parse("256").is_ok());
+        assert!(parse("8").is_ok());
+        assert!(parse("264").is_err());
+        assert!(parse("12").is_err());
+        assert!(parse("0").is_err());
+    }
+
+    #[test]
+    fn print_redundant_only_with_identical_bytes() {
+        let default_read_size = DEFAULT_READ_SIZE.to_string();
+        let parse = |args: &[&str]| {
+            let args = ["fddup", "--print-redundant"].iter().chain(args.iter());
+            app(&default_read_size, SystemTime::now()).get_matches_from_safe(args)
+        };
+
+        assert!(parse(&[]).is_ok());
+
+        for args in [
+            &["--sample", "4"][..],
+            &["--normalize-text", "eol"],
+            &["--decompress"],
+            &["--skip-header", "4"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn split_threads_default_to_threads() {
+        let threads = |args: &[&str]| {
+            let options = parse_from(args);
+            (options.num_threads, options.hash_threads)
+        };
+
+        assert_eq!(threads(&["-j", "3"]), (3, None));
+        assert_eq!(threads(&["-j", "3", "--threads-io", "8"]), (8, Some(3)));
+        assert_eq!(threads(&["-j", "3", "--threads-hash", "1"]), (3, Some(1)));
+        assert_eq!(
+            threads(&["--threads-io", "8", "--threads-hash", "2"]),
+            (8, Some(2))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_of_files_is_read_through_a_link() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("list"), "a\nb\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("list"), dir.join("link")).unwrap();
+
+        let options = parse_from(&["--files", dir.join("link").to_str().unwrap()]);
+        let lines: Vec<_> = input_stream(&options)
+            .unwrap()
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert!(parse_mode("800").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rw").is_err());
+    }
+
+    #[test]
+    fn same_file_however_spelled() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let list = dir.join("list.txt");
+        let other = dir.join("other.txt");
+        fs::write(&list, "a\n").unwrap();
+        fs::write(&other, "a\n").unwrap();
+
+        assert!(same_file(&list, &list));
+        assert!(same_file(&list, &dir.join(".").join("list.txt")));
+        assert!(!same_file(&list, &other));
+        assert!(!same_file(&list, &dir.join("missing.txt")));
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("4k"), Some(4096));
+        assert_eq!(parse_size("4M"), Some(4 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("4T"), None);
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("99999999999999999999G"), None);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use anyhow::{Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+// Every gzip stream starts with these two bytes.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Returns true if `header`, the start of a stream, looks like gzip.
+pub fn is_gzip(header: &[u8]) -> bool {
+    header.starts_with(&GZIP_MAGIC)
+}
+
+// Return `reader` unchanged unless it's gzip compressed, by `named_gz` or by its
+// magic bytes, in which case return a reader of its decompressed contents. Data is
+// decompressed as it's read, so memory use stays bounded no matter how large the
+// input is.
+pub fn decompressed<R>(mut reader: R, named_gz: bool) -> Result<Box<dyn BufRead>>
+where
+    R: BufRead + Send + 'static,
+{
+    let header = reader
+        .fill_buf()
+        .context("failed to read input file list")?;
+
+    if !named_gz && !is_gzip(header) {
+        return Ok(Box::new(reader));
+    }
+
+    Ok(Box::new(BufReader::new(Decompressed {
+        decoder: MultiGzDecoder::new(reader),
+        what: String::from("input file list"),
+    })))
+}
+
+// Returns true if `path` is named like a gzip compressed file.
+pub fn is_gzip_name(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz") || ext.eq_ignore_ascii_case("tgz"))
+}
+
+// Return a reader of the decompressed contents of `file`, the gzip compressed
+// file at `path`.
+pub fn decompress_file(file: File, path: &Path) -> impl Read {
+    Decompressed {
+        decoder: MultiGzDecoder::new(BufReader::new(file)),
+        what: path.display().to_string(),
+    }
+}
+
+// A gzip stream being decompressed. Every member is decompressed, as `gzip -dc`
+// does. Failure to decompress (e.g., corrupt or truncated input) is an
+// `InvalidData` error naming what was being decompressed, rather than letting the
+// input list or file silently end early.
+struct Decompressed<R: BufRead> {
+    decoder: MultiGzDecoder<R>,
+    what: String,
+}
+
+impl<R: BufRead> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to decompress {}: {}", self.what, e),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // "a\nb\n" compressed with gzip.
+    const COMPRESSED: [u8; 24] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x4b, 0xe4, 0x4a, 0xe2, 0x02,
+        0x00, 0x97, 0x2a, 0x57, 0x18, 0x04, 0x00, 0x00, 0x00,
+    ];
+
+    fn read_all(mut reader: Box<dyn BufRead>) -> io::Result<String> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    #[test]
+    fn plain_input_is_unchanged() {
+        let reader = decompressed(Cursor::new(b"a\nb\n".to_vec()), false).unwrap();
+        assert_eq!(read_all(reader).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn gzip_input_is_detected_by_magic() {
+        let reader = decompressed(Cursor::new(COMPRESSED.to_vec()), false).unwrap();
+        assert_eq!(read_all(reader).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn corrupt_gzip_input_is_an_error() {
+        let reader = decompressed(Cursor::new(COMPRESSED[..16].to_vec()), true).unwrap();
+        assert!(read_all(reader).is_err());
+    }
+}
@@ -19,240 +19,3323 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use crate::algo;
-use crate::cli::Options;
-use crate::possdupe::PossDupe;
+use crate::algo::{self, Work};
+use crate::bloom::Bloom;
+use crate::clean::Cleaner;
+use crate::cli::{Accounting, ErrorAction, InputFormat, Options, SymlinkMode};
+use crate::manifest::Manifest;
+use crate::output::{Output, OutputThread};
+use crate::possdupe::{ArchiveMember, PossDupe, Sampling, DIGEST_LEN};
+use crate::reflink::Reflinker;
+use crate::script::Script;
+use crate::sizeindex::{self, SizeIndex};
+use crate::stages::Stages;
+use crate::stats::{CollisionEstimate, OnFile, Plan, Stats, Timing};
+use crate::status::StatusFile;
+use crate::walk::{walk, WalkOptions};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, log_enabled, Level};
 use std::cell::RefCell;
-use std::cmp::min;
-use std::fs::symlink_metadata;
-use std::io::{BufRead, Read, Write};
-
-// Read filenames, one per line, from the given `BufRead`. Find some relevant
-// data about the file, such as whether it's a symlink or directory, and the
-// file's size.
-fn stat_files(reader: Box<dyn BufRead>) -> Result<Vec<PossDupe>> {
-    let mut result = Vec::new();
+use std::collections::HashMap;
+use std::fs::{metadata, read_link, symlink_metadata, Metadata};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+
+// Find some relevant data about a single file, such as whether it's a symlink or
+// directory, and the file's size and modification time. Directories are walked
+// when recursing and skipped otherwise. `listed` is set for paths from the list
+// of files, rather than found while walking.
+fn stat_path(
+    path: PathBuf,
+    listed: bool,
+    options: &Options,
+    state: &mut StatState,
+    result: &mut Vec<PossDupe>,
+    stats: &mut Stats,
+    error_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<()> {
+    if state.stopped() {
+        return Ok(());
+    }
+
+    if let Some(entry) = state.indexed(&path, options) {
+        if options.no_hidden && crate::platform::has_hidden_name(&path) {
+            debug!("skipped hidden file: {}", path.display());
+            return Ok(());
+        }
+
+        return add_file(path, entry, None, true, options, state, result);
+    }
+
+    let mut attr = symlink_metadata(crate::platform::long_path(&path))
+        .with_context(|| format!("couldn't open file to read attributes: {}", path.display()))?;
+
+    if options.no_hidden && crate::platform::is_hidden(&path, &attr) {
+        debug!("skipped hidden file: {}", path.display());
+        return Ok(());
+    }
+
+    // A listed link to a directory stands in for the directory with
+    // --follow-input-symlinks. Links found while walking are never followed into
+    // directories, so walking can't loop.
+    if listed && options.follow_input_symlinks && crate::platform::is_link(&attr) {
+        if let Ok(target) = metadata(crate::platform::long_path(&path)) {
+            if target.is_dir() {
+                attr = target;
+            }
+        }
+    }
+
+    if crate::platform::is_link(&attr) {
+        match options.symlink_mode {
+            SymlinkMode::Skip => {
+                debug!("skipped link: {}", path.display());
+                return Ok(());
+            }
+            SymlinkMode::Follow => {
+                // Links to directories aren't followed, so walking can't loop.
+                match metadata(crate::platform::long_path(&path)) {
+                    Ok(target) if !target.is_dir() => attr = target,
+                    Ok(_) => {
+                        debug!("skipped link to directory: {}", path.display());
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        eprintln!("warning: skipped broken link: {}", path.display());
+                        return Ok(());
+                    }
+                }
+            }
+            SymlinkMode::Target => {
+                let target = read_link(crate::platform::long_path(&path))
+                    .with_context(|| format!("couldn't read link: {}", path.display()))?;
+                let mtime = attr.modified().ok();
+
+                if !in_time_window(mtime, options.newer_than, options.older_than) {
+                    debug!("skipped link outside time window: {}", path.display());
+                    return Ok(());
+                }
+
+                let mut pd = PossDupe::new(path, 0);
+                pd.mtime = mtime;
+                pd.file_id = crate::platform::file_id(&attr);
+                pd.digest_len = (options.digest_bits / 8) as u8;
+                pd.compare_link_target(target);
+                result.push(pd);
+
+                return Ok(());
+            }
+        }
+    }
+
+    if attr.is_dir() {
+        if options.recursive {
+            let walk_options = WalkOptions {
+                prune_dirs: options.prune_dirs.clone(),
+                one_filesystem: options.one_filesystem,
+                skip_hidden: options.no_hidden,
+                deadline: state.deadline,
+                interrupted: state.interrupted,
+            };
+
+            walk(&path, &walk_options, &mut |p| match p {
+                Ok(p) => stat_path(
+                    p.clone(),
+                    false,
+                    options,
+                    state,
+                    result,
+                    stats,
+                    error_writer,
+                )
+                .or_else(|e| skip_stat_error(e, Some(&p), options, stats, error_writer)),
+                Err(e) => skip_stat_error(e, None, options, stats, error_writer),
+            })?;
+        } else {
+            debug!("skipped directory: {}", path.display());
+        }
+
+        return Ok(());
+    }
+
+    // Reading a named pipe could block forever, and devices and sockets have no
+    // contents to compare, so only regular files go on to be read.
+    if is_special_file(&attr) {
+        debug!("skipped special file: {}", path.display());
+        return Ok(());
+    }
+
+    let entry = sizeindex::Entry {
+        len: attr.len(),
+        mtime: attr.modified().ok(),
+        file_id: crate::platform::file_id(&attr),
+    };
+    let allocated_len = match options.accounting {
+        Accounting::Allocated => crate::platform::allocated_len(&attr),
+        Accounting::Logical => None,
+    };
+
+    add_file(path, entry, allocated_len, false, options, state, result)
+}
+
+// Add a regular file as a possible duplicate, unless it's filtered out, given what
+// stat or the size index says about it. Tar archives are expanded into their
+// members instead with `--expand-tar`.
+fn add_file(
+    path: PathBuf,
+    entry: sizeindex::Entry,
+    allocated_len: Option<u64>,
+    indexed: bool,
+    options: &Options,
+    state: &mut StatState,
+    result: &mut Vec<PossDupe>,
+) -> Result<()> {
+    if options.expand_tar && is_tar(&path) {
+        return stat_tar_members(&path, options, &mut state.listed, result);
+    }
+
+    if options.skip_empty && entry.len == 0 {
+        debug!("skipped empty file: {}", path.display());
+        return Ok(());
+    }
+
+    if !in_time_window(entry.mtime, options.newer_than, options.older_than) {
+        debug!("skipped file outside time window: {}", path.display());
+        return Ok(());
+    }
+
+    if !options.magic.is_empty() && !crate::magic::matches(&path, &options.magic)? {
+        debug!("skipped file of another type: {}", path.display());
+        return Ok(());
+    }
+
+    let mut pd = PossDupe::new(path, entry.len);
+    pd.mtime = entry.mtime;
+    pd.file_id = entry.file_id;
+    pd.allocated_len = allocated_len;
+    pd.indexed = indexed;
+
+    prepare_contents(&mut pd, options)?;
+    state.listed.add(pd, options, result);
+
+    Ok(())
+}
+
+// Return true if a file is compared by its decompressed contents with
+// `--decompress`: a gzip compressed file, by its name, outside any archive.
+fn is_decompressed(pd: &PossDupe, options: &Options) -> bool {
+    options.decompress && pd.archive.is_none() && crate::compress::is_gzip_name(&pd.path)
+}
+
+// Set up how a file's contents will be compared: which bytes are ignored, how
+// much of the digest is kept, and whether it's compared as normalized text, or
+// sampled. Files compared decompressed are decompressed later, by
+// `decompress_files`.
+fn prepare_contents(pd: &mut PossDupe, options: &Options) -> Result<()> {
+    pd.header_len = options.skip_header;
+    pd.digest_len = (options.digest_bits / 8) as u8;
+
+    if is_decompressed(pd, options) {
+        // Decompressed in parallel once every file has been listed.
+    } else if is_normalized_text(pd, options) {
+        pd.normalize_text()?;
+    } else if let Some(blocks) = options.sample {
+        pd.sample(Sampling {
+            blocks,
+            block_size: options.read_size as u64,
+        });
+    }
+
+    Ok(())
+}
+
+// Return true if a file is a tar archive to be expanded, by its name. Compressed
+// archives can't be read in place, so only `.tar` files are.
+fn is_tar(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tar"))
+}
+
+// Path shown for a member of an archive: the archive's path and the member's name
+// within it, separated by `!`.
+fn member_path(archive: &Path, name: Vec<u8>) -> Result<PathBuf> {
+    let mut path = archive.as_os_str().to_owned();
+    path.push("!");
+    path.push(crate::platform::path_from_bytes(name)?);
+    Ok(PathBuf::from(path))
+}
+
+// Add the regular files stored in a tar archive as possible duplicates, which are
+// read in place from the archive. They're filtered like any other file, using the
+// modification times recorded in the archive.
+fn stat_tar_members(
+    archive: &Path,
+    options: &Options,
+    listed: &mut ListedPaths,
+    result: &mut Vec<PossDupe>,
+) -> Result<()> {
+    for member in crate::tar::members(archive)? {
+        let path = member_path(archive, member.name)?;
+
+        if options.skip_empty && member.size == 0 {
+            debug!("skipped empty file: {}", path.display());
+            continue;
+        }
 
-    for line in reader.lines() {
-        let filename = line.with_context(|| "an input line isn't a valid unicode string")?;
-        let attr = symlink_metadata(&filename)
-            .with_context(|| format!("couldn't open file to read attributes: {}", filename))?;
-        if attr.is_dir() || attr.file_type().is_symlink() {
+        let mtime = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(member.mtime));
+
+        if !in_time_window(mtime, options.newer_than, options.older_than) {
+            debug!("skipped file outside time window: {}", path.display());
             continue;
         }
 
-        result.push(PossDupe::new(&filename, attr.len()));
+        let mut pd = PossDupe::new(path, member.size);
+        pd.mtime = mtime;
+        pd.archive = Some(Box::new(ArchiveMember {
+            archive: archive.to_path_buf(),
+            offset: member.offset,
+        }));
+
+        if !options.magic.is_empty() {
+            let contents = pd.open_at(0)?.take(member.size);
+
+            if !crate::magic::reader_matches(contents, &options.magic)
+                .with_context(|| format!("couldn't read {} to find its type", pd.path.display()))?
+            {
+                debug!("skipped file of another type: {}", pd.path.display());
+                continue;
+            }
+        }
+
+        prepare_contents(&mut pd, options)?;
+        listed.add(pd, options, result);
     }
 
-    Ok(result)
+    Ok(())
 }
 
-// Remove any duplicate paths which may have been specified as input.
-fn remove_duplicate_paths(poss_dupes: &mut Vec<PossDupe>) {
-    poss_dupes.sort_by(|a, b| a.path.cmp(&b.path));
-    poss_dupes.dedup_by(|a, b| a.path.eq(&b.path));
+// Return true if a file is neither a regular file nor a directory, such as a named
+// pipe, socket, or device. Links are handled before this is checked.
+fn is_special_file(attr: &Metadata) -> bool {
+    !attr.is_file() && !attr.is_dir()
 }
 
-// Sort our possible duplicates by length and digest snapshot.
-fn sort_poss_dupes(poss_dupes: &mut Vec<PossDupe>) {
-    poss_dupes.sort_by(|a, b| {
-        a.key
-            .len
-            .cmp(&b.key.len)
-            .then_with(|| a.key.digest_snapshot.cmp(&b.key.digest_snapshot))
+// Remove other paths to physical files already listed, so the same file given
+// under different paths, such as `a.txt` and `A.txt` on a case-insensitive
+// filesystem or hard links, is only compared once rather than reported as a
+// duplicate of itself. The smallest path to each file is kept, whatever order the
+// paths were listed in, and the paths removed are returned. Files are always kept
+// on platforms which don't identify them.
+fn remove_other_paths(poss_dupes: &mut Vec<PossDupe>) -> Vec<PathBuf> {
+    let mut smallest: HashMap<(u64, u64), usize> = HashMap::new();
+
+    for (i, pd) in poss_dupes.iter().enumerate() {
+        if let Some(id) = pd.file_id {
+            let kept = smallest.entry(id).or_insert(i);
+
+            if pd.path < poss_dupes[*kept].path {
+                *kept = i;
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    let mut i = 0;
+
+    poss_dupes.retain_mut(|pd| {
+        let keep = pd.file_id.is_none_or(|id| smallest[&id] == i);
+        i += 1;
+
+        if !keep {
+            debug!(
+                "skipped file already listed under another path: {}",
+                pd.path.display()
+            );
+            removed.push(std::mem::take(&mut pd.path));
+        }
+
+        keep
     });
-}
 
-pub struct Fddup {
-    options: Options,
-    poss_dupes: Vec<PossDupe>,
+    removed
 }
 
-impl Fddup {
-    pub fn new(options: Options) -> Fddup {
-        Fddup {
-            options,
-            poss_dupes: Vec::new(),
+// Paths of the files listed so far, each with the file's place in the list, so a
+// path given more than once is only considered once. Paths under different
+// stripped prefixes are the same input if the remainder of their paths match.
+#[derive(Default)]
+struct ListedPaths(HashMap<PathBuf, usize>);
+
+impl ListedPaths {
+    // Add a file to the list unless its path is already there, leaving files in
+    // the order they were first listed. Of the same input listed more than once,
+    // the smallest full path is kept, or with `--first-listed`, the first one.
+    fn add(&mut self, pd: PossDupe, options: &Options, result: &mut Vec<PossDupe>) {
+        let identity = input_identity(&pd.path, &options.strip_prefixes).to_path_buf();
+
+        match self.0.get(&identity) {
+            Some(&i) => {
+                if !options.first_listed && pd.path < result[i].path {
+                    result[i] = pd;
+                }
+            }
+            None => {
+                self.0.insert(identity, result.len());
+                result.push(pd);
+            }
         }
     }
+}
 
-    pub async fn run(&mut self) {
-        match self.run_impl().await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("{}", e)
+// What's known while files are stat'ed: the paths listed so far, with
+// `--size-index`, the files which needn't be stat'ed at all, and when listing
+// stops, with `--deadline` or once interrupted, and whether it has.
+struct StatState {
+    listed: ListedPaths,
+    size_index: Option<SizeIndex>,
+    deadline: Option<Instant>,
+    interrupted: fn() -> bool,
+    stopped: Option<Outcome>,
+}
+
+impl StatState {
+    // Return true once interrupted or the deadline has passed, after which nothing
+    // more is listed.
+    fn stopped(&mut self) -> bool {
+        if self.stopped.is_none() {
+            if (self.interrupted)() {
+                self.stopped = Some(Outcome::Interrupted);
+            } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                self.stopped = Some(Outcome::DeadlineReached);
             }
         }
+
+        self.stopped.is_some()
     }
 
-    async fn run_impl(&mut self) -> Result<()> {
-        let reader = crate::cli::input_stream(&self.options)?;
-        let mut writer = crate::cli::output_writer(&self.options)?;
-        let mut stats = crate::stats::Stats::new();
+    // What the size index says about a file, if it's there and says enough. A
+    // modification time is needed to filter by time, and on Windows, only stat can
+    // tell if a file is hidden. Files are opened to find their type with `--magic`,
+    // so they're stat'ed first, in case they're no longer regular files. `clean`
+    // only touches a file whose modification time is still the one it had when it
+    // was stat'ed, which an index may not know or may round, so it stats them all.
+    fn indexed(&self, path: &Path, options: &Options) -> Option<sizeindex::Entry> {
+        let entry = self.size_index.as_ref()?.get(path)?;
+        let needs_mtime = options.newer_than.is_some() || options.older_than.is_some();
 
-        self.poss_dupes = stat_files(reader)?;
-        remove_duplicate_paths(&mut self.poss_dupes);
-        sort_poss_dupes(&mut self.poss_dupes);
+        if (needs_mtime && entry.mtime.is_none())
+            || (cfg!(windows) && options.no_hidden)
+            || !options.magic.is_empty()
+            || options.clean.is_some()
+        {
+            return None;
+        }
 
-        // Keep going as long as we have some possibly duplicate files.
-        while !self.poss_dupes.is_empty() {
-            // Obtain a group of work equal to the number of configured threads,
-            // but we may obtain more files than the number of threads to ensure
-            // that all files of the same length are handled by the inner loop.
-            let mut w = algo::find_work(&mut self.poss_dupes, self.options.num_threads);
+        Some(entry)
+    }
+}
 
-            // Keep going with this group of work as long as there are possible
-            // duplicates or confirmed duplicates.
-            while !w.work.is_empty() || !w.duplicates.is_empty() || !w.uniques.is_empty() {
-                for unique in w.uniques.into_iter() {
-                    stats.unique(&unique);
+// Return true if a file's modification time is strictly after `newer_than` and
+// strictly before `older_than`, when given. A file without a modification time
+// can't be placed in a window, so it's only accepted when there's no window.
+fn in_time_window(
+    mtime: Option<SystemTime>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+) -> bool {
+    if newer_than.is_none() && older_than.is_none() {
+        return true;
+    }
+
+    match mtime {
+        Some(mtime) => newer_than.is_none_or(|t| mtime > t) && older_than.is_none_or(|t| mtime < t),
+        None => false,
+    }
+}
+
+// Return true if the file should be compared as normalized text. Normalized text
+// is read in one go, so files above the large file threshold never are.
+fn is_normalized_text(pd: &PossDupe, options: &Options) -> bool {
+    if pd.file_len > options.normalize_max_size
+        || options
+            .large_file_threshold
+            .is_some_and(|threshold| pd.file_len > threshold)
+    {
+        return false;
+    }
+
+    let name = match pd.path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+
+    options
+        .normalize_text
+        .iter()
+        .any(|pattern| crate::glob::matches(pattern, &name))
+}
+
+// Return the kind of I/O error which caused `e`, if any.
+fn io_error_kind(e: &anyhow::Error) -> Option<io::ErrorKind> {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map(|e| e.kind())
+}
+
+// Return the kind of error if the options say to skip files failing with it, or
+// the error itself if it should abort the run. Only permission denied, not found
+// and invalid data errors can be skipped, and files which shrank while being read
+// when they're being reported as incomplete; anything else is unexpected.
+fn skippable_error(e: anyhow::Error, options: &Options) -> Result<io::ErrorKind> {
+    let (kind, action) = match io_error_kind(&e) {
+        Some(kind @ io::ErrorKind::PermissionDenied) => (kind, options.on_permission_denied),
+        Some(kind @ io::ErrorKind::NotFound) => (kind, options.on_not_found),
+        Some(kind @ io::ErrorKind::InvalidData) => (kind, options.on_invalid),
+        Some(kind @ io::ErrorKind::UnexpectedEof) if options.report_incomplete.is_some() => {
+            (kind, ErrorAction::Skip)
+        }
+        _ => return Err(e),
+    };
+
+    match action {
+        ErrorAction::Skip => {
+            debug!("skipped after error: {:#}", e);
+            Ok(kind)
+        }
+        ErrorAction::Abort => Err(e),
+    }
+}
+
+// Skip a file which failed to be listed, if the options allow it, logging it. An
+// error walking a directory names the path it happened on itself.
+fn skip_stat_error(
+    e: anyhow::Error,
+    path: Option<&Path>,
+    options: &Options,
+    stats: &mut Stats,
+    error_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<()> {
+    let message = format!("{:#}", e);
+    let path = path
+        .or_else(|| crate::walk::error_path(&e))
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let kind = skippable_error(e, options)?;
+    stats.skipped_after_error(kind);
+    write_error(error_writer, kind, &message, &path)
+}
+
+// A file being decompressed with `--decompress`, or one which isn't compressed.
+enum Decompressing {
+    Done(Box<PossDupe>),
+    Task(tokio::task::JoinHandle<Result<PossDupe, ReadFailure>>),
+}
+
+// Decompress a file to hash its decompressed contents.
+fn decompress(mut pd: PossDupe) -> Result<PossDupe, ReadFailure> {
+    let result = pd.open_at(0).and_then(|file| {
+        let reader = crate::compress::decompress_file(file, &pd.path);
+        pd.decompress(reader)
+    });
+
+    match result {
+        Ok(()) => Ok(pd),
+        Err(error) => Err(ReadFailure {
+            path: pd.path,
+            error,
+        }),
+    }
+}
+
+// Hash the decompressed contents of the files compared decompressed with
+// `--decompress`. Their decompressed length is needed to group them, so they're
+// decompressed before anything else is read, but on blocking threads, up to
+// `--threads` at a time. A file which fails to decompress is skipped if the
+// options allow, as if it had failed to be listed.
+async fn decompress_files(
+    poss_dupes: Vec<PossDupe>,
+    options: &Options,
+    stats: &mut Stats,
+    error_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<Vec<PossDupe>> {
+    if !options.decompress {
+        return Ok(poss_dupes);
+    }
+
+    let permits = Arc::new(Semaphore::new(options.num_threads.max(1)));
+    let mut pending = Vec::with_capacity(poss_dupes.len());
+
+    for pd in poss_dupes {
+        if !is_decompressed(&pd, options) {
+            pending.push(Decompressing::Done(Box::new(pd)));
+            continue;
+        }
+
+        let permit = Arc::clone(&permits).acquire_owned().await?;
+        pending.push(Decompressing::Task(tokio::task::spawn_blocking(
+            move || {
+                let _permit = permit;
+                decompress(pd)
+            },
+        )));
+    }
+
+    let mut result = Vec::with_capacity(pending.len());
+
+    for decompressing in pending {
+        let decompressed = match decompressing {
+            Decompressing::Done(pd) => Ok(*pd),
+            Decompressing::Task(task) => task
+                .await
+                .map_err(|e| anyhow!("decompressing failed: {}", e))?,
+        };
+
+        match decompressed {
+            Ok(pd) => result.push(pd),
+            Err(failure) => skip_stat_error(
+                failure.error,
+                Some(&failure.path),
+                options,
+                stats,
+                error_writer,
+            )?,
+        }
+    }
+
+    Ok(result)
+}
+
+// Read filenames, one per line, from the given `BufRead` and stat each of them.
+// Listing stops once `interrupted` returns true or `deadline` has passed, and how
+// the run ends if it did is returned along with the files listed before it.
+fn stat_files(
+    mut reader: Box<dyn BufRead>,
+    options: &Options,
+    deadline: Option<Instant>,
+    interrupted: fn() -> bool,
+    stats: &mut Stats,
+    error_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<(Vec<PossDupe>, Option<Outcome>)> {
+    let mut result = Vec::new();
+    let mut state = StatState {
+        listed: ListedPaths::default(),
+        size_index: match &options.size_index {
+            Some(path) => Some(SizeIndex::load(path)?),
+            None => None,
+        },
+        deadline,
+        interrupted,
+        stopped: None,
+    };
+
+    match options.input_format {
+        InputFormat::Lines => {
+            for line in reader.split(b'\n') {
+                if state.stopped() {
+                    break;
                 }
 
-                // Display digest and filenames of any duplicates.
-                for duplicate in w.duplicates.into_iter() {
-                    stats.duplicate(&duplicate);
-
-                    if self.options.show_size {
-                        writer.write_fmt(format_args!(
-                            "{}  {}  {}\n",
-                            hex::encode(duplicate.key.digest_snapshot),
-                            duplicate.file_len,
-                            duplicate.path.to_str().unwrap()
-                        ))?;
-                    } else {
-                        writer.write_fmt(format_args!(
-                            "{}  {}\n",
-                            hex::encode(duplicate.key.digest_snapshot),
-                            duplicate.path.to_str().unwrap()
-                        ))?;
-                    }
+                let mut line = line.with_context(|| "couldn't read the list of files")?;
+
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                let path = crate::platform::path_from_bytes(line)?;
+
+                stat_path(
+                    path.clone(),
+                    true,
+                    options,
+                    &mut state,
+                    &mut result,
+                    stats,
+                    error_writer,
+                )
+                .or_else(|e| skip_stat_error(e, Some(&path), options, stats, error_writer))?;
+            }
+        }
+        InputFormat::Listing => {
+            for (number, line) in reader.split(b'\n').enumerate() {
+                if state.stopped() {
+                    break;
                 }
 
-                // Create tasks, one per possible duplicate. Each task is spawned
-                // and will open the file (if it's not already open), perform a
-                // single read of the configured size, and update the digest.
-                let mut tasks = Vec::new();
+                let mut line = line.with_context(|| "couldn't read the list of files")?;
 
-                for pd in w.work.into_iter() {
-                    let task = tokio::spawn(read_poss_dupe(pd, self.options.read_size));
-                    tasks.push(task);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
                 }
 
-                let mut results = vec![];
+                let name = match crate::listing::parse_line(&line).with_context(|| {
+                    format!(
+                        "line {} of the list of files isn't a directory listing entry",
+                        number + 1
+                    )
+                })? {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let path = crate::platform::path_from_bytes(name)?;
+
+                stat_path(
+                    path.clone(),
+                    true,
+                    options,
+                    &mut state,
+                    &mut result,
+                    stats,
+                    error_writer,
+                )
+                .or_else(|e| skip_stat_error(e, Some(&path), options, stats, error_writer))?;
+            }
+        }
+        InputFormat::Json => {
+            let mut text = String::new();
+            reader
+                .read_to_string(&mut text)
+                .with_context(|| "the list of files isn't a valid unicode string")?;
+
+            let filenames = crate::json::parse_string_array(&text)
+                .map_err(|e| anyhow!("invalid JSON list of files: {}", e))?;
 
-                // Join up with the tasks, tracking the results for each.
-                for t in tasks {
-                    let pd = tokio::join!(t).0.unwrap().unwrap();
-                    results.push(pd);
+            for filename in filenames {
+                if state.stopped() {
+                    break;
                 }
 
-                sort_poss_dupes(&mut results);
+                let path = PathBuf::from(filename);
 
-                // Find work again, but only on the subset of work for this loop. Note that we use
-                // usize::MAX here rather than the configured number of threads. This is because
-                // it's possible that in the earlier call to `find_work` may have obtained more
-                // files than the configured number of threads if the number of files for a single
-                // size spanned the remaining number of threads.
-                w = algo::find_work(&mut results, usize::MAX);
+                stat_path(
+                    path.clone(),
+                    true,
+                    options,
+                    &mut state,
+                    &mut result,
+                    stats,
+                    error_writer,
+                )
+                .or_else(|e| skip_stat_error(e, Some(&path), options, stats, error_writer))?;
             }
         }
+    }
 
-        if self.options.verbose {
-            stats.display()?;
+    if !options.roots.is_empty() {
+        for pd in result.iter_mut() {
+            pd.root = crate::roots::root_of(&pd.path, &options.roots);
         }
+    }
 
-        Ok(())
+    if options.same_name {
+        for pd in result.iter_mut() {
+            pd.key.name = pd.path.file_name().map(Arc::from);
+        }
     }
+
+    Ok((result, state.stopped))
 }
 
-thread_local! {
-    // Re-use the same buffer for reading in each thread.
-    pub static BUFFER: RefCell<[u8; crate::cli::MAX_READ_BUFFER_SIZE]> =
-        RefCell::new([0; crate::cli::MAX_READ_BUFFER_SIZE]);
+// Return the path used to recognize the same input given more than once: the
+// path with the first matching prefix stripped, or the path itself.
+fn input_identity<'a>(path: &'a Path, strip_prefixes: &[PathBuf]) -> &'a Path {
+    strip_prefixes
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix).ok())
+        .unwrap_or(path)
 }
 
-// In the thread pool, asynchronously open the file if needed, perform a read operation,
-// and hash the data.
-async fn read_poss_dupe(mut poss_dupe: PossDupe, read_size: usize) -> Result<PossDupe> {
-    poss_dupe.open()?;
+// Keep only the files which the `--filter-cmd` command keeps. The command isn't
+// run at all when there's nothing to filter.
+fn filter_poss_dupes(poss_dupes: &mut Vec<PossDupe>, cmd: &str) -> Result<()> {
+    if poss_dupes.is_empty() {
+        return Ok(());
+    }
 
-    BUFFER.with(|b| {
-        let mut buffer = *b.borrow_mut();
-        let to_read = min(read_size as u64, poss_dupe.bytes_remaining()) as usize;
+    let paths: Vec<&Path> = poss_dupes.iter().map(|pd| pd.path.as_path()).collect();
+    let kept = crate::filter::run(cmd, &paths)?;
+
+    poss_dupes.retain(|pd| {
+        let keep = kept.contains(&pd.path);
 
-        if let Some(file) = &mut poss_dupe.file {
-            let bytes_read = file.read(&mut buffer[0..to_read])?;
-            assert!(bytes_read == to_read);
-            poss_dupe.bytes_read += bytes_read as u64;
-            poss_dupe.update_digest(&buffer[0..bytes_read]);
+        if !keep {
+            debug!(
+                "skipped file rejected by filter command: {}",
+                pd.path.display()
+            );
         }
 
-        Ok(poss_dupe)
-    })
+        keep
+    });
+
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Sort our possible duplicates by length, name with `--same-name`, and digest
+// snapshot, then by path, so files with the same key are always in the same order
+// however their reads finished, and so are popped by `find_work` and written in
+// the same order.
+fn sort_poss_dupes(poss_dupes: &mut [PossDupe]) {
+    poss_dupes.sort_by(|a, b| {
+        a.key
+            .bucket()
+            .cmp(&b.key.bucket())
+            .then_with(|| a.key.digest_snapshot.cmp(&b.key.digest_snapshot))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+}
 
-    fn mk_pd(path: &str, len: u64) -> PossDupe {
-        PossDupe::new(path, len)
+// Bucket possible duplicates by size, and by name with `--same-name`. Files with
+// a unique size can't have a duplicate, so they're returned separately without
+// any sorting or hashing. The rest are returned ordered by size, which is how
+// `find_work` expects them since nothing has been hashed yet.
+fn bucket_by_size(poss_dupes: Vec<PossDupe>) -> (Vec<PossDupe>, Vec<PossDupe>) {
+    let mut buckets: HashMap<_, Vec<PossDupe>> = HashMap::new();
+
+    for pd in poss_dupes {
+        let bucket = (pd.key.len, pd.key.name.clone());
+        buckets.entry(bucket).or_default().push(pd);
     }
 
-    impl PartialEq for PossDupe {
-        fn eq(&self, other: &Self) -> bool {
-            self.path == other.path
-                && self.key.len == other.key.len
-                && self.bytes_read == other.bytes_read
+    let mut uniques = Vec::new();
+    let mut shared = Vec::new();
+
+    for (_, bucket) in buckets {
+        if bucket.len() == 1 {
+            uniques.extend(bucket);
+        } else {
+            shared.push(bucket);
         }
     }
 
-    #[test]
-    fn remove_duplicate_paths() {
-        let mut pd = Vec::new();
-        pd.push(mk_pd("a", 10));
-        pd.push(mk_pd("b", 10));
-        pd.push(mk_pd("a", 10));
+    uniques.sort_by(|a, b| a.path.cmp(&b.path));
+    shared.sort_by(|a, b| a[0].key.bucket().cmp(&b[0].key.bucket()));
 
-        // Shouldn't be possible to have the same path with different lengths
-        // unless the length were to change during iteration, but if we do,
-        // treat it as a duplicate.
-        pd.push(mk_pd("b", 11));
+    (uniques, shared.into_iter().flatten().collect())
+}
+
+// Remove empty files from possible duplicates ordered by size, returning them.
+// Empty files are all identical, so there's no need to hash them. A file whose
+// normalized contents are empty isn't itself empty, so it stays to be compared.
+fn split_empty(shared: &mut Vec<PossDupe>) -> Vec<PossDupe> {
+    let num_zero = shared.iter().take_while(|pd| pd.key.len == 0).count();
+    let (empty, blank): (Vec<_>, Vec<_>) =
+        shared.drain(..num_zero).partition(|pd| pd.file_len == 0);
+    shared.splice(..0, blank);
+    empty
+}
 
-        crate::fddup::remove_duplicate_paths(&mut pd);
+// Split confirmed duplicates into groups of identical files. `find_work` returns
+// duplicates in sorted order, so members of the same group are adjacent.
+fn split_groups(duplicates: Vec<PossDupe>) -> Vec<Vec<PossDupe>> {
+    let mut groups: Vec<Vec<PossDupe>> = Vec::new();
 
-        assert_eq!(pd, vec![mk_pd("a", 10), mk_pd("b", 10)]);
+    for duplicate in duplicates {
+        match groups.last_mut() {
+            Some(group) if group[0].key == duplicate.key => group.push(duplicate),
+            _ => groups.push(vec![duplicate]),
+        }
     }
 
-    #[test]
-    fn sort_poss_dupes() {
-        let mut pd = Vec::new();
+    groups
+}
 
-        // "c" and "d" have been read some
-        let mut d = mk_pd("d", 300);
-        d.update_digest(&['a' as u8]);
-        pd.push(d);
+// Split files ordered by size into groups of the same size, and the same name
+// with `--same-name`.
+fn split_by_size(shared: Vec<PossDupe>) -> Vec<Vec<PossDupe>> {
+    let mut groups: Vec<Vec<PossDupe>> = Vec::new();
 
-        let mut c = mk_pd("c", 300);
-        c.update_digest(&['b' as u8]);
-        pd.push(c);
+    for pd in shared {
+        match groups.last_mut() {
+            Some(group) if group[0].key.bucket() == pd.key.bucket() => group.push(pd),
+            _ => groups.push(vec![pd]),
+        }
+    }
 
-        // "a" and "b" haven't yet been read
-        pd.push(mk_pd("a", 100));
-        pd.push(mk_pd("b", 200));
+    groups
+}
 
-        crate::fddup::sort_poss_dupes(&mut pd);
+// Verify each group byte for byte, splitting groups whose contents differ despite
+// matching digests. Files left without a duplicate are added to `uniques`. With a
+// full-length digest, a group should never split; if one does, it's either a
+// genuine SHA-256 collision or a bug in hashing, so warn loudly.
+fn verify_groups(
+    groups: Vec<Vec<PossDupe>>,
+    read_size: usize,
+    large_file_threshold: Option<u64>,
+    stats: &mut Stats,
+    uniques: &mut Vec<PossDupe>,
+) -> Result<Vec<Vec<PossDupe>>> {
+    let mut verified = Vec::new();
 
-        assert_eq!(
-            pd,
-            vec![
-                mk_pd("a", 100),
-                mk_pd("b", 200),
-                mk_pd("c", 300),
-                mk_pd("d", 300)
-            ]
+    for group in groups {
+        let full_digest = group
+            .first()
+            .is_some_and(|pd| usize::from(pd.digest_len) == DIGEST_LEN);
+        let read_size = group.first().map_or(read_size, |pd| {
+            capped_read_size(pd, read_size, large_file_threshold)
+        });
+        let subgroups = crate::verify::verify_group(group, read_size)?;
+
+        if full_digest {
+            for subgroup in subgroups.iter().skip(1) {
+                eprintln!(
+                    "warning: hash collision: {} and {} have the same size and digest but different contents",
+                    crate::platform::display_path(&subgroups[0][0].path),
+                    crate::platform::display_path(&subgroup[0].path)
+                );
+                stats.collision();
+            }
+        }
+
+        for subgroup in subgroups {
+            if subgroup.len() > 1 {
+                verified.push(subgroup);
+            } else {
+                uniques.extend(subgroup);
+            }
+        }
+    }
+
+    Ok(verified)
+}
+
+// Number of paths listed when warning about a large group
+const LARGE_GROUP_SAMPLE: usize = 3;
+
+// Return true if a group of duplicates has fewer files than `--min-copies`, so
+// it isn't reported.
+fn too_few_copies(group: &[PossDupe], min_copies: usize) -> bool {
+    group.len() < min_copies
+}
+
+// Return true if every file in a group of duplicates is in the same directory, so
+// it isn't reported with `--exclude-same-dir`.
+fn same_dir(group: &[PossDupe]) -> bool {
+    group
+        .windows(2)
+        .all(|pair| pair[0].path.parent() == pair[1].path.parent())
+}
+
+// Warning for a group with suspiciously many files, such as from a sync job which
+// has gone wrong, listing a few of its paths.
+fn large_group_warning(group: &[PossDupe]) -> String {
+    let sample: Vec<_> = group
+        .iter()
+        .take(LARGE_GROUP_SAMPLE)
+        .map(|pd| crate::platform::display_path(&pd.path))
+        .collect();
+    let more = group.len().saturating_sub(LARGE_GROUP_SAMPLE);
+
+    format!(
+        "warning: {} files have the same contents: {}{}",
+        group.len(),
+        sample.join(", "),
+        if more > 0 {
+            format!(" and {} more", more)
+        } else {
+            String::new()
+        }
+    )
+}
+
+// Flush an optional listing before returning, so a failed write, such as to a
+// full disk, is an error rather than lost when the writer is dropped.
+fn flush_writer(writer: &mut Option<BufWriter<Box<dyn Write + Send>>>) -> Result<()> {
+    if let Some(writer) = writer {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// Account for a file found to be unique, listing it if it was unique by size.
+fn report_unique(
+    unique: &PossDupe,
+    stats: &mut Stats,
+    skipped_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<()> {
+    stats.unique(unique);
+
+    // Files which are unique before any reads were unique by size, or known to be
+    // unique from the last run.
+    if let Some(writer) = skipped_writer {
+        if unique.bytes_read == 0 {
+            writer.write_all(&crate::platform::path_bytes(&unique.path))?;
+            writer.write_all(b"\n")?;
+        }
+    }
+
+    Ok(())
+}
+
+// With `--full-hash`, move files found to be unique before being read in full
+// from `uniques` to `finishing`, closing them until it's their turn to be read.
+fn set_aside_unfinished(uniques: &mut Vec<PossDupe>, finishing: &mut Vec<PossDupe>) {
+    let (unfinished, finished): (Vec<_>, Vec<_>) = std::mem::take(uniques)
+        .into_iter()
+        .partition(|pd| pd.bytes_remaining() > 0);
+
+    *uniques = finished;
+    finishing.extend(unfinished.into_iter().map(close));
+}
+
+// Drop a file which couldn't be read if the options allow it, counting, listing
+// and logging it.
+fn skip_read_failure(
+    failure: ReadFailure,
+    options: &Options,
+    stats: &mut Stats,
+    incomplete_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+    error_writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+) -> Result<()> {
+    let message = format!("{:#}", failure.error);
+    let kind = skippable_error(failure.error, options)?;
+    stats.skipped_after_read_error(kind);
+    write_incomplete(incomplete_writer, incomplete_reason(kind), &failure.path)?;
+    write_error(error_writer, kind, &message, &failure.path)
+}
+
+// Why a file which failed to be read couldn't be told to be unique or a duplicate.
+fn incomplete_reason(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::PermissionDenied => "permission denied",
+        io::ErrorKind::NotFound => "not found",
+        io::ErrorKind::UnexpectedEof => "truncated",
+        io::ErrorKind::InvalidData => "invalid",
+        _ => "error",
+    }
+}
+
+// List a file which couldn't be told to be unique or a duplicate, with the reason.
+fn write_incomplete(
+    writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+    reason: &str,
+    path: &Path,
+) -> Result<()> {
+    if let Some(writer) = writer {
+        write!(writer, "{}\t", reason)?;
+        writer.write_all(&crate::platform::path_bytes(path))?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+// Name of a kind of error which a file was skipped after, for `--error-log`.
+fn error_kind_name(kind: io::ErrorKind) -> &'static str {
+    match kind {
+        io::ErrorKind::PermissionDenied => "permission-denied",
+        io::ErrorKind::NotFound => "not-found",
+        io::ErrorKind::UnexpectedEof => "truncated",
+        io::ErrorKind::InvalidData => "invalid",
+        _ => "other",
+    }
+}
+
+// Log a file which was skipped after an error as one line: the kind of error, a
+// tab, the message, a tab, and the path. The message is kept on one line and free
+// of tabs so every line splits into the same three fields.
+fn write_error(
+    writer: &mut Option<BufWriter<Box<dyn Write + Send>>>,
+    kind: io::ErrorKind,
+    message: &str,
+    path: &Path,
+) -> Result<()> {
+    if let Some(writer) = writer {
+        let message = message.replace(['\t', '\n', '\r'], " ");
+        write!(writer, "{}\t{}\t", error_kind_name(kind), message)?;
+        writer.write_all(&crate::platform::path_bytes(path))?;
+        writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+// Largest read from a file above `--large-file-threshold`
+const LARGE_FILE_READ_SIZE: usize = crate::cli::DEFAULT_READ_SIZE;
+
+// Size of reads from a file, which is capped for files above the large file
+// threshold so a large read size doesn't mean a large buffer for every read of a
+// huge file. The cap depends only on the file's length, so files of the same
+// length are still read in lockstep.
+fn capped_read_size(pd: &PossDupe, read_size: usize, large_file_threshold: Option<u64>) -> usize {
+    match large_file_threshold {
+        Some(threshold) if pd.key.len > threshold => read_size.min(LARGE_FILE_READ_SIZE),
+        _ => read_size,
+    }
+}
+
+// Size of the next read from a file. The first read is often enough to tell
+// files of the same size apart, so it may be smaller than later reads.
+fn next_read_size(
+    pd: &PossDupe,
+    first_read_size: usize,
+    read_size: usize,
+    large_file_threshold: Option<u64>,
+) -> usize {
+    let read_size = if pd.bytes_read == 0 {
+        first_read_size
+    } else {
+        read_size
+    };
+
+    capped_read_size(pd, read_size, large_file_threshold)
+}
+
+// Close a file which is no longer being read, so files kept for later don't hold
+// a descriptor each.
+fn close(mut pd: PossDupe) -> PossDupe {
+    pd.file = None;
+    pd
+}
+
+// Cluster near duplicates among files found to be unique and those kept from
+// groups of duplicates. Files which can't be read are left out if the options
+// allow it; they've already been counted.
+fn find_near_duplicates(
+    uniques: Vec<PossDupe>,
+    tolerance: f64,
+    options: &Options,
+) -> Result<Vec<Vec<PossDupe>>> {
+    let mut fingerprinted = Vec::new();
+
+    for pd in crate::near::candidates(uniques, tolerance) {
+        match crate::near::fingerprint(&pd, crate::near::BLOCK_SIZE) {
+            Ok(fingerprint) => fingerprinted.push((pd, fingerprint)),
+            Err(e) => {
+                skippable_error(e, options)?;
+            }
+        }
+    }
+
+    Ok(crate::near::clusters(fingerprinted, tolerance))
+}
+
+// Find the next work, either counting uniques as they're found or returning them.
+fn next_work(
+    possible: &mut Vec<PossDupe>,
+    desired: usize,
+    stats: &mut Stats,
+    stream_uniques: bool,
+) -> Work<PossDupe> {
+    if stream_uniques {
+        algo::find_work_with(possible, desired, |unique| stats.unique(&unique))
+    } else {
+        algo::find_work(possible, desired)
+    }
+}
+
+// Trace how `find_work` classified each file in a round.
+fn debug_work(w: &Work<PossDupe>) {
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+
+    for (kind, poss_dupes) in [
+        ("work", &w.work),
+        ("duplicate", &w.duplicates),
+        ("unique", &w.uniques),
+    ] {
+        for pd in poss_dupes.iter() {
+            debug!(
+                "{}: {} ({}/{} bytes read, digest {})",
+                kind,
+                pd.path.display(),
+                pd.bytes_read,
+                pd.bytes_to_read(),
+                pd.digest_hex()
+            );
+        }
+    }
+}
+
+// Number of groups of files sharing the same key in files grouped by key.
+fn count_groups(poss_dupes: &[PossDupe]) -> usize {
+    poss_dupes
+        .windows(2)
+        .filter(|w| w[0].key != w[1].key)
+        .count()
+        + usize::from(!poss_dupes.is_empty())
+}
+
+// Number of threads worth scheduling work for: there's no point in more threads
+// than there are files left to read.
+fn effective_threads(num_threads: usize, pending: usize) -> usize {
+    num_threads.min(pending).max(1)
+}
+
+// Threads per logical CPU above which more threads usually only add contention
+const MAX_THREADS_PER_CPU: usize = 32;
+
+// Warning for a number of threads far above the number of logical CPUs, which is
+// only a warning since it can pay off with storage that's slow to respond.
+fn threads_warning(num_threads: usize, cpus: usize) -> Option<String> {
+    if num_threads <= cpus.saturating_mul(MAX_THREADS_PER_CPU) {
+        return None;
+    }
+
+    Some(format!(
+        "warning: {} threads for {} logical CPUs is more than {} per CPU, which usually makes fddup slower",
+        num_threads, cpus, MAX_THREADS_PER_CPU
+    ))
+}
+
+// How a run ended, which determines the process's exit status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Complete,
+    Interrupted,
+    Collision,
+    DeadlineReached,
+    CleanupFailed,
+    ReadLimitReached,
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Complete => 0,
+            Outcome::Interrupted => 130,
+            Outcome::Collision => 3,
+            Outcome::DeadlineReached => 4,
+            Outcome::CleanupFailed => 5,
+            Outcome::ReadLimitReached => 6,
+        }
+    }
+}
+
+pub struct Fddup {
+    options: Options,
+    poss_dupes: Vec<PossDupe>,
+    on_file: Vec<OnFile>,
+}
+
+impl Fddup {
+    pub fn new(options: Options) -> Fddup {
+        Fddup {
+            options,
+            poss_dupes: Vec::new(),
+            on_file: Vec::new(),
+        }
+    }
+
+    // Call `f` with every file once it's found to be unique or a duplicate, when
+    // its digest and the number of bytes read from it are final, so callers can
+    // keep their own index of files. Nothing extra is kept unless a callback is
+    // given. An error from `f` stops the run.
+    pub fn on_file<F>(&mut self, f: F)
+    where
+        F: FnMut(&PossDupe) -> Result<()> + Send + 'static,
+    {
+        self.on_file.push(Box::new(f));
+    }
+
+    // Run to completion, returning the process's exit status.
+    pub async fn run(&mut self) -> i32 {
+        match self.run_impl().await {
+            Ok(outcome) => outcome.exit_code(),
+            Err(e) => {
+                error!("{}", e);
+                1
+            }
+        }
+    }
+
+    async fn run_impl(&mut self) -> Result<Outcome> {
+        crate::cli::check_outputs(&self.options)?;
+
+        if self.options.verbosity > 0 {
+            eprintln!("{}", self.options.summary());
+        }
+
+        if let Some(warning) = threads_warning(self.options.num_threads, num_cpus::get()) {
+            eprintln!("{}", warning);
+        }
+
+        let reader = crate::cli::input_stream(&self.options)?;
+        let mut run = self.start_run()?;
+        let (poss_dupes, listing_stopped) = self.stat_phase(reader, &mut run).await?;
+
+        if let Some(outcome) = listing_stopped {
+            return stop_listing(run, &poss_dupes, outcome);
+        }
+
+        let (uniques, shared) = self.bloom_phase(poss_dupes, &mut run)?;
+
+        if self.options.plan {
+            return show_plan(run, &uniques, &shared);
+        }
+
+        if self.options.size_only {
+            return self.write_by_size(run, uniques, shared);
+        }
+
+        self.read_phase(uniques, shared, &mut run).await?;
+        self.output_phase(run)
+    }
+
+    // Open everything a run writes to, and hand the callbacks given with `on_file`
+    // to its stats.
+    fn start_run(&mut self) -> Result<RunState> {
+        let output = Output::new(crate::cli::output_writer(&self.options)?, &self.options)?;
+        let output = OutputThread::spawn(output);
+        let skipped_writer = match &self.options.list_skipped {
+            Some(filename) => Some(crate::cli::file_writer(filename, self.options.output_mode)?),
+            None => None,
+        };
+        let incomplete_writer = match &self.options.report_incomplete {
+            Some(filename) => Some(crate::cli::file_writer(filename, self.options.output_mode)?),
+            None => None,
+        };
+        let error_writer = match &self.options.error_log {
+            Some(filename) => Some(crate::cli::file_writer(filename, self.options.output_mode)?),
+            None => None,
+        };
+        let manifest = match &self.options.write_manifest {
+            Some(filename) => Some(Arc::new(Mutex::new(Manifest::new(
+                filename,
+                self.options.output_mode,
+            )?))),
+            None => None,
+        };
+
+        // Each file is written to the manifest once it's found to be unique or a
+        // duplicate.
+        if let Some(manifest) = &manifest {
+            let manifest = Arc::clone(manifest);
+            self.on_file(move |pd| manifest.lock().unwrap().write(pd));
+        }
+
+        let mut stats = Stats::new();
+
+        for on_file in self.on_file.drain(..) {
+            stats.on_file(on_file);
+        }
+
+        Ok(RunState {
+            timing: Timing::new(),
+            stats,
+            output,
+            skipped_writer,
+            incomplete_writer,
+            error_writer,
+            manifest,
+            status: self.options.status_file.as_ref().map(StatusFile::new),
+            deadline: None,
+            interrupted: crate::interrupt::interrupted,
+            next_bloom: None,
+            collision_estimate: None,
+            reflinker: None,
+            cleaner: None,
+            script: None,
+            near_candidates: None,
+            finishing: Vec::new(),
+            outcome: Outcome::Complete,
+            undetermined: None,
+        })
+    }
+
+    // List the files to compare, leaving out other paths to files already listed,
+    // files the `--filter-cmd` command doesn't keep and files which fail to
+    // decompress. If listing stopped, at the deadline or because the run was
+    // interrupted, how the run ends is returned too.
+    async fn stat_phase(
+        &self,
+        reader: Box<dyn BufRead>,
+        run: &mut RunState,
+    ) -> Result<(Vec<PossDupe>, Option<Outcome>)> {
+        let started = Instant::now();
+        run.deadline = self.options.deadline.map(|deadline| started + deadline);
+        let (mut poss_dupes, listing_stopped) = stat_files(
+            reader,
+            &self.options,
+            run.deadline,
+            run.interrupted,
+            &mut run.stats,
+            &mut run.error_writer,
+        )?;
+
+        if listing_stopped.is_some() {
+            return Ok((poss_dupes, listing_stopped));
+        }
+
+        let other_paths = remove_other_paths(&mut poss_dupes);
+
+        if self.options.verbosity > 0 && !other_paths.is_empty() {
+            eprintln!(
+                "{} paths were other paths to files already listed and won't be read",
+                other_paths.len()
+            );
+        }
+
+        if let Some(writer) = &mut run.skipped_writer {
+            for path in &other_paths {
+                writer.write_all(&crate::platform::path_bytes(path))?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        if let Some(cmd) = &self.options.filter_cmd {
+            filter_poss_dupes(&mut poss_dupes, cmd)?;
+        }
+
+        let poss_dupes = decompress_files(
+            poss_dupes,
+            &self.options,
+            &mut run.stats,
+            &mut run.error_writer,
+        )
+        .await?;
+
+        // Filtering and decompressing can take as long as listing, so an interrupt
+        // while they run stops the run before anything is read, too.
+        if (run.interrupted)() {
+            return Ok((poss_dupes, Some(Outcome::Interrupted)));
+        }
+
+        run.timing.stat(started);
+        run.stats.set_total_files(poss_dupes.len());
+
+        Ok((poss_dupes, None))
+    }
+
+    // Split the files listed into those which are unique, by their size or because
+    // the filter written by the last run says they were, and those which share their
+    // size with another file and go on to be read. The filter for the next run
+    // records every file this run finds to be unique or a duplicate.
+    fn bloom_phase(
+        &self,
+        poss_dupes: Vec<PossDupe>,
+        run: &mut RunState,
+    ) -> Result<(Vec<PossDupe>, Vec<PossDupe>)> {
+        let settings = crate::bloom::comparison_settings(&self.options);
+        let previous_bloom = match &self.options.bloom {
+            Some(path) => Bloom::load(path, &settings)?,
+            None => None,
+        };
+        run.next_bloom = self
+            .options
+            .bloom
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(Bloom::new(poss_dupes.len(), settings))));
+
+        if let Some(bloom) = &run.next_bloom {
+            let seen = Arc::clone(bloom);
+            run.stats.on_file(Box::new(move |pd| {
+                seen.lock().unwrap().insert_file(pd, false);
+                Ok(())
+            }));
+
+            let unique = Arc::clone(bloom);
+            run.stats.on_unique(Box::new(move |pd| {
+                unique.lock().unwrap().insert_file(pd, true);
+                Ok(())
+            }));
+        }
+
+        // Files with a unique size are dealt with immediately; only files which
+        // share their size with another file go on to be hashed.
+        let (mut uniques, mut shared) = bucket_by_size(poss_dupes);
+        run.collision_estimate = self
+            .options
+            .collision_estimate
+            .then(|| CollisionEstimate::new(&shared, self.options.digest_bits));
+
+        if self.options.verbosity > 0 && !self.options.plan {
+            let plan = Plan::new(&uniques, &shared);
+            eprintln!("{}", plan.unique_size_summary(self.options.full_hash)?);
+        }
+
+        if let Some(previous) = &previous_bloom {
+            let (known, rest) = previous.split_known_uniques(shared);
+
+            if self.options.verbosity > 0 {
+                eprintln!(
+                    "{} files were unique in the last run and won't be read",
+                    known.len()
+                );
+            }
+
+            shared = rest;
+            uniques.extend(known);
+            uniques.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        Ok((uniques, shared))
+    }
+
+    // With `--size-only`, write every group of files sharing a size as duplicates,
+    // without reading any of them.
+    fn write_by_size(
+        &self,
+        mut run: RunState,
+        uniques: Vec<PossDupe>,
+        shared: Vec<PossDupe>,
+    ) -> Result<Outcome> {
+        for unique in uniques.iter() {
+            report_unique(unique, &mut run.stats, &mut run.skipped_writer)?;
+        }
+
+        for mut group in split_by_size(shared) {
+            crate::keep::order_group(&self.options.keep, &mut group);
+
+            let hidden = too_few_copies(&group, self.options.min_copies)
+                || (self.options.exclude_same_dir && same_dir(&group));
+
+            if !hidden {
+                run.output.write_group(group)?;
+            }
+        }
+
+        run.output.join()?.flush()?;
+        flush_writer(&mut run.skipped_writer)?;
+        flush_writer(&mut run.error_writer)?;
+
+        if let Some(manifest) = &run.manifest {
+            manifest.lock().unwrap().flush()?;
+        }
+
+        if let Some(status) = &mut run.status {
+            status.finish(&run.stats, "complete")?;
+        }
+
+        Ok(Outcome::Complete)
+    }
+
+    // Read the files which share their size a round at a time, until each is known
+    // to be unique or a duplicate, or reading stops early, writing each group of
+    // duplicates as soon as it's found.
+    async fn read_phase(
+        &mut self,
+        uniques: Vec<PossDupe>,
+        shared: Vec<PossDupe>,
+        run: &mut RunState,
+    ) -> Result<()> {
+        self.poss_dupes = shared;
+
+        // Summarize empty files as a count rather than listing them as a group.
+        if self.options.separate_empty {
+            let empty = split_empty(&mut self.poss_dupes);
+
+            if !empty.is_empty() {
+                run.stats.duplicates(&empty);
+
+                eprintln!("{} empty files", empty.len());
+            }
+        }
+
+        // With --full-hash, files are read to the end even once they're unique,
+        // and only reported once they have been.
+        let mut uniques = uniques;
+
+        if self.options.full_hash {
+            set_aside_unfinished(&mut uniques, &mut run.finishing);
+        }
+
+        for unique in uniques.iter() {
+            report_unique(unique, &mut run.stats, &mut run.skipped_writer)?;
+        }
+
+        // Files found to be unique, and the file kept from each group of duplicates,
+        // are kept to look for near duplicates among them once every duplicate is
+        // known.
+        run.near_candidates = self.options.size_tolerance.map(|_| Vec::new());
+
+        if let Some(near_candidates) = &mut run.near_candidates {
+            near_candidates.extend(uniques);
+        }
+
+        if let Some(status) = &mut run.status {
+            status.update(&run.stats)?;
+        }
+
+        // Uniques are only counted unless they're listed, traced or compared for
+        // near duplicates, so they can be dropped as soon as they're found.
+        let stream_uniques = run.skipped_writer.is_none()
+            && run.near_candidates.is_none()
+            && !self.options.full_hash
+            && !log_enabled!(Level::Debug);
+
+        run.reflinker = self.options.reflink.then(Reflinker::default);
+        run.cleaner = self
+            .options
+            .clean
+            .map(|action| Cleaner::new(action, self.options.dry_run));
+        run.script = match &self.options.emit_script {
+            Some((action, filename)) => {
+                Some(Script::new(filename, self.options.output_mode, *action)?)
+            }
+            None => None,
+        };
+
+        let stages = self
+            .options
+            .hash_threads
+            .map(|hash_threads| Stages::new(self.options.num_threads, hash_threads));
+        let mut bytes_read = 0;
+
+        // Keep going as long as we have some possibly duplicate files, or unique
+        // files still to be read to the end.
+        while !self.poss_dupes.is_empty() || !run.finishing.is_empty() {
+            // Obtain a group of work equal to the number of configured threads times
+            // the batch multiplier, but we may obtain more files than that to ensure
+            // that all files of the same length are handled by the inner loop.
+            let desired = effective_threads(self.options.num_threads, self.poss_dupes.len())
+                .saturating_mul(self.options.batch_multiplier);
+            let mut w = next_work(
+                &mut self.poss_dupes,
+                desired,
+                &mut run.stats,
+                stream_uniques,
+            );
+
+            // Keep going with this group of work as long as there are possible
+            // duplicates or confirmed duplicates.
+            while !w.work.is_empty()
+                || !w.duplicates.is_empty()
+                || !w.uniques.is_empty()
+                || !run.finishing.is_empty()
+            {
+                debug_work(&w);
+
+                let mut uniques = w.uniques;
+                let mut groups = split_groups(w.duplicates);
+
+                if self.options.verify {
+                    groups = verify_groups(
+                        groups,
+                        self.options.read_size,
+                        self.options.large_file_threshold,
+                        &mut run.stats,
+                        &mut uniques,
+                    )?;
+                }
+
+                if self.options.full_hash {
+                    set_aside_unfinished(&mut uniques, &mut run.finishing);
+                }
+
+                for unique in uniques.iter() {
+                    report_unique(unique, &mut run.stats, &mut run.skipped_writer)?;
+                }
+
+                if let Some(near_candidates) = &mut run.near_candidates {
+                    near_candidates.extend(uniques.into_iter().map(close));
+                }
+
+                self.write_groups(groups, run)?;
+                run.stats.on_file_result()?;
+
+                if let Some(status) = &mut run.status {
+                    status.update(&run.stats)?;
+                }
+
+                if self.stop_reading(&w.work, bytes_read, run)? {
+                    return Ok(());
+                }
+
+                // Unique files are finished a batch at a time, from the front, so
+                // only one batch of them is open at once.
+                let num_finishing =
+                    effective_threads(self.options.num_threads, run.finishing.len())
+                        .saturating_mul(self.options.batch_multiplier)
+                        .min(run.finishing.len());
+                let finishing_now: Vec<PossDupe> = run.finishing.drain(..num_finishing).collect();
+
+                bytes_read += w
+                    .work
+                    .iter()
+                    .chain(finishing_now.iter())
+                    .map(|pd| pd.next_read(self.read_size(pd) as u64).1)
+                    .sum::<u64>();
+
+                let mut results = self
+                    .read_round(w.work, finishing_now, stages.as_ref(), run)
+                    .await?;
+
+                // Find work again, but only on the subset of work for this loop. Note that we use
+                // usize::MAX here rather than the configured number of threads. This is because
+                // it's possible that in the earlier call to `find_work` may have obtained more
+                // files than the configured number of threads if the number of files for a single
+                // size spanned the remaining number of threads.
+                w = next_work(&mut results, usize::MAX, &mut run.stats, stream_uniques);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Size of the next read from a file.
+    fn read_size(&self, pd: &PossDupe) -> usize {
+        next_read_size(
+            pd,
+            self.options.first_read_size,
+            self.options.read_size,
+            self.options.large_file_threshold,
+        )
+    }
+
+    // Display digest and filenames of each group of duplicates found in a round,
+    // with the file to keep listed first, and act on the group as asked.
+    fn write_groups(&self, groups: Vec<Vec<PossDupe>>, run: &mut RunState) -> Result<()> {
+        let started = Instant::now();
+
+        for mut group in groups {
+            crate::keep::order_group(&self.options.keep, &mut group);
+
+            if too_few_copies(&group, self.options.min_copies) {
+                debug!(
+                    "not reporting group of {} files with too few copies: {}",
+                    group.len(),
+                    group[0].path.display()
+                );
+                run.stats.too_few_copies(&group);
+                continue;
+            }
+
+            if self.options.exclude_same_dir && same_dir(&group) {
+                debug!(
+                    "not reporting group of {} files in the same directory: {}",
+                    group.len(),
+                    group[0].path.display()
+                );
+                run.stats.same_dir(&group);
+                continue;
+            }
+
+            run.stats.duplicates(&group);
+
+            if self
+                .options
+                .max_group_size
+                .is_some_and(|max| group.len() > max)
+            {
+                eprintln!("{}", large_group_warning(&group));
+            }
+
+            if let Some(reflinker) = &mut run.reflinker {
+                reflinker.dedupe_group(&group);
+            }
+
+            if let Some(cleaner) = &mut run.cleaner {
+                cleaner.clean_group(&group);
+            }
+
+            if let Some(script) = &mut run.script {
+                script.write_group(&group)?;
+            }
+
+            if let Some(near_candidates) = &mut run.near_candidates {
+                near_candidates.push(crate::near::representative(&group[0]));
+            }
+
+            run.output.write_group(group)?;
+        }
+
+        run.timing.output(started);
+        Ok(())
+    }
+
+    // Stop reading before the next round, returning true, once interrupted, once
+    // the read budget is spent, or once the deadline has passed. Whatever is still
+    // being compared in `work` or waiting to be can't be determined either way.
+    fn stop_reading(&self, work: &[PossDupe], bytes_read: u64, run: &mut RunState) -> Result<bool> {
+        // Every read from this round has already completed, so there's nothing in
+        // flight to wait for.
+        if (run.interrupted)() {
+            run.undetermined = Some((
+                "interrupted",
+                work.len() + self.poss_dupes.len(),
+                count_groups(work) + count_groups(&self.poss_dupes),
+            ));
+            run.stats.undetermined(work.len() + self.poss_dupes.len());
+
+            for pd in work.iter().chain(self.poss_dupes.iter()) {
+                write_incomplete(&mut run.incomplete_writer, "interrupted", &pd.path)?;
+            }
+
+            run.outcome = Outcome::Interrupted;
+            return Ok(true);
+        }
+
+        if self
+            .options
+            .max_read_bytes
+            .is_some_and(|max| bytes_read >= max)
+        {
+            run.undetermined = Some((
+                "read limit",
+                work.len() + self.poss_dupes.len(),
+                count_groups(work) + count_groups(&self.poss_dupes),
+            ));
+            run.stats.undetermined(work.len() + self.poss_dupes.len());
+
+            for pd in work.iter().chain(self.poss_dupes.iter()) {
+                write_incomplete(&mut run.incomplete_writer, "read limit", &pd.path)?;
+            }
+
+            run.outcome = Outcome::ReadLimitReached;
+            return Ok(true);
+        }
+
+        // The deadline is only reached if there's something left to do. Like the
+        // read limit, it's reported with its own exit status so a scheduled job can
+        // tell.
+        let unfinished =
+            !work.is_empty() || !self.poss_dupes.is_empty() || !run.finishing.is_empty();
+
+        if unfinished
+            && run
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            run.undetermined = Some((
+                "deadline",
+                work.len() + self.poss_dupes.len(),
+                count_groups(work) + count_groups(&self.poss_dupes),
+            ));
+            run.stats.undetermined(work.len() + self.poss_dupes.len());
+
+            for pd in work.iter().chain(self.poss_dupes.iter()) {
+                write_incomplete(&mut run.incomplete_writer, "deadline", &pd.path)?;
+            }
+
+            run.outcome = Outcome::DeadlineReached;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // Read the next block of every file in `work`, and of every unique file in
+    // `finishing_now`, returning the files in `work` which were read, sorted. Unique
+    // files with more left to read go back to the front of `run.finishing`.
+    async fn read_round(
+        &self,
+        work: Vec<PossDupe>,
+        finishing_now: Vec<PossDupe>,
+        stages: Option<&Stages>,
+        run: &mut RunState,
+    ) -> Result<Vec<PossDupe>> {
+        let num_work = work.len();
+
+        // Create tasks, one per possible duplicate. Each task is spawned and will
+        // open the file (if it's not already open), perform a single read of the
+        // configured size, and update the digest, in separate stages with
+        // --threads-io and --threads-hash. With --single-thread, each read is
+        // instead performed inline, in order.
+        let started = Instant::now();
+        let mut read_results = Vec::new();
+        let hash_block_size = self.options.hash_block_size;
+
+        if self.options.single_thread {
+            for pd in work.into_iter().chain(finishing_now) {
+                let size = self.read_size(&pd);
+                read_results.push(read_poss_dupe(pd, size, hash_block_size, None).await);
+            }
+        } else {
+            let mut tasks = Vec::new();
+
+            for pd in work.into_iter().chain(finishing_now) {
+                let size = self.read_size(&pd);
+                let task = tokio::spawn(read_poss_dupe(pd, size, hash_block_size, stages.cloned()));
+                tasks.push(task);
+            }
+
+            for t in tasks {
+                read_results.push(tokio::join!(t).0.unwrap());
+            }
+        }
+
+        let finished_results = read_results.split_off(num_work);
+        let mut results = vec![];
+
+        // Track the results for each read. A file which can't be read is dropped
+        // from its group if the options allow it, and the files left in the group
+        // carry on without it.
+        for read_result in read_results {
+            match read_result {
+                Ok(pd) => results.push(pd),
+                Err(failure) => skip_read_failure(
+                    failure,
+                    &self.options,
+                    &mut run.stats,
+                    &mut run.incomplete_writer,
+                    &mut run.error_writer,
+                )?,
+            }
+        }
+
+        let mut unfinished = Vec::new();
+
+        for read_result in finished_results {
+            match read_result {
+                Ok(pd) if pd.bytes_remaining() > 0 => unfinished.push(pd),
+                Ok(pd) => {
+                    report_unique(&pd, &mut run.stats, &mut run.skipped_writer)?;
+
+                    if let Some(near_candidates) = &mut run.near_candidates {
+                        near_candidates.push(close(pd));
+                    }
+                }
+                Err(failure) => skip_read_failure(
+                    failure,
+                    &self.options,
+                    &mut run.stats,
+                    &mut run.incomplete_writer,
+                    &mut run.error_writer,
+                )?,
+            }
+        }
+
+        run.finishing.splice(0..0, unfinished);
+
+        sort_poss_dupes(&mut results);
+        run.timing.read(started);
+
+        Ok(results)
+    }
+
+    // Write what comes after the groups of duplicates, flush everything the run
+    // wrote to, and summarize the run, returning how it ended.
+    fn output_phase(&self, mut run: RunState) -> Result<Outcome> {
+        // Files still being read to the end when a run stopped early are unique
+        // all the same; only their digests are partial.
+        for unique in std::mem::take(&mut run.finishing) {
+            report_unique(&unique, &mut run.stats, &mut run.skipped_writer)?;
+        }
+
+        let started = Instant::now();
+        let mut output = run.output.join()?;
+
+        // Near duplicates are only looked for once every file has been classified,
+        // since a file which stopped early might have had a duplicate.
+        if let (Some(tolerance), Some(near_candidates)) =
+            (self.options.size_tolerance, run.near_candidates.take())
+        {
+            if run.outcome == Outcome::Complete && run.undetermined.is_none() {
+                for cluster in find_near_duplicates(near_candidates, tolerance, &self.options)? {
+                    output.write_near_group(&cluster)?;
+                }
+            }
+        }
+
+        output.finish(&run.stats)?;
+        output.flush()?;
+        run.stats.on_file_result()?;
+
+        if let Some(manifest) = &run.manifest {
+            manifest.lock().unwrap().flush()?;
+        }
+
+        flush_writer(&mut run.skipped_writer)?;
+        flush_writer(&mut run.incomplete_writer)?;
+        flush_writer(&mut run.error_writer)?;
+
+        if let Some(script) = &mut run.script {
+            script.flush()?;
+        }
+
+        // Files left undetermined weren't recorded, so the next run reads them.
+        if let (Some(path), Some(bloom)) = (&self.options.bloom, &run.next_bloom) {
+            bloom.lock().unwrap().save(path)?;
+        }
+
+        run.timing.output(started);
+
+        if let Some(reflinker) = &run.reflinker {
+            eprintln!("{}", reflinker.summary());
+        }
+
+        if let Some(cleaner) = &run.cleaner {
+            eprintln!("{}", cleaner.summary());
+        }
+
+        match run.undetermined {
+            Some(("interrupted", files, groups)) => eprintln!(
+                "interrupted; results are incomplete, and {} files in {} possible groups are undetermined",
+                files, groups
+            ),
+            Some((limit, files, groups)) => eprintln!(
+                "{} reached; {} files in {} possible groups are undetermined",
+                limit, files, groups
+            ),
+            None => (),
+        }
+
+        if self.options.verbosity > 0 {
+            run.stats.display()?;
+        }
+
+        if let Some(estimate) = &run.collision_estimate {
+            estimate.display();
+        }
+
+        if self.options.timing {
+            run.timing.display();
+        }
+
+        // A collision means results can't be trusted, which matters more than the
+        // results being incomplete.
+        let mut outcome = run.outcome;
+
+        if run.stats.num_collisions() > 0 {
+            outcome = Outcome::Collision;
+        } else if outcome == Outcome::Complete
+            && run
+                .cleaner
+                .as_ref()
+                .is_some_and(|cleaner| cleaner.num_failed() > 0)
+        {
+            outcome = Outcome::CleanupFailed;
+        }
+
+        if let Some(status) = &mut run.status {
+            let state = match outcome {
+                Outcome::Complete if run.undetermined.is_some() => "incomplete",
+                Outcome::Complete => "complete",
+                Outcome::Interrupted => "interrupted",
+                Outcome::Collision => "collision",
+                Outcome::DeadlineReached | Outcome::ReadLimitReached => "incomplete",
+                Outcome::CleanupFailed => "cleanup failed",
+            };
+            status.finish(&run.stats, state)?;
+        }
+
+        Ok(outcome)
+    }
+}
+
+// Everything a run writes to and keeps track of, handed from one phase of the run
+// to the next.
+struct RunState {
+    timing: Timing,
+    stats: Stats,
+    output: OutputThread,
+    skipped_writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    incomplete_writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    error_writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    manifest: Option<Arc<Mutex<Manifest>>>,
+    status: Option<StatusFile>,
+    deadline: Option<Instant>,
+
+    // Whether the run has been interrupted, which tests can stand in for
+    interrupted: fn() -> bool,
+
+    next_bloom: Option<Arc<Mutex<Bloom>>>,
+    collision_estimate: Option<CollisionEstimate>,
+    reflinker: Option<Reflinker>,
+    cleaner: Option<Cleaner>,
+    script: Option<Script>,
+
+    // Unique files, and the file kept from each group, to look for near
+    // duplicates among with `--size-tolerance`
+    near_candidates: Option<Vec<PossDupe>>,
+
+    // Unique files still to be read to the end with `--full-hash`
+    finishing: Vec<PossDupe>,
+
+    outcome: Outcome,
+
+    // What stopped reading early, and how many files in how many possible groups
+    // were left undetermined
+    undetermined: Option<(&'static str, usize, usize)>,
+}
+
+// Files which weren't listed before the deadline or an interrupt could share a
+// size with any of those which were, so none of them can be determined.
+fn stop_listing(mut run: RunState, listed: &[PossDupe], outcome: Outcome) -> Result<Outcome> {
+    let (reason, stopped, state) = match outcome {
+        Outcome::Interrupted => ("interrupted", "interrupted", "interrupted"),
+        _ => ("deadline", "deadline reached", "incomplete"),
+    };
+
+    run.stats.undetermined(listed.len());
+
+    for pd in listed {
+        write_incomplete(&mut run.incomplete_writer, reason, &pd.path)?;
+    }
+
+    eprintln!(
+        "{} while listing files; {} files listed are undetermined",
+        stopped,
+        listed.len()
+    );
+
+    run.output.join()?.flush()?;
+    flush_writer(&mut run.incomplete_writer)?;
+    flush_writer(&mut run.error_writer)?;
+
+    if let Some(status) = &mut run.status {
+        status.finish(&run.stats, state)?;
+    }
+
+    Ok(outcome)
+}
+
+// With `--plan`, show how much would be read, without reading anything.
+fn show_plan(run: RunState, uniques: &[PossDupe], shared: &[PossDupe]) -> Result<Outcome> {
+    Plan::new(uniques, shared).display()?;
+
+    if let Some(estimate) = &run.collision_estimate {
+        estimate.display();
+    }
+
+    run.output.join()?.flush()?;
+    Ok(Outcome::Complete)
+}
+
+thread_local! {
+    // Re-use the same heap-allocated buffer for reading in each thread. It grows to
+    // the configured read size on first use, so memory used for reading is the
+    // number of threads times the read size, and reads after that don't allocate.
+    pub static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// A file which couldn't be read; the file is dropped, but its path is kept to
+// report it.
+struct ReadFailure {
+    path: PathBuf,
+    error: anyhow::Error,
+}
+
+// In the thread pool, asynchronously open the file if needed, perform a read operation,
+// and hash the data, on the hashing threads if reading and hashing are separate stages.
+async fn read_poss_dupe(
+    mut poss_dupe: PossDupe,
+    read_size: usize,
+    hash_block_size: Option<usize>,
+    stages: Option<Stages>,
+) -> Result<PossDupe, ReadFailure> {
+    let (poss_dupe, read) = match stages {
+        Some(stages) => {
+            let read = |pd: &mut PossDupe, buffer: &mut Vec<u8>| {
+                pd.open()?;
+                pd.read_chunk(read_size, buffer)
+            };
+            stages.read_next(poss_dupe, hash_block_size, read).await
+        }
+        None => {
+            let read = read_next(&mut poss_dupe, read_size, hash_block_size);
+            (poss_dupe, read)
+        }
+    };
+
+    match read {
+        Ok(()) => Ok(poss_dupe),
+        Err(error) => Err(ReadFailure {
+            path: poss_dupe.path,
+            error,
+        }),
+    }
+}
+
+// Read the next chunk of a file and hash it, in updates of `hash_block_size` bytes
+// if given, or all at once.
+fn read_next(
+    poss_dupe: &mut PossDupe,
+    read_size: usize,
+    hash_block_size: Option<usize>,
+) -> Result<()> {
+    poss_dupe.open()?;
+
+    BUFFER.with(|b| {
+        let mut buffer = b.borrow_mut();
+        let n = poss_dupe.read_chunk(read_size, &mut buffer)?;
+        poss_dupe.update_digest_in_blocks(&buffer[0..n], hash_block_size.unwrap_or(n));
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // Counts the allocations made on each thread, so a test can check that reads
+    // don't allocate.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    fn mk_pd(path: &str, len: u64) -> PossDupe {
+        PossDupe::new(path, len)
+    }
+
+    // A directory holding the given files for a test, removed when it's dropped,
+    // so it's gone once the test is over whether or not it passed.
+    fn test_dir(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, contents) in files {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        dir
+    }
+
+    // Write a list of the files in `root` with the given names, one per line, to
+    // `root/list`, returning its path to be given to `--files`.
+    fn write_list(root: &Path, names: &[&str]) -> String {
+        let contents: String = names
+            .iter()
+            .map(|name| format!("{}\n", root.join(name).display()))
+            .collect();
+        std::fs::write(root.join("list"), contents).unwrap();
+        root.join("list").display().to_string()
+    }
+
+    // Run with the given command line arguments, returning the exit status.
+    fn run_with(args: &[&str]) -> i32 {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(Fddup::new(crate::cli::parse_from(args)).run())
+    }
+
+    impl PartialEq for PossDupe {
+        fn eq(&self, other: &Self) -> bool {
+            self.path == other.path
+                && self.key.len == other.key.len
+                && self.bytes_read == other.bytes_read
+        }
+    }
+
+    // List files as `stat_files` does, dropping paths already listed.
+    fn list(pd: Vec<PossDupe>, args: &[&str]) -> Vec<PossDupe> {
+        let options = crate::cli::parse_from(args);
+        let mut listed = ListedPaths::default();
+        let mut result = Vec::new();
+
+        for pd in pd {
+            listed.add(pd, &options, &mut result);
+        }
+
+        result
+    }
+
+    #[test]
+    #[allow(clippy::vec_init_then_push)]
+    fn remove_duplicate_paths() {
+        let mut pd = Vec::new();
+        pd.push(mk_pd("a", 10));
+        pd.push(mk_pd("b", 10));
+        pd.push(mk_pd("a", 10));
+
+        // Shouldn't be possible to have the same path with different lengths
+        // unless the length were to change during iteration, but if we do,
+        // treat it as a duplicate.
+        pd.push(mk_pd("b", 11));
+
+        let pd = list(pd, &[]);
+
+        assert_eq!(pd, vec![mk_pd("a", 10), mk_pd("b", 10)]);
+    }
+
+    #[test]
+    fn repeated_paths_with_stripped_prefixes() {
+        let pd = vec![
+            mk_pd("/snap/2/a", 10),
+            mk_pd("/snap/1/b", 10),
+            mk_pd("/snap/1/a", 10),
+            mk_pd("/other/a", 10),
+        ];
+        let args = ["--strip-prefix", "/snap/1", "--strip-prefix", "/snap/2"];
+
+        // The smallest full path is kept, in the place its input was first listed.
+        assert_eq!(
+            list(pd, &args),
+            vec![
+                mk_pd("/snap/1/a", 10),
+                mk_pd("/snap/1/b", 10),
+                mk_pd("/other/a", 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_paths_keeping_the_first_listed() {
+        let pd = vec![
+            mk_pd("/snap/2/a", 10),
+            mk_pd("/snap/1/b", 10),
+            mk_pd("/snap/1/a", 10),
+            mk_pd("/other/a", 10),
+            mk_pd("/snap/1/b", 10),
+        ];
+        let args = [
+            "--strip-prefix",
+            "/snap/1",
+            "--strip-prefix",
+            "/snap/2",
+            "--first-listed",
+        ];
+
+        assert_eq!(
+            list(pd, &args),
+            vec![
+                mk_pd("/snap/2/a", 10),
+                mk_pd("/snap/1/b", 10),
+                mk_pd("/other/a", 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn same_dir_groups() {
+        let same = [mk_pd("dl/a", 1), mk_pd("dl/b", 1), mk_pd("dl/c", 1)];
+        let split = [mk_pd("dl/a", 1), mk_pd("dl/b", 1), mk_pd("photos/a", 1)];
+        let nested = [mk_pd("dl/a", 1), mk_pd("dl/old/a", 1)];
+        let relative = [mk_pd("a", 1), mk_pd("b", 1)];
+
+        assert!(same_dir(&same));
+        assert!(!same_dir(&split));
+        assert!(!same_dir(&nested));
+        assert!(same_dir(&relative));
+    }
+
+    #[test]
+    fn split_by_size() {
+        let shared = vec![mk_pd("a", 1), mk_pd("b", 1), mk_pd("c", 5), mk_pd("d", 5)];
+        let sizes: Vec<Vec<u64>> = super::split_by_size(shared)
+            .iter()
+            .map(|g| g.iter().map(|pd| pd.file_len).collect())
+            .collect();
+
+        assert_eq!(sizes, vec![vec![1, 1], vec![5, 5]]);
+    }
+
+    #[test]
+    fn bucket_by_size() {
+        let pd = vec![
+            mk_pd("d", 300),
+            mk_pd("c", 100),
+            mk_pd("b", 200),
+            mk_pd("a", 300),
+            mk_pd("e", 100),
+            mk_pd("f", 100),
+        ];
+
+        let (uniques, shared) = crate::fddup::bucket_by_size(pd);
+
+        assert_eq!(uniques, vec![mk_pd("b", 200)]);
+        assert_eq!(
+            shared,
+            vec![
+                mk_pd("c", 100),
+                mk_pd("e", 100),
+                mk_pd("f", 100),
+                mk_pd("d", 300),
+                mk_pd("a", 300)
+            ]
+        );
+    }
+
+    #[test]
+    fn same_name_splits_identical_files() {
+        // Identical contents, read in full, with names set as `--same-name` does.
+        let read = |path: &str, same_name: bool| {
+            let mut pd = mk_pd(path, 4);
+            pd.update_digest(b"same");
+            pd.bytes_read = 4;
+
+            if same_name {
+                pd.key.name = pd.path.file_name().map(Arc::from);
+            }
+
+            pd
+        };
+        let classify = |same_name| {
+            let pds = ["a/photo.jpg", "b/photo.jpg", "b/copy.jpg"]
+                .iter()
+                .map(|path| read(path, same_name))
+                .collect();
+            let (uniques, mut shared) = super::bucket_by_size(pds);
+            super::sort_poss_dupes(&mut shared);
+            let w = algo::find_work(&mut shared, usize::MAX);
+            let groups: Vec<_> = split_groups(w.duplicates)
+                .iter()
+                .map(|g| names(g))
+                .collect();
+            (names(&uniques), groups)
+        };
+
+        let (uniques, groups) = classify(false);
+        assert!(uniques.is_empty());
+        assert_eq!(
+            groups,
+            vec![vec!["a/photo.jpg", "b/copy.jpg", "b/photo.jpg"]]
+        );
+
+        // Only the two files called photo.jpg are duplicates; the copy under
+        // another name is unique without being read.
+        let (uniques, groups) = classify(true);
+        assert_eq!(uniques, vec!["b/copy.jpg"]);
+        assert_eq!(groups, vec![vec!["a/photo.jpg", "b/photo.jpg"]]);
+    }
+
+    #[test]
+    fn split_empty() {
+        let (_, mut shared) = crate::fddup::bucket_by_size(vec![
+            mk_pd("a", 10),
+            mk_pd("b", 0),
+            mk_pd("c", 0),
+            mk_pd("d", 10),
+            mk_pd("e", 0),
+        ]);
+
+        let empty = crate::fddup::split_empty(&mut shared);
+
+        assert_eq!(empty, vec![mk_pd("b", 0), mk_pd("c", 0), mk_pd("e", 0)]);
+        assert_eq!(shared, vec![mk_pd("a", 10), mk_pd("d", 10)]);
+        assert_eq!(crate::fddup::split_empty(&mut shared), vec![]);
+    }
+
+    #[test]
+    fn split_empty_keeps_files_with_blank_normalized_text() {
+        let mut blank = mk_pd("b", 0);
+        blank.file_len = 3;
+        let (_, mut shared) = crate::fddup::bucket_by_size(vec![
+            mk_pd("a", 10),
+            blank,
+            mk_pd("c", 0),
+            mk_pd("d", 10),
+        ]);
+
+        let empty = crate::fddup::split_empty(&mut shared);
+
+        assert_eq!(empty, vec![mk_pd("c", 0)]);
+        assert_eq!(shared.len(), 3);
+        assert_eq!(shared[0].path, Path::new("b"));
+        assert_eq!(shared[0].file_len, 3);
+    }
+
+    #[test]
+    #[allow(clippy::char_lit_as_u8)]
+    fn sort_poss_dupes() {
+        let mut pd = Vec::new();
+
+        // "c" and "d" have been read some
+        let mut d = mk_pd("d", 300);
+        d.update_digest(&['a' as u8]);
+        pd.push(d);
+
+        let mut c = mk_pd("c", 300);
+        c.update_digest(&['b' as u8]);
+        pd.push(c);
+
+        // "a" and "b" haven't yet been read
+        pd.push(mk_pd("a", 100));
+        pd.push(mk_pd("b", 200));
+
+        crate::fddup::sort_poss_dupes(&mut pd);
+
+        assert_eq!(
+            pd,
+            vec![
+                mk_pd("a", 100),
+                mk_pd("b", 200),
+                mk_pd("c", 300),
+                mk_pd("d", 300)
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_poss_dupes_breaks_ties_by_path() {
+        let read = |path: &str| {
+            let mut pd = mk_pd(path, 300);
+            pd.update_digest(b"same");
+            pd
+        };
+        let mut pd = vec![read("c"), read("a"), mk_pd("z", 100), read("b")];
+
+        crate::fddup::sort_poss_dupes(&mut pd);
+
+        let paths: Vec<_> = pd.iter().map(|pd| pd.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["z", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn resorting_leftovers_is_a_fixpoint() {
+        let read = |path: &str, len: u64, data: &[u8]| {
+            let mut pd = mk_pd(path, len);
+            pd.update_digest(data);
+            pd.bytes_read = data.len() as u64;
+            pd
+        };
+
+        let mut pd = vec![
+            read("f", 300, b"b"),
+            read("e", 300, b"a"),
+            read("d", 300, b"b"),
+            read("c", 300, b"a"),
+            mk_pd("b", 200),
+            mk_pd("a", 200),
+        ];
+
+        crate::fddup::sort_poss_dupes(&mut pd);
+        let paths =
+            |pd: &[PossDupe]| -> Vec<PathBuf> { pd.iter().map(|p| p.path.clone()).collect() };
+        let sorted = paths(&pd);
+        crate::fddup::sort_poss_dupes(&mut pd);
+        assert_eq!(paths(&pd), sorted);
+
+        // Pause after one group, as a read budget would, then put the unread work
+        // back and carry on: what's left is unchanged by sorting again, and every
+        // file comes out exactly once.
+        let w = algo::find_work(&mut pd, 1);
+        let leftover = paths(&pd);
+        crate::fddup::sort_poss_dupes(&mut pd);
+        assert_eq!(paths(&pd), leftover);
+
+        pd.extend(w.work);
+        crate::fddup::sort_poss_dupes(&mut pd);
+        let rest = algo::find_work(&mut pd, usize::MAX);
+        assert!(pd.is_empty());
+
+        let mut seen: Vec<_> = rest
+            .work
+            .iter()
+            .chain(&w.duplicates)
+            .chain(&rest.duplicates)
+            .chain(&w.uniques)
+            .chain(&rest.uniques)
+            .map(|p| p.path.clone())
+            .collect();
+        seen.sort();
+        let mut expected = sorted;
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn verify_groups_counts_collisions() {
+        let dir = test_dir(&[("a", "aaaa"), ("b", "bbbb")]);
+        let root = dir.path();
+
+        // Both files have an all-zero digest snapshot, as if their digests matched.
+        let group = || {
+            vec![
+                mk_pd(root.join("a").to_str().unwrap(), 4),
+                mk_pd(root.join("b").to_str().unwrap(), 4),
+            ]
+        };
+
+        let mut stats = Stats::new();
+        let mut uniques = Vec::new();
+        assert!(
+            verify_groups(vec![group()], 4, None, &mut stats, &mut uniques)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(stats.num_collisions(), 1);
+        assert_eq!(uniques.len(), 2);
+
+        // Differing contents are expected when the digest is truncated.
+        let mut truncated = group();
+        truncated.iter_mut().for_each(|pd| pd.digest_len = 4);
+
+        let mut stats = Stats::new();
+        assert!(
+            verify_groups(vec![truncated], 4, None, &mut stats, &mut Vec::new())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(stats.num_collisions(), 0);
+    }
+
+    #[test]
+    fn bloom_skips_files_unique_in_the_last_run() {
+        let dir = test_dir(&[("a", "abcd"), ("b", "abcd"), ("c", "abce"), ("d", "wxyz")]);
+        let root = dir.path();
+        let list = write_list(root, &["a", "b", "c", "d"]);
+        let bloom = root.join("bloom");
+        let skipped = root.join("skipped");
+        let out = root.join("out");
+
+        let run = || {
+            assert_eq!(
+                run_with(&[
+                    "--files",
+                    &list,
+                    "--bloom",
+                    bloom.to_str().unwrap(),
+                    "--list-skipped",
+                    skipped.to_str().unwrap(),
+                    "--output",
+                    out.to_str().unwrap(),
+                ]),
+                0
+            );
+            assert_eq!(std::fs::read_to_string(&out).unwrap().lines().count(), 2);
+
+            let mut skipped: Vec<_> = std::fs::read_to_string(&skipped)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            skipped.sort();
+            skipped
+        };
+        let path = |name: &str| root.join(name).display().to_string();
+
+        assert!(run().is_empty());
+        assert_eq!(run(), vec![path("c"), path("d")]);
+
+        // A changed file could be a duplicate of the others, so they're all read.
+        std::fs::write(root.join("d"), "abcf").unwrap();
+        assert!(run().is_empty());
+    }
+
+    #[test]
+    fn io_error_kinds_are_found_through_context() {
+        let missing = std::fs::File::open("/nonexistent/fddup")
+            .with_context(|| "couldn't open /nonexistent/fddup")
+            .unwrap_err();
+        let denied = anyhow::Error::new(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("couldn't read directory");
+
+        assert_eq!(
+            super::io_error_kind(&missing),
+            Some(io::ErrorKind::NotFound)
+        );
+        assert_eq!(
+            super::io_error_kind(&denied),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(super::io_error_kind(&anyhow!("not an I/O error")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn named_pipes_are_special_files() {
+        let dir = test_dir(&[("file", "a")]);
+        let root = dir.path();
+
+        let fifo = std::ffi::CString::new(root.join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let attr = |name| symlink_metadata(root.join(name)).unwrap();
+
+        assert!(super::is_special_file(&attr("fifo")));
+        assert!(!super::is_special_file(&attr("file")));
+        assert!(!super::is_special_file(&symlink_metadata(root).unwrap()));
+    }
+
+    // A hard link is another path to the same physical file, just as `A.txt` is
+    // to `a.txt` on a case-insensitive filesystem.
+    #[cfg(unix)]
+    #[test]
+    fn other_paths_to_a_file_are_removed() {
+        let dir = test_dir(&[("b.txt", "a"), ("c.txt", "a")]);
+        let root = dir.path();
+        std::fs::hard_link(root.join("b.txt"), root.join("a.txt")).unwrap();
+        std::fs::hard_link(root.join("b.txt"), root.join("d.txt")).unwrap();
+
+        let pd = |name| {
+            let mut pd = mk_pd(name, 1);
+            pd.file_id = crate::platform::file_id(&symlink_metadata(root.join(name)).unwrap());
+            pd
+        };
+
+        // The smallest path to a file is kept wherever it was listed.
+        let mut listed = vec![pd("d.txt"), pd("b.txt"), pd("c.txt"), pd("a.txt")];
+        let removed = remove_other_paths(&mut listed);
+
+        assert_eq!(listed, vec![mk_pd("c.txt", 1), mk_pd("a.txt", 1)]);
+        assert_eq!(
+            removed,
+            vec![PathBuf::from("d.txt"), PathBuf::from("b.txt")]
+        );
+    }
+
+    // Only meaningful where the temporary directory is case-insensitive, as it is
+    // by default on macOS.
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn seen_files_recognize_other_cases() {
+        let dir = test_dir(&[("a.txt", "a")]);
+        let root = dir.path();
+
+        if let Ok(upper) = symlink_metadata(root.join("A.txt")) {
+            let mut lower = mk_pd("a.txt", 1);
+            lower.file_id =
+                crate::platform::file_id(&symlink_metadata(root.join("a.txt")).unwrap());
+            let mut upper_pd = mk_pd("A.txt", 1);
+            upper_pd.file_id = crate::platform::file_id(&upper);
+
+            let mut listed = vec![lower, upper_pd];
+            assert_eq!(
+                remove_other_paths(&mut listed),
+                vec![PathBuf::from("a.txt")]
+            );
+        }
+    }
+
+    #[test]
+    fn in_time_window() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let t = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert!(super::in_time_window(t(10), None, None));
+        assert!(super::in_time_window(None, None, None));
+        assert!(!super::in_time_window(None, t(5), None));
+
+        // Both ends of the window are exclusive.
+        assert!(super::in_time_window(t(10), t(9), t(11)));
+        assert!(!super::in_time_window(t(10), t(10), None));
+        assert!(!super::in_time_window(t(10), None, t(10)));
+        assert!(!super::in_time_window(t(8), t(9), None));
+        assert!(!super::in_time_window(t(12), None, t(11)));
+    }
+
+    #[test]
+    fn threads_far_above_the_cpus_are_warned_about() {
+        assert_eq!(
+            super::threads_warning(1000, 8).as_deref(),
+            Some("warning: 1000 threads for 8 logical CPUs is more than 32 per CPU, which usually makes fddup slower")
+        );
+        assert_eq!(super::threads_warning(256, 8), None);
+        assert_eq!(super::threads_warning(64, 4), None);
+    }
+
+    #[test]
+    fn effective_threads_are_capped_by_pending_files() {
+        assert_eq!(effective_threads(64, 4), 4);
+        assert_eq!(effective_threads(4, 64), 4);
+        assert_eq!(effective_threads(8, 0), 1);
+    }
+
+    // Outcome of running the rounds of the main loop over in-memory contents.
+    #[derive(Debug, Default)]
+    struct Rounds {
+        work: Vec<Vec<String>>,
+        groups: Vec<Vec<String>>,
+        uniques: Vec<String>,
+    }
+
+    fn names(poss_dupes: &[PossDupe]) -> Vec<String> {
+        let mut names: Vec<String> = poss_dupes
+            .iter()
+            .map(|pd| pd.path.to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Run the rounds of the main loop, reading from `contents` rather than files,
+    // and check the invariant `find_work` relies on after every round: two files
+    // have equal keys exactly when their contents read so far are equal.
+    fn run_rounds(contents: &[(&str, &[u8])], read_size: u64) -> Rounds {
+        let data: HashMap<PathBuf, &[u8]> = contents
+            .iter()
+            .map(|(name, data)| (PathBuf::from(name), *data))
+            .collect();
+        let mut pending: Vec<PossDupe> = contents
+            .iter()
+            .map(|(name, data)| mk_pd(name, data.len() as u64))
+            .collect();
+        let mut rounds = Rounds::default();
+
+        super::sort_poss_dupes(&mut pending);
+
+        loop {
+            for a in pending.iter() {
+                for b in pending.iter() {
+                    let prefix = |pd: &PossDupe| &data[&pd.path][..pd.bytes_read as usize];
+                    assert_eq!(a.key == b.key, prefix(a) == prefix(b));
+                }
+            }
+
+            let w = algo::find_work(&mut pending, usize::MAX);
+            rounds.uniques.extend(names(&w.uniques));
+            rounds
+                .groups
+                .extend(split_groups(w.duplicates).iter().map(|g| names(g)));
+
+            if w.work.is_empty() {
+                break;
+            }
+
+            rounds.work.push(names(&w.work));
+            pending = w.work;
+
+            for pd in pending.iter_mut() {
+                let (offset, len) = pd.next_read(read_size);
+                let range = offset as usize..(offset + len) as usize;
+                pd.update_digest(&data[&pd.path][range]);
+                pd.bytes_read += len;
+            }
+
+            super::sort_poss_dupes(&mut pending);
+        }
+
+        rounds.uniques.sort();
+        rounds
+    }
+
+    #[test]
+    fn files_differing_only_in_their_final_read_are_unique() {
+        let rounds = run_rounds(
+            &[
+                ("a", b"0123456789"),
+                ("b", b"012345678X"),
+                ("c", b"0123456789"),
+            ],
+            4,
+        );
+
+        // Equal prefixes keep all three in work until the final read splits them.
+        assert_eq!(rounds.work, vec![vec!["a", "b", "c"]; 3]);
+        assert_eq!(rounds.groups, vec![vec!["a", "c"]]);
+        assert_eq!(rounds.uniques, vec!["b"]);
+    }
+
+    #[test]
+    fn files_differing_only_in_their_final_whole_read_are_unique() {
+        let rounds = run_rounds(&[("a", b"01234567"), ("b", b"0123456X")], 4);
+
+        assert_eq!(rounds.work, vec![vec!["a", "b"]; 2]);
+        assert!(rounds.groups.is_empty());
+        assert_eq!(rounds.uniques, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn files_leave_work_once_their_prefixes_differ() {
+        let rounds = run_rounds(
+            &[
+                ("a", b"aaaa"),
+                ("b", b"abaa"),
+                ("c", b"abab"),
+                ("d", b"aaaa"),
+            ],
+            1,
+        );
+
+        assert_eq!(
+            rounds.work,
+            vec![
+                vec!["a", "b", "c", "d"],
+                vec!["a", "b", "c", "d"],
+                vec!["a", "b", "c", "d"],
+                vec!["a", "b", "c", "d"],
+            ]
+        );
+        assert_eq!(rounds.groups, vec![vec!["a", "d"]]);
+        assert_eq!(rounds.uniques, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn equal_keys_mean_equal_prefixes() {
+        // Every 4-byte string over two letters, so each round has files whose
+        // prefixes match and files whose prefixes have just diverged.
+        let contents: Vec<(String, Vec<u8>)> = (0..16u8)
+            .map(|n| {
+                let data: Vec<u8> = (0..4).map(|bit| b'a' + ((n >> bit) & 1)).collect();
+                (String::from_utf8(data.clone()).unwrap(), data)
+            })
+            .collect();
+        let contents: Vec<(&str, &[u8])> = contents
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+
+        for read_size in 1..=4 {
+            let rounds = run_rounds(&contents, read_size);
+
+            assert!(rounds.groups.is_empty());
+            assert_eq!(rounds.uniques.len(), 16);
+        }
+    }
+
+    #[test]
+    fn unfinished_uniques_resume_after_closing() {
+        let dir = test_dir(&[("a", "abcdefgh")]);
+        let root = dir.path();
+
+        let mut unfinished = mk_pd(root.join("a").to_str().unwrap(), 8);
+        read_next(&mut unfinished, 4, None).unwrap();
+        let mut uniques = vec![mk_pd("unread", 0), unfinished];
+        let mut finishing = Vec::new();
+
+        set_aside_unfinished(&mut uniques, &mut finishing);
+        assert_eq!(names(&uniques), vec!["unread"]);
+        assert_eq!(finishing.len(), 1);
+        assert!(finishing[0].file.is_none());
+
+        // Reopening carries on from where reading left off.
+        read_next(&mut finishing[0], 4, Some(3)).unwrap();
+        let mut whole = mk_pd("whole", 8);
+        whole.update_digest(b"abcdefgh");
+        assert_eq!(finishing[0].bytes_remaining(), 0);
+        assert_eq!(finishing[0].digest_hex(), whole.digest_hex());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn output_write_failures_are_errors() {
+        let dir = test_dir(&[("a", "abcd"), ("b", "abcd"), ("c", "unique")]);
+        let root = dir.path();
+        let list = write_list(root, &["a", "b", "c"]);
+        let out = root.join("out");
+        let out = out.to_str().unwrap();
+
+        assert_eq!(run_with(&["--files", &list, "--output", out]), 0);
+        assert_eq!(run_with(&["--files", &list, "--output", "/dev/full"]), 1);
+        assert_eq!(
+            run_with(&[
+                "--files",
+                &list,
+                "--output",
+                out,
+                "--list-skipped",
+                "/dev/full"
+            ]),
+            1
+        );
+    }
+
+    #[test]
+    fn skipped_files_are_logged() {
+        let dir = test_dir(&[("a", "abcd")]);
+        let root = dir.path();
+        let list = write_list(root, &["a", "missing"]);
+        let log = root.join("errors");
+
+        assert_eq!(
+            run_with(&[
+                "--files",
+                &list,
+                "--on-not-found",
+                "skip",
+                "--error-log",
+                log.to_str().unwrap(),
+            ]),
+            0
+        );
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        let fields: Vec<_> = log.trim_end().split('\t').collect();
+        assert_eq!(log.lines().count(), 1);
+        assert_eq!(fields[0], "not-found");
+        assert!(fields[1].starts_with("couldn't open file to read attributes"));
+        assert_eq!(fields[2], root.join("missing").to_str().unwrap());
+    }
+
+    #[test]
+    fn gzip_files_are_compared_decompressed() {
+        let dir = test_dir(&[
+            ("text", &"abcd".repeat(1000)),
+            ("other", &"abce".repeat(1000)),
+        ]);
+        let root = dir.path();
+
+        let gzip = |level: u32, name: &str| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+            encoder
+                .write_all(&std::fs::read(root.join(name)).unwrap())
+                .unwrap();
+            encoder.finish().unwrap()
+        };
+        let (fast, best) = (gzip(1, "text"), gzip(9, "text"));
+        assert_ne!(fast, best);
+        std::fs::write(root.join("a.gz"), fast).unwrap();
+        std::fs::write(root.join("b.gz"), best).unwrap();
+        std::fs::write(root.join("c.gz"), gzip(9, "other")).unwrap();
+        std::fs::write(root.join("d.gz"), "not gzip").unwrap();
+
+        let list = write_list(root, &["a.gz", "b.gz", "c.gz", "d.gz"]);
+        let out = root.join("out");
+        let errors = root.join("errors");
+
+        let run = |on_invalid: &str| {
+            run_with(&[
+                "--files",
+                &list,
+                "--decompress",
+                "--on-invalid",
+                on_invalid,
+                "--error-log",
+                errors.to_str().unwrap(),
+                "--output",
+                out.to_str().unwrap(),
+            ])
+        };
+
+        // A file which fails to decompress stops the run, unless it's skipped.
+        assert_eq!(run("abort"), 1);
+        assert_eq!(run("skip"), 0);
+
+        let out = std::fs::read_to_string(&out).unwrap();
+        let a = root.join("a.gz").display().to_string();
+        let b = root.join("b.gz").display().to_string();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.lines().any(|line| line.ends_with(&a)));
+        assert!(out.lines().any(|line| line.ends_with(&b)));
+
+        let errors = std::fs::read_to_string(&errors).unwrap();
+        let d = root.join("d.gz").display().to_string();
+        assert_eq!(errors.lines().count(), 1);
+        assert!(errors.starts_with("invalid\t"));
+        assert!(errors.trim_end().ends_with(&d));
+    }
+
+    #[test]
+    fn read_limit_leaves_files_undetermined() {
+        let dir = test_dir(&[("a", "abcdefgh"), ("b", "abcdefgx"), ("c", "xyz")]);
+        let root = dir.path();
+        let list = write_list(root, &["a", "b", "c"]);
+        let incomplete = root.join("incomplete");
+
+        assert_eq!(
+            run_with(&[
+                "--files",
+                &list,
+                "--read-size",
+                "4",
+                "--max-read-bytes",
+                "1",
+                "--report-incomplete",
+                incomplete.to_str().unwrap(),
+            ]),
+            Outcome::ReadLimitReached.exit_code()
+        );
+
+        // Only the first half of each of the files sharing a size was read.
+        let incomplete = std::fs::read_to_string(&incomplete).unwrap();
+        assert_eq!(incomplete.lines().count(), 2);
+        assert!(incomplete
+            .lines()
+            .all(|line| line.starts_with("read limit\t")));
+    }
+
+    #[test]
+    fn deadline_leaves_files_undetermined() {
+        let dir = test_dir(&[("a", "abcdefgh"), ("b", "abcdefgx")]);
+        let root = dir.path();
+        let list = write_list(root, &["a", "b"]);
+        let incomplete = root.join("incomplete");
+
+        assert_eq!(
+            run_with(&[
+                "--files",
+                &list,
+                "--read-size",
+                "4",
+                "--deadline",
+                "0s",
+                "--report-incomplete",
+                incomplete.to_str().unwrap(),
+            ]),
+            Outcome::DeadlineReached.exit_code()
+        );
+
+        // The deadline passed before any file was listed, so none were.
+        assert_eq!(std::fs::read_to_string(&incomplete).unwrap(), "");
+
+        // Listing stops once the deadline has passed or the run is interrupted,
+        // whether reading the list or walking a directory.
+        let list_contents = format!("{}\n{}\n", root.join("a").display(), root.display());
+        let list = |deadline, interrupted: fn() -> bool| {
+            let (pds, stopped) = stat_files(
+                Box::new(io::Cursor::new(list_contents.clone())),
+                &crate::cli::parse_from(&["-r"]),
+                Some(deadline),
+                interrupted,
+                &mut Stats::new(),
+                &mut None,
+            )
+            .unwrap();
+            (pds.len(), stopped)
+        };
+        let later = Instant::now() + Duration::from_secs(3600);
+
+        assert_eq!(
+            list(Instant::now(), || false),
+            (0, Some(Outcome::DeadlineReached))
+        );
+        assert_eq!(list(later, || true), (0, Some(Outcome::Interrupted)));
+        assert_eq!(list(later, || false), (4, None));
+    }
+
+    #[test]
+    fn interrupts_stop_reading() {
+        let mut fddup = Fddup::new(crate::cli::parse_from(&["--read-size", "4"]));
+        let mut run = fddup.start_run().unwrap();
+        fddup.poss_dupes = vec![mk_pd("c", 8), mk_pd("d", 8), mk_pd("e", 8)];
+        let work = [mk_pd("a", 4), mk_pd("b", 4)];
+
+        assert!(!fddup.stop_reading(&work, 0, &mut run).unwrap());
+        assert_eq!(run.outcome, Outcome::Complete);
+
+        // Whatever was still being compared, or waiting to be, is undetermined.
+        run.interrupted = || true;
+        assert!(fddup.stop_reading(&work, 0, &mut run).unwrap());
+        assert_eq!(run.outcome, Outcome::Interrupted);
+        assert_eq!(run.undetermined, Some(("interrupted", 5, 2)));
+
+        run.output.join().unwrap();
+    }
+
+    #[test]
+    fn clean_stats_indexed_files() {
+        let dir = test_dir(&[("a", "abcd"), ("b", "abcd")]);
+        let root = dir.path();
+
+        let (a, b) = (root.join("a"), root.join("b"));
+        let index = root.join("index");
+        std::fs::write(
+            &index,
+            format!("4\t-\t-\t{}\n4\t1\t-\t{}\n", a.display(), b.display()),
+        )
+        .unwrap();
+
+        let mut options = crate::cli::parse_from(&["--size-index", index.to_str().unwrap()]);
+        options.clean = Some(crate::clean::Action::Delete);
+        let list = format!("{}\n{}\n", a.display(), b.display());
+        let pds = stat_files(
+            Box::new(io::Cursor::new(list)),
+            &options,
+            None,
+            || false,
+            &mut Stats::new(),
+            &mut None,
+        )
+        .unwrap()
+        .0;
+
+        // Each file has the modification time it really has, not the index's.
+        for pd in &pds {
+            assert!(!pd.indexed);
+            assert_eq!(
+                pd.mtime,
+                std::fs::metadata(&pd.path).unwrap().modified().ok()
+            );
+        }
+    }
+
+    #[test]
+    fn indexed_files_are_not_stated_until_opened() {
+        let dir = test_dir(&[("a", "abcd"), ("b", "abcd")]);
+        let root = dir.path();
+
+        let (a, b, missing) = (root.join("a"), root.join("b"), root.join("missing"));
+        let index = root.join("index");
+        std::fs::write(
+            &index,
+            format!(
+                "# size index\n4\t-\t-\t{}\n7\t-\t-\t{}\n",
+                a.display(),
+                missing.display()
+            ),
+        )
+        .unwrap();
+
+        let list = format!("{}\n{}\n{}\n", a.display(), b.display(), missing.display());
+        let pds = stat_files(
+            Box::new(io::Cursor::new(list)),
+            &crate::cli::parse_from(&["--size-index", index.to_str().unwrap()]),
+            None,
+            || false,
+            &mut Stats::new(),
+            &mut None,
+        )
+        .unwrap()
+        .0;
+
+        // The missing file is only known from the index, and b only from stat.
+        let found: Vec<_> = pds
+            .iter()
+            .map(|pd| (pd.path.clone(), pd.file_len, pd.indexed))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(a, 4, true), (b, 4, false), (missing.clone(), 7, true)]
+        );
+        assert!(pds[0].open_at(0).is_ok());
+
+        // Once the file is opened, it no longer matches the index, which can be
+        // skipped as invalid data.
+        std::fs::write(&missing, "abc").unwrap();
+        let error = pds[2].open_at(0).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("has changed since the size index was written"));
+        let options = crate::cli::parse_from(&["--skip-errors"]);
+        assert_eq!(
+            skippable_error(error, &options).unwrap(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Nor does a link in place of the file, which isn't followed.
+        #[cfg(unix)]
+        {
+            std::fs::remove_file(&pds[0].path).unwrap();
+            std::os::unix::fs::symlink(&pds[1].path, &pds[0].path).unwrap();
+            assert_eq!(
+                io_error_kind(&pds[0].open_at(0).unwrap_err()),
+                Some(io::ErrorKind::InvalidData)
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listed_links_to_directories_are_walked() {
+        let dir = test_dir(&[]);
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("dir/sub")).unwrap();
+        std::fs::write(root.join("dir/a"), "a").unwrap();
+        std::fs::write(root.join("dir/sub/b"), "b").unwrap();
+        std::os::unix::fs::symlink(root.join("dir"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(root.join("dir"), root.join("dir/sub/loop")).unwrap();
+
+        let listed = |args: &[&str]| {
+            let list = format!("{}\n", root.join("link").display());
+            let pds = stat_files(
+                Box::new(io::Cursor::new(list)),
+                &crate::cli::parse_from(args),
+                None,
+                || false,
+                &mut Stats::new(),
+                &mut None,
+            )
+            .unwrap()
+            .0;
+            names(&pds)
+                .iter()
+                .map(|name| {
+                    name.strip_prefix(root.to_str().unwrap())
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The link found while walking isn't followed, so the walk doesn't loop.
+        assert_eq!(
+            listed(&["-r", "--follow-input-symlinks"]),
+            vec!["/link/a", "/link/sub/b"]
+        );
+        assert!(listed(&["-r"]).is_empty());
+        assert!(listed(&["-r", "--symlink-mode", "follow"]).is_empty());
+    }
+
+    #[test]
+    fn reads_reuse_the_thread_buffer() {
+        let dir = test_dir(&[("a", "abcdefghijklmnop"), ("b", "abcdefghijklmnop")]);
+        let root = dir.path();
+
+        let mut a = mk_pd(root.join("a").to_str().unwrap(), 16);
+        let mut b = mk_pd(root.join("b").to_str().unwrap(), 16);
+
+        // The first read opens the file and may grow the buffer; later reads only
+        // reuse what's there, however many files they're from.
+        read_next(&mut a, 4, None).unwrap();
+        read_next(&mut b, 4, None).unwrap();
+        let before = allocations();
+
+        for _ in 0..3 {
+            read_next(&mut a, 4, None).unwrap();
+            read_next(&mut b, 4, Some(1)).unwrap();
+        }
+
+        assert_eq!(allocations(), before);
+        assert_eq!(a.bytes_remaining(), 0);
+        assert_eq!(a.digest_hex(), b.digest_hex());
+        assert_eq!(BUFFER.with(|buffer| buffer.borrow().len()), 4);
+    }
+
+    #[test]
+    fn first_read_size_only_applies_to_the_first_read() {
+        let mut pd = mk_pd("a", 100);
+        assert_eq!(next_read_size(&pd, 4, 16, None), 4);
+
+        pd.bytes_read = 4;
+        assert_eq!(next_read_size(&pd, 4, 16, None), 16);
+    }
+
+    #[test]
+    fn reads_from_large_files_are_capped() {
+        let huge = 4 * crate::cli::MAX_READ_SIZE;
+        let small = mk_pd("small", 1000);
+        let large = mk_pd("large", 10_000);
+
+        assert_eq!(next_read_size(&small, huge, huge, Some(1000)), huge);
+        assert_eq!(
+            next_read_size(&large, huge, huge, Some(1000)),
+            LARGE_FILE_READ_SIZE
+        );
+        assert_eq!(next_read_size(&large, 16, 16, Some(1000)), 16);
+        assert_eq!(next_read_size(&large, huge, huge, None), huge);
+    }
+
+    #[test]
+    fn count_groups() {
+        let mut read = mk_pd("c", 10);
+        read.update_digest(b"c");
+
+        let pds = vec![mk_pd("a", 10), mk_pd("b", 10), read, mk_pd("d", 20)];
+
+        assert_eq!(super::count_groups(&pds), 3);
+        assert_eq!(super::count_groups(&[]), 0);
+    }
+
+    #[test]
+    fn groups_with_too_few_copies() {
+        let pair: Vec<_> = ["a", "b"].iter().map(|p| mk_pd(p, 1)).collect();
+        let four: Vec<_> = ["c", "d", "e", "f"].iter().map(|p| mk_pd(p, 2)).collect();
+
+        assert!(!super::too_few_copies(&pair, 2));
+        assert!(!super::too_few_copies(&four, 2));
+
+        assert!(super::too_few_copies(&pair, 3));
+        assert!(!super::too_few_copies(&four, 3));
+    }
+
+    #[test]
+    fn large_group_warning() {
+        let group: Vec<_> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|p| mk_pd(p, 1))
+            .collect();
+
+        assert_eq!(
+            super::large_group_warning(&group),
+            "warning: 5 files have the same contents: a, b, c and 2 more"
+        );
+        assert_eq!(
+            super::large_group_warning(&group[..2]),
+            "warning: 2 files have the same contents: a, b"
         );
     }
 }
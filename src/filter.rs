@@ -0,0 +1,152 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Filter paths through an external command, for inclusion rules too involved
+// for any option. The command is run once, through the shell, with every path
+// written to its standard input, one per line. It writes the paths to keep to
+// its standard output, one per line, in any order, and must exit with status 0,
+// or with status 1 having written nothing, as `grep` does when nothing matches;
+// anything else stops the run. Commands such as `grep -v` work as filters as is.
+// A path containing a newline can't be written as one line, so it's never passed
+// to the command, and never kept.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// Return the paths which `cmd` keeps. Anything else it writes is returned too,
+// but matches none of the files being considered.
+pub fn run(cmd: &str, paths: &[&Path]) -> Result<HashSet<PathBuf>> {
+    let mut input = Vec::new();
+
+    for path in paths {
+        let bytes = crate::platform::path_bytes(path);
+
+        if bytes.contains(&b'\n') {
+            eprintln!(
+                "warning: skipped path containing a newline, which can't be filtered: {}",
+                path.display()
+            );
+            continue;
+        }
+
+        input.extend_from_slice(&bytes);
+        input.push(b'\n');
+    }
+
+    let mut child = shell(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("couldn't run filter command: {}", cmd))?;
+
+    // Paths are written from another thread so a command which writes its output
+    // as it reads can't block on a full pipe while we're still writing.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || match stdin.write_all(&input) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    });
+
+    let mut kept = HashSet::new();
+
+    for line in io::BufReader::new(child.stdout.take().unwrap()).split(b'\n') {
+        let mut line =
+            line.with_context(|| format!("couldn't read from filter command: {}", cmd))?;
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        kept.insert(crate::platform::path_from_bytes(line)?);
+    }
+
+    let status = child
+        .wait()
+        .with_context(|| format!("couldn't run filter command: {}", cmd))?;
+    writer
+        .join()
+        .unwrap()
+        .with_context(|| format!("couldn't write to filter command: {}", cmd))?;
+
+    // Like grep, the command may exit with status 1 when it keeps nothing.
+    let kept_nothing = status.code() == Some(1) && kept.is_empty();
+
+    if !status.success() && !kept_nothing {
+        bail!("filter command failed with {}: {}", status, cmd);
+    }
+
+    Ok(kept)
+}
+
+#[cfg(unix)]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(windows)]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_paths_written_back() {
+        let given = [Path::new("a.jpg"), Path::new("b.txt"), Path::new("c.jpg")];
+        let mut kept: Vec<_> = run("grep 'jpg$'", &given).unwrap().into_iter().collect();
+        kept.sort();
+
+        assert_eq!(kept, vec![PathBuf::from("a.jpg"), PathBuf::from("c.jpg")]);
+    }
+
+    #[test]
+    fn failures_are_errors() {
+        let given = [Path::new("a.jpg")];
+
+        assert!(run("cat; exit 2", &given).is_err());
+        assert!(run("cat; exit 1", &given).is_err());
+        assert!(run("true", &given).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeping_nothing_may_exit_with_status_1() {
+        let given = [Path::new("a.jpg"), Path::new("b.txt")];
+
+        assert!(run("grep 'png$'", &given).unwrap().is_empty());
+    }
+
+    #[test]
+    fn paths_containing_newlines_are_never_kept() {
+        let given = [Path::new("a\nb.jpg"), Path::new("c.jpg")];
+        let kept: Vec<_> = run("cat", &given).unwrap().into_iter().collect();
+
+        assert_eq!(kept, vec![PathBuf::from("c.jpg")]);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Minimal glob matching for paths. `*` matches any run of characters other
+// than `/`, `**` matches any run of characters including `/`, and `?` matches
+// a single character other than `/`. Everything else matches literally.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let (rest, crosses_dirs) = match pattern.get(1) {
+                Some('*') => (&pattern[2..], true),
+                _ => (&pattern[1..], false),
+            };
+
+            for i in 0..=text.len() {
+                if matches_from(rest, &text[i..]) {
+                    return true;
+                }
+
+                if i < text.len() && text[i] == '/' && !crosses_dirs {
+                    return false;
+                }
+            }
+
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => matches_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(p) => match text.first() {
+            Some(c) if c == p => matches_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(matches("/a/b.txt", "/a/b.txt"));
+        assert!(!matches("/a/b.txt", "/a/c.txt"));
+    }
+
+    #[test]
+    fn star_stays_within_directory() {
+        assert!(matches("/a/*.jpg", "/a/b.jpg"));
+        assert!(!matches("/a/*.jpg", "/a/b/c.jpg"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("/a/**.jpg", "/a/b/c.jpg"));
+        assert!(matches("/a/**", "/a/b/c.jpg"));
+        assert!(!matches("/b/**", "/a/b/c.jpg"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("/a/?.txt", "/a/b.txt"));
+        assert!(!matches("/a/?.txt", "/a/bb.txt"));
+        assert!(!matches("/a?b", "/a/b"));
+    }
+}
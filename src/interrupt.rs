@@ -0,0 +1,77 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Ctrl-C (SIGINT) handling. The first interrupt only sets a flag which the main
+// loop checks between rounds of work, so results found so far can be flushed.
+// A second interrupt terminates the process immediately as usual.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Return true once an interrupt has been received.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Record an interrupt in `flag`. Tests give their own flag, since setting the
+// process-wide one would interrupt every other test running in the process.
+#[cfg(any(unix, test))]
+fn set_interrupted(flag: &AtomicBool) {
+    flag.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    set_interrupted(&INTERRUPTED);
+
+    // Restore the default so a second interrupt terminates; this is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// Install the interrupt handler. Only Unix platforms are supported; elsewhere,
+// Ctrl-C terminates the process immediately as before.
+#[cfg(unix)]
+pub fn install() {
+    let handler: extern "C" fn(libc::c_int) = handle_sigint;
+
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupts_set_the_flag() {
+        let flag = AtomicBool::new(false);
+        set_interrupted(&flag);
+
+        assert!(flag.load(Ordering::SeqCst));
+    }
+}
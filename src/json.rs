@@ -0,0 +1,76 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Reading a list of files given as a JSON array of strings.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+// Parse a JSON array of strings. Any other element, such as a number or nested
+// array, is an error naming its position in the array.
+pub fn parse_string_array(text: &str) -> Result<Vec<String>> {
+    let values: Vec<Value> = serde_json::from_str(text)?;
+    let mut result = Vec::with_capacity(values.len());
+
+    for (i, value) in values.into_iter().enumerate() {
+        match value {
+            Value::String(s) => result.push(s),
+            _ => bail!("element {} of the array isn't a string", i),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arrays_of_strings() {
+        assert_eq!(parse_string_array("[]").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            parse_string_array(" [ \"a\" ,\n\"b/c\" ]\n").unwrap(),
+            vec!["a", "b/c"]
+        );
+    }
+
+    #[test]
+    fn parses_escapes() {
+        assert_eq!(
+            parse_string_array(r#"["C:\\dir\/f\"\n", "\u00e9\ud83d\ude00"]"#).unwrap(),
+            vec!["C:\\dir/f\"\n", "é😀"]
+        );
+    }
+
+    #[test]
+    fn rejects_non_strings() {
+        let e = parse_string_array(r#"["a", 1]"#).unwrap_err();
+        assert_eq!(e.to_string(), "element 1 of the array isn't a string");
+
+        assert!(parse_string_array(r#"["a", ["b"]]"#).is_err());
+        assert!(parse_string_array(r#"{"a": "b"}"#).is_err());
+        assert!(parse_string_array(r#"["a""#).is_err());
+        assert!(parse_string_array(r#"["a",]"#).is_err());
+        assert!(parse_string_array(r#"["a"] x"#).is_err());
+        assert!(parse_string_array(r#"["\ud800"]"#).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+// A single rule used to decide which file of a duplicate group survives.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// Prefer files whose path matches the glob
+    Glob(String),
+
+    /// Prefer the file with the oldest modification time
+    Oldest,
+
+    /// Prefer the file with the newest modification time
+    Newest,
+
+    /// Prefer the file with the fewest characters in its path
+    Shortest,
+}
+
+// Parse a keep expression: a comma-separated list of rules, such as
+// `glob:/mnt/main/**,oldest,shortest`. Rules are listed in order of precedence.
+pub fn parse(expr: &str) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+
+    for token in expr.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let rule = match token {
+            "oldest" => Rule::Oldest,
+            "newest" => Rule::Newest,
+            "shortest" => Rule::Shortest,
+            _ => match token.strip_prefix("glob:") {
+                Some(pattern) if !pattern.is_empty() => Rule::Glob(String::from(pattern)),
+                _ => return Err(anyhow!("unknown keep rule: {}", token)),
+            },
+        };
+
+        rules.push(rule);
+    }
+
+    Ok(rules)
+}
+
+// Compare two files under a single rule; `Less` means `a` is preferred.
+fn compare_by(rule: &Rule, a: &PossDupe, b: &PossDupe) -> Ordering {
+    match rule {
+        Rule::Glob(pattern) => {
+            let a_matches = crate::glob::matches(pattern, &a.path.to_string_lossy());
+            let b_matches = crate::glob::matches(pattern, &b.path.to_string_lossy());
+            b_matches.cmp(&a_matches)
+        }
+        // Files without a known modification time are never preferred.
+        Rule::Oldest => match (a.mtime, b.mtime) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        },
+        Rule::Newest => match (a.mtime, b.mtime) {
+            (Some(a), Some(b)) => b.cmp(&a),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        },
+        Rule::Shortest => a.path.as_os_str().len().cmp(&b.path.as_os_str().len()),
+    }
+}
+
+// Compare two files under all rules. The first rule that prefers one file over
+// the other decides; if every rule ties, the lexicographically smallest path wins
+// so the choice is deterministic.
+pub fn compare(rules: &[Rule], a: &PossDupe, b: &PossDupe) -> Ordering {
+    rules
+        .iter()
+        .map(|rule| compare_by(rule, a, b))
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or_else(|| a.path.cmp(&b.path))
+}
+
+// Order a duplicate group so that the file to keep comes first.
+pub fn order_group(rules: &[Rule], group: &mut [PossDupe]) {
+    group.sort_by(|a, b| compare(rules, a, b));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn mk_pd(path: &str, age_secs: Option<u64>) -> PossDupe {
+        let mut pd = PossDupe::new(path, 10);
+        pd.mtime = age_secs.map(|s| SystemTime::UNIX_EPOCH + Duration::from_secs(s));
+        pd
+    }
+
+    fn paths(group: &[PossDupe]) -> Vec<&str> {
+        group.iter().map(|pd| pd.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert_eq!(
+            parse("glob:/a/**, oldest,shortest").unwrap(),
+            vec![
+                Rule::Glob(String::from("/a/**")),
+                Rule::Oldest,
+                Rule::Shortest
+            ]
+        );
+        assert!(parse("biggest").is_err());
+        assert!(parse("glob:").is_err());
+    }
+
+    #[test]
+    fn no_rules_prefers_smallest_path() {
+        let mut group = vec![mk_pd("/b", None), mk_pd("/a", None)];
+        order_group(&[], &mut group);
+        assert_eq!(paths(&group), vec!["/a", "/b"]);
+    }
+
+    #[test]
+    fn earlier_rules_take_precedence() {
+        let mut group = vec![
+            mk_pd("/backup/x", Some(1)),
+            mk_pd("/main/long/x", Some(3)),
+            mk_pd("/main/x", Some(2)),
+        ];
+
+        order_group(&parse("glob:/main/**,oldest").unwrap(), &mut group);
+        assert_eq!(paths(&group), vec!["/main/x", "/main/long/x", "/backup/x"]);
+
+        order_group(&parse("oldest,glob:/main/**").unwrap(), &mut group);
+        assert_eq!(paths(&group), vec!["/backup/x", "/main/x", "/main/long/x"]);
+    }
+
+    #[test]
+    fn unknown_mtime_is_never_preferred() {
+        let mut group = vec![mk_pd("/a", None), mk_pd("/b", Some(5))];
+
+        order_group(&[Rule::Newest], &mut group);
+        assert_eq!(paths(&group), vec!["/b", "/a"]);
+
+        order_group(&[Rule::Oldest], &mut group);
+        assert_eq!(paths(&group), vec!["/b", "/a"]);
+    }
+
+    #[test]
+    fn shortest_path() {
+        let mut group = vec![mk_pd("/aaa/x", None), mk_pd("/b/x", None)];
+        order_group(&[Rule::Shortest], &mut group);
+        assert_eq!(paths(&group), vec!["/b/x", "/aaa/x"]);
+    }
+}
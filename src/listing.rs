@@ -0,0 +1,268 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Reading paths out of long directory listings, as written by `ls -l` or `find -ls`.
+
+use anyhow::{bail, Result};
+
+const MONTHS: [&[u8]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+// The start and end of each whitespace separated field in a line.
+fn fields(line: &[u8]) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    let mut start = None;
+
+    for (i, &b) in line.iter().enumerate() {
+        match (b == b' ' || b == b'\t', start) {
+            (true, Some(s)) => {
+                result.push((s, i));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+
+    if let Some(s) = start {
+        result.push((s, line.len()));
+    }
+
+    result
+}
+
+// Something like `-rw-r--r--`, `drwxr-xr-x.` or `lrwxrwxrwx+`.
+fn is_permissions(field: &[u8]) -> bool {
+    let (kind, rest) = match field.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    let rest = match rest {
+        [modes @ .., b'.'] | [modes @ .., b'+'] | [modes @ .., b'@'] => modes,
+        modes => modes,
+    };
+
+    b"-dlcbpsD".contains(kind) && rest.len() == 9 && rest.iter().all(|b| b"rwxsStT-".contains(b))
+}
+
+fn is_digits(field: &[u8], lengths: std::ops::RangeInclusive<usize>) -> bool {
+    lengths.contains(&field.len()) && field.iter().all(u8::is_ascii_digit)
+}
+
+// `2026-10-16`, as written with `--time-style=long-iso` or `full-iso`.
+fn is_iso_date(field: &[u8]) -> bool {
+    field.len() == 10
+        && field[4] == b'-'
+        && field[7] == b'-'
+        && is_digits(&field[..4], 4..=4)
+        && is_digits(&field[5..7], 2..=2)
+        && is_digits(&field[8..], 2..=2)
+}
+
+// `07:47` or `07:47:08.736791752`.
+fn is_time(field: &[u8]) -> bool {
+    field.len() >= 5 && field[2] == b':' && is_digits(&field[..2], 2..=2)
+}
+
+// `+0000` or `-0500`.
+fn is_zone(field: &[u8]) -> bool {
+    field.len() == 5 && (field[0] == b'+' || field[0] == b'-') && is_digits(&field[1..], 4..=4)
+}
+
+// The number of fields taken up by a date starting at `fields[0]`, if there is one.
+fn date_len(line: &[u8], fields: &[(usize, usize)]) -> Option<usize> {
+    let field = |i: usize| fields.get(i).map(|&(start, end)| &line[start..end]);
+
+    if is_iso_date(field(0)?) {
+        if !is_time(field(1)?) {
+            return None;
+        }
+
+        return Some(if field(2).is_some_and(is_zone) { 3 } else { 2 });
+    }
+
+    if MONTHS.contains(&field(0)?)
+        && is_digits(field(1)?, 1..=2)
+        && (is_time(field(2)?) || is_digits(field(2)?, 4..=4))
+    {
+        return Some(3);
+    }
+
+    None
+}
+
+// Undoes the backslash escapes `find -ls` uses for spaces, backslashes and unprintable characters.
+fn unescape(name: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(name.len());
+    let mut i = 0;
+
+    while i < name.len() {
+        if name[i] != b'\\' || i + 1 == name.len() {
+            result.push(name[i]);
+            i += 1;
+            continue;
+        }
+
+        let octal = &name[i + 1..name.len().min(i + 4)];
+
+        if octal.len() == 3 && octal.iter().all(|b| (b'0'..=b'7').contains(b)) {
+            result.push(
+                octal
+                    .iter()
+                    .fold(0u8, |n, b| n.wrapping_mul(8) + (b - b'0')),
+            );
+            i += 4;
+            continue;
+        }
+
+        result.push(match name[i + 1] {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            other => other,
+        });
+        i += 2;
+    }
+
+    result
+}
+
+// The path in one line of a listing, or `None` for lines without one, such as the `total` line
+// at the top of `ls -l` output. Everything after the date is the path, so names with spaces are
+// kept whole; the target of a symlink is dropped.
+pub fn parse_line(line: &[u8]) -> Result<Option<Vec<u8>>> {
+    let fields = fields(line);
+
+    if fields.is_empty() || (fields.len() == 2 && &line[fields[0].0..fields[0].1] == b"total") {
+        return Ok(None);
+    }
+
+    // `ls -l` starts with the permissions; `find -ls` has an inode number and a block count first.
+    let permissions = match fields
+        .iter()
+        .take(3)
+        .position(|&(start, end)| is_permissions(&line[start..end]))
+    {
+        Some(i) => i,
+        None => bail!("no permissions column"),
+    };
+    let escaped = permissions == 2;
+
+    // The link count, owner, group and size come next, though some of them can be left out.
+    let date =
+        match (permissions + 2..fields.len()).find(|&i| date_len(line, &fields[i..]).is_some()) {
+            Some(i) => i,
+            None => bail!("no date column"),
+        };
+    let last = date + date_len(line, &fields[date..]).unwrap() - 1;
+
+    if last + 1 == fields.len() {
+        bail!("no path after the date");
+    }
+
+    // The path starts after exactly one separator, in case the name itself starts with a space.
+    let mut name = &line[fields[last].1 + 1..];
+
+    if line[fields[permissions].0] == b'l' {
+        if let Some(arrow) = name.windows(4).position(|w| w == b" -> ") {
+            name = &name[..arrow];
+        }
+    }
+
+    Ok(Some(if escaped {
+        unescape(name)
+    } else {
+        name.to_vec()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<String> {
+        parse_line(line.as_bytes())
+            .unwrap()
+            .map(|path| String::from_utf8(path).unwrap())
+    }
+
+    #[test]
+    fn parses_ls_output() {
+        assert_eq!(parse("total 12"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(
+            parse("-rw-r--r-- 1 root root    3 Oct 16 07:54 a b.txt").as_deref(),
+            Some("a b.txt")
+        );
+        assert_eq!(
+            parse("lrwxrwxrwx 1 root root    7 Oct 16 07:54 link one -> a b.txt").as_deref(),
+            Some("link one")
+        );
+        assert_eq!(
+            parse("-rw-r--r-- 1 root root    0 Jan  1  2020 old").as_deref(),
+            Some("old")
+        );
+        assert_eq!(
+            parse("-rw-r--r--. 1 0 0 2 2026-10-16 07:54 plain").as_deref(),
+            Some("plain")
+        );
+        assert_eq!(
+            parse("-rw-r--r-- 1 root root 3 2026-10-16 07:54:08.736791752 +0000  leading space")
+                .as_deref(),
+            Some(" leading space")
+        );
+        assert_eq!(
+            parse("crw-rw-rw- 1 root root 1, 3 Oct 16 07:47 null").as_deref(),
+            Some("null")
+        );
+    }
+
+    #[test]
+    fn parses_find_output() {
+        assert_eq!(
+            parse("  1220620      4 -rw-r--r--   1 root     root            3 Oct 16 07:54 ./a\\ b.txt")
+                .as_deref(),
+            Some("./a b.txt")
+        );
+        assert_eq!(
+            parse("  1220631      0 lrwxrwxrwx   1 root     root            7 Oct 16 07:54 ./link\\ one -> a\\ b.txt")
+                .as_deref(),
+            Some("./link one")
+        );
+        assert_eq!(
+            parse("  1220625      4 -rw-r--r--   1 root     root            2 Oct 16 07:54 ./back\\\\slash\\ttab\\303\\251")
+                .as_deref(),
+            Some("./back\\slash\ttabé")
+        );
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        assert!(parse_line(b"./plain").is_err());
+        assert!(parse_line(b"-rw-r--r-- 1 root root 3 yesterday plain").is_err());
+        assert!(parse_line(b"-rw-r--r-- 1 root root 3 Oct 16 07:54").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Sniffing a file's type from the magic bytes at its start, so only files which
+// really are, say, images are considered, whatever their names.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Number of bytes read from the start of each file to sniff its type
+pub const SNIFF_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Tiff,
+    Bmp,
+    Heic,
+    Avif,
+    Mp4,
+    Mov,
+    Mkv,
+    Avi,
+    Pdf,
+    Zip,
+    Gzip,
+}
+
+// Each kind's token on the command line
+const TOKENS: &[(&str, Kind)] = &[
+    ("jpeg", Kind::Jpeg),
+    ("jpg", Kind::Jpeg),
+    ("png", Kind::Png),
+    ("gif", Kind::Gif),
+    ("webp", Kind::Webp),
+    ("tiff", Kind::Tiff),
+    ("bmp", Kind::Bmp),
+    ("heic", Kind::Heic),
+    ("avif", Kind::Avif),
+    ("mp4", Kind::Mp4),
+    ("mov", Kind::Mov),
+    ("mkv", Kind::Mkv),
+    ("avi", Kind::Avi),
+    ("pdf", Kind::Pdf),
+    ("zip", Kind::Zip),
+    ("gzip", Kind::Gzip),
+];
+
+const IMAGE: &[Kind] = &[
+    Kind::Jpeg,
+    Kind::Png,
+    Kind::Gif,
+    Kind::Webp,
+    Kind::Tiff,
+    Kind::Bmp,
+    Kind::Heic,
+    Kind::Avif,
+];
+
+const VIDEO: &[Kind] = &[Kind::Mp4, Kind::Mov, Kind::Mkv, Kind::Avi];
+
+// Parse a comma-separated list of type tokens, such as `jpeg,png` or `image`,
+// where `image` and `video` stand for every kind of image or video.
+pub fn parse(expr: &str) -> Result<Vec<Kind>> {
+    let mut kinds = Vec::new();
+
+    for token in expr.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        match token {
+            "image" => kinds.extend_from_slice(IMAGE),
+            "video" => kinds.extend_from_slice(VIDEO),
+            _ => match TOKENS.iter().find(|(name, _)| *name == token) {
+                Some((_, kind)) => kinds.push(*kind),
+                None => return Err(anyhow!("unknown file type: {}", token)),
+            },
+        }
+    }
+
+    if kinds.is_empty() {
+        return Err(anyhow!("no file types given"));
+    }
+
+    Ok(kinds)
+}
+
+// Identify an ISO base media file (MP4, MOV, HEIC, AVIF, ...) by the major brand
+// in its `ftyp` box.
+fn sniff_brand(brand: &[u8]) -> Kind {
+    match brand {
+        b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"mif1" | b"msf1" => Kind::Heic,
+        b"avif" | b"avis" => Kind::Avif,
+        b"qt  " => Kind::Mov,
+        _ => Kind::Mp4,
+    }
+}
+
+// Identify a file's type from its first bytes, which may be fewer than SNIFF_LEN
+// for small files.
+pub fn sniff(header: &[u8]) -> Option<Kind> {
+    let starts = |magic: &[u8]| header.starts_with(magic);
+
+    if starts(b"\xFF\xD8\xFF") {
+        Some(Kind::Jpeg)
+    } else if starts(b"\x89PNG\r\n\x1A\n") {
+        Some(Kind::Png)
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some(Kind::Gif)
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        Some(Kind::Tiff)
+    } else if starts(b"BM") {
+        Some(Kind::Bmp)
+    } else if starts(b"\x1A\x45\xDF\xA3") {
+        Some(Kind::Mkv)
+    } else if starts(b"%PDF-") {
+        Some(Kind::Pdf)
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        Some(Kind::Zip)
+    } else if starts(b"\x1F\x8B") {
+        Some(Kind::Gzip)
+    } else if header.len() >= 12 && starts(b"RIFF") {
+        match &header[8..12] {
+            b"WEBP" => Some(Kind::Webp),
+            b"AVI " => Some(Kind::Avi),
+            _ => None,
+        }
+    } else if header.len() >= 12 && &header[4..8] == b"ftyp" {
+        Some(sniff_brand(&header[8..12]))
+    } else {
+        None
+    }
+}
+
+// Return true if the file at `path` is one of the given kinds.
+pub fn matches(path: &Path, kinds: &[Kind]) -> Result<bool> {
+    File::open(crate::platform::long_path(path))
+        .and_then(|f| reader_matches(f, kinds))
+        .with_context(|| format!("couldn't read {} to find its type", path.display()))
+}
+
+// Return true if the contents read from `reader` are one of the given kinds.
+pub fn reader_matches<R: Read>(reader: R, kinds: &[Kind]) -> std::io::Result<bool> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    reader.take(SNIFF_LEN as u64).read_to_end(&mut header)?;
+
+    Ok(sniff(&header).is_some_and(|kind| kinds.contains(&kind)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_types() {
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF\0"), Some(Kind::Jpeg));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1A\n\0\0\0\x0D"), Some(Kind::Png));
+        assert_eq!(sniff(b"GIF89a"), Some(Kind::Gif));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some(Kind::Webp));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0AVI LIST"), Some(Kind::Avi));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"\0\0\0\x18ftypheic"), Some(Kind::Heic));
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom"), Some(Kind::Mp4));
+        assert_eq!(sniff(b"\0\0\0\x14ftypqt  "), Some(Kind::Mov));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some(Kind::Pdf));
+        assert_eq!(sniff(b"hello, world"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn parses_tokens() {
+        assert_eq!(parse("jpeg, png").unwrap(), vec![Kind::Jpeg, Kind::Png]);
+        assert_eq!(parse("video").unwrap(), VIDEO);
+        assert!(parse("image,pdf").unwrap().contains(&Kind::Pdf));
+        assert_eq!(parse("jpg").unwrap(), vec![Kind::Jpeg]);
+        assert!(parse("exe").is_err());
+        assert!(parse(",").is_err());
+    }
+}
@@ -20,24 +20,99 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 mod algo;
+mod bloom;
+mod clean;
 mod cli;
+mod compress;
 mod fddup;
+mod filter;
+mod glob;
+mod interrupt;
+mod json;
+mod keep;
+mod listing;
+mod magic;
+mod manifest;
+mod near;
+mod output;
+mod platform;
 mod possdupe;
+mod reflink;
+mod roots;
+mod script;
+mod sizeindex;
+mod stages;
 mod stats;
+mod status;
+mod tar;
+mod text;
+mod timespec;
+mod verify;
+mod walk;
 
 use anyhow::Result;
+use log::{error, LevelFilter};
+use std::io::Write;
 use tokio::runtime::Builder;
 
 fn main() -> Result<()> {
-    let options = crate::cli::Options::parse();
+    let options = match crate::cli::parse() {
+        crate::cli::Command::Scan(options) => *options,
+        crate::cli::Command::Verify(manifest) => std::process::exit(verify_manifest(&manifest)),
+    };
+    init_logger(options.verbosity);
 
-    let runtime = Builder::new_multi_thread()
-        .thread_name("fddup")
-        .worker_threads(options.num_threads)
-        .enable_all()
-        .build()?;
+    // With --single-thread, every task runs on this thread rather than a pool of
+    // workers, so nothing is scheduled concurrently.
+    let runtime = if options.single_thread {
+        Builder::new_current_thread().enable_all().build()?
+    } else {
+        Builder::new_multi_thread()
+            .thread_name("fddup")
+            .worker_threads(options.num_threads)
+            .enable_all()
+            .build()?
+    };
 
-    runtime.block_on(fddup::Fddup::new(options).run());
+    crate::interrupt::install();
+    let exit_code = runtime.block_on(fddup::Fddup::new(options).run());
+    drop(runtime);
 
-    Ok(())
+    std::process::exit(exit_code)
+}
+
+// Write errors to stderr, along with debug messages tracing why each file was or
+// wasn't grouped with `-vv`.
+fn init_logger(verbosity: usize) {
+    let level = if verbosity >= 2 {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Error
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "{}: {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            )
+        })
+        .init();
+}
+
+// Check files against a manifest, returning the process's exit status.
+fn verify_manifest(manifest: &std::path::Path) -> i32 {
+    match crate::manifest::check(manifest, &mut std::io::stdout()) {
+        Ok(check) => {
+            eprintln!("{}", check.summary());
+            i32::from(!check.passed())
+        }
+        Err(e) => {
+            error!("{}", e);
+            2
+        }
+    }
 }
@@ -0,0 +1,346 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A manifest lists every file scanned, whether unique or a duplicate, once its
+// outcome is known. Each line is `DIGEST  SIZE  PATH`, where DIGEST is one of:
+//
+// - the hex digest of the entire file
+// - `partial:BYTES:HEX`, the digest of only the first BYTES bytes, for files which
+//   were found to be unique before being read in full
+// - `sampled:HEX`, the digest of the blocks read with `--sample`
+// - `text:HEX`, the digest of the normalized text with `--normalize-text`
+// - `link:HEX`, the digest of a link's target path with `--symlink-mode target`
+// - `unread`, for files which were never read, because their size was unique,
+//   they were empty or `--bloom` knew them to be unique
+//
+// With `--digest-bits`, digests are truncated just as they are in the output.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const HEADER: &str = "# fddup manifest: DIGEST  SIZE  PATH";
+
+pub struct Manifest {
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+// The DIGEST field of a file's manifest entry.
+fn digest_field(pd: &PossDupe) -> String {
+    if pd.link_target.is_some() {
+        format!("link:{}", pd.digest_hex())
+    } else if pd.bytes_read == 0 {
+        String::from("unread")
+    } else if pd.normalized {
+        format!("text:{}", pd.digest_hex())
+    } else if pd.decompressed {
+        format!("gunzip:{}", pd.digest_hex())
+    } else if pd.sampling.is_some() {
+        format!("sampled:{}", pd.digest_hex())
+    } else if pd.content_offset() + pd.bytes_read < pd.file_len {
+        format!("partial:{}:{}", pd.bytes_read, pd.digest_hex())
+    } else {
+        pd.digest_hex()
+    }
+}
+
+impl Manifest {
+    pub fn new<P: AsRef<Path>>(filename: P, mode: Option<u32>) -> Result<Manifest> {
+        let mut writer = crate::cli::file_writer(filename, mode)?;
+        writeln!(writer, "{}", HEADER)?;
+
+        Ok(Manifest { writer })
+    }
+
+    pub fn write(&mut self, pd: &PossDupe) -> Result<()> {
+        write!(self.writer, "{}  {}  ", digest_field(pd), pd.file_len)?;
+        self.writer
+            .write_all(&crate::platform::path_bytes(&pd.path))?;
+        writeln!(self.writer)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Outcome of checking files against a manifest with `fddup verify`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Check {
+    // Files whose contents still have the digest recorded
+    pub num_ok: usize,
+
+    // Files whose size or digest no longer match
+    pub num_changed: usize,
+
+    // Files which couldn't be opened, such as those removed since
+    pub num_missing: usize,
+
+    // Entries without a full digest to check, such as partial or unread ones
+    pub num_skipped: usize,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.num_changed == 0 && self.num_missing == 0
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "checked {} files: {} ok, {} changed, {} missing; {} skipped without a full digest",
+            self.num_ok + self.num_changed + self.num_missing,
+            self.num_ok,
+            self.num_changed,
+            self.num_missing,
+            self.num_skipped
+        )
+    }
+}
+
+// Split a manifest entry into its digest, size and path. Digests and sizes never
+// contain the separator, so whatever follows them is the path, however odd.
+fn parse_entry(line: &[u8]) -> Option<(&str, u64, &[u8])> {
+    let field = |line: &'_ [u8]| -> Option<(usize, usize)> {
+        let end = line.windows(2).position(|w| w == b"  ")?;
+        Some((end, end + 2))
+    };
+
+    let (digest_end, rest) = field(line)?;
+    let digest = std::str::from_utf8(&line[..digest_end]).ok()?;
+    let line = &line[rest..];
+    let (size_end, rest) = field(line)?;
+    let size = std::str::from_utf8(&line[..size_end]).ok()?.parse().ok()?;
+
+    Some((digest, size, &line[rest..]))
+}
+
+// Return true if a digest field is a full (if perhaps truncated) hex digest,
+// rather than one of the other kinds of entry.
+fn is_full_digest(digest: &str) -> bool {
+    !digest.is_empty() && digest.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Compare a digest from a manifest with a file's digest, ignoring case. The
+// manifest may come from anyone, so every digit is compared whatever the first
+// difference, and how long the comparison takes says nothing about how much of
+// the digest matched. Lengths aren't secret; they're in the manifest itself.
+fn digests_match(expected: &str, actual: &str) -> bool {
+    if expected.len() != actual.len() {
+        return false;
+    }
+
+    let difference = expected.bytes().zip(actual.bytes()).fold(0, |acc, (e, a)| {
+        acc | (e.to_ascii_lowercase() ^ a.to_ascii_lowercase())
+    });
+
+    difference == 0
+}
+
+// Hex digest of a file's entire contents, truncated to `hex_len` digits.
+fn file_digest(path: &Path, hex_len: usize) -> std::io::Result<String> {
+    let mut file = File::open(crate::platform::long_path(path))?;
+    let mut digest = Sha256::new();
+    let mut buffer = vec![0; crate::cli::DEFAULT_READ_SIZE];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            n => digest.update(&buffer[..n]),
+        }
+    }
+
+    let mut hex = hex::encode(digest.finalize());
+    hex.truncate(hex_len);
+    Ok(hex)
+}
+
+// Check every file with a full digest in a manifest against its contents now,
+// writing a line for each file which changed or is missing to `writer`. Entries
+// written with `--skip-header` record the digest of the contents after the
+// header, so they're reported as changed.
+pub fn check<W: Write>(filename: &Path, writer: &mut W) -> Result<Check> {
+    let file = File::open(filename)
+        .with_context(|| format!("couldn't open manifest {}", filename.display()))?;
+    let mut check = Check::default();
+
+    for (number, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line =
+            line.with_context(|| format!("couldn't read manifest {}", filename.display()))?;
+
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+
+        let (digest, size, path) = parse_entry(&line).ok_or_else(|| {
+            anyhow!(
+                "{}:{}: not a manifest entry",
+                filename.display(),
+                number + 1
+            )
+        })?;
+
+        if !is_full_digest(digest) {
+            check.num_skipped += 1;
+            continue;
+        }
+
+        let path: PathBuf = crate::platform::path_from_bytes(path.to_vec())?;
+        let status = match std::fs::metadata(crate::platform::long_path(&path)) {
+            Err(_) => "MISSING",
+            Ok(attr) if attr.len() != size => "CHANGED",
+            Ok(_) => match file_digest(&path, digest.len()) {
+                Ok(actual) if digests_match(digest, &actual) => "OK",
+                Ok(_) => "CHANGED",
+                Err(_) => "MISSING",
+            },
+        };
+
+        match status {
+            "OK" => check.num_ok += 1,
+            "CHANGED" => check.num_changed += 1,
+            _ => check.num_missing += 1,
+        }
+
+        if status != "OK" {
+            write!(writer, "{}  ", status)?;
+            writer.write_all(&crate::platform::path_bytes(&path))?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::possdupe::Sampling;
+
+    #[test]
+    fn digest_fields() {
+        let mut pd = PossDupe::new("a", 4);
+        pd.digest_len = 2;
+        assert_eq!(digest_field(&pd), "unread");
+        assert_eq!(digest_field(&PossDupe::new("e", 0)), "unread");
+
+        pd.update_digest(b"ab");
+        pd.bytes_read = 2;
+        assert_eq!(digest_field(&pd), "partial:2:fb8e");
+
+        pd.update_digest(b"cd");
+        pd.bytes_read = 4;
+        assert_eq!(digest_field(&pd), "88d4");
+
+        pd.sampling = Some(Sampling {
+            blocks: 1,
+            block_size: 1,
+        });
+        assert_eq!(digest_field(&pd), "sampled:88d4");
+
+        pd.sampling = None;
+        pd.normalized = true;
+        assert_eq!(digest_field(&pd), "text:88d4");
+
+        pd.normalized = false;
+        pd.decompressed = true;
+        assert_eq!(digest_field(&pd), "gunzip:88d4");
+
+        pd.link_target = Some(std::path::Path::new("abcd").into());
+        assert!(digest_field(&pd).starts_with("link:"));
+    }
+
+    #[test]
+    fn digests_match_whatever_the_case() {
+        assert!(digests_match("88d4266f", "88d4266f"));
+        assert!(digests_match("88D4266F", "88d4266f"));
+        assert!(!digests_match("98d4266f", "88d4266f"));
+        assert!(!digests_match("88d4266e", "88d4266f"));
+        assert!(!digests_match("88d4", "88d4266f"));
+        assert!(digests_match("", ""));
+    }
+
+    #[test]
+    fn parse_entries() {
+        assert_eq!(
+            parse_entry(b"88d4  4  dir/a  b"),
+            Some(("88d4", 4, &b"dir/a  b"[..]))
+        );
+        assert_eq!(
+            parse_entry(b"partial:2:fb8e  4  a"),
+            Some(("partial:2:fb8e", 4, &b"a"[..]))
+        );
+        assert_eq!(parse_entry(b"88d4  four  a"), None);
+        assert_eq!(parse_entry(b"88d4 4 a"), None);
+
+        assert!(is_full_digest("88d4"));
+        assert!(!is_full_digest("partial:2:fb8e"));
+        assert!(!is_full_digest("unread"));
+    }
+
+    #[test]
+    fn checks_files_against_a_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("same"), "abcd").unwrap();
+        std::fs::write(root.join("changed"), "abce").unwrap();
+
+        // The digests of "abcd", truncated and not.
+        let manifest = format!(
+            "{}\n{}  4  {}\n88d4  4  {}\n88d4  4  {}\nunread  9  {}\n",
+            HEADER,
+            "88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589",
+            root.join("changed").display(),
+            root.join("same").display(),
+            root.join("missing").display(),
+            root.join("same").display(),
+        );
+        std::fs::write(root.join("manifest"), manifest).unwrap();
+
+        let mut report = Vec::new();
+        let check = check(&root.join("manifest"), &mut report).unwrap();
+
+        assert_eq!(
+            check,
+            Check {
+                num_ok: 1,
+                num_changed: 1,
+                num_missing: 1,
+                num_skipped: 1,
+            }
+        );
+        assert!(!check.passed());
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            format!(
+                "CHANGED  {}\nMISSING  {}\n",
+                root.join("changed").display(),
+                root.join("missing").display()
+            )
+        );
+    }
+}
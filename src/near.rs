@@ -0,0 +1,245 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Near duplicates: files which aren't identical but whose sizes are within a
+// tolerance of each other and which share their first or last block, such as a
+// file with metadata appended to it or a header rewritten. Each candidate's first
+// and last block are hashed, and files sharing either are clustered together.
+// Clusters are linked pair by pair, so the smallest and largest files in a cluster
+// may be further apart than the tolerance.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+
+// Size of the first and last blocks compared. Small enough that the blocks of most
+// files leave plenty in between to differ; a file no larger than a block can only
+// match a file of the same size, which would be a duplicate.
+pub const BLOCK_SIZE: u64 = 16 * 1024;
+
+// Digests of a file's first and last blocks, which overlap for small files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+// Return true if two sizes differ by no more than `tolerance` percent of the
+// smaller one.
+pub fn within_tolerance(a: u64, b: u64, tolerance: f64) -> bool {
+    let (smaller, larger) = (a.min(b), a.max(b));
+    (larger - smaller) as f64 <= smaller as f64 * tolerance / 100.0
+}
+
+// Keep only the files whose size is within the tolerance of another file's, which
+// are the only ones which could be near duplicates, ordered by size. Files no
+// larger than a block and links have no first and last blocks to compare.
+pub fn candidates(mut files: Vec<PossDupe>, tolerance: f64) -> Vec<PossDupe> {
+    files.retain(|pd| pd.file_len > BLOCK_SIZE && pd.link_target.is_none());
+    files.sort_by(|a, b| (a.file_len, &a.path).cmp(&(b.file_len, &b.path)));
+
+    let near_next: Vec<bool> = files
+        .windows(2)
+        .map(|pair| within_tolerance(pair[0].file_len, pair[1].file_len, tolerance))
+        .collect();
+
+    files
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| near_next.get(*i) == Some(&true) || (*i > 0 && near_next[*i - 1]))
+        .map(|(_, pd)| pd)
+        .collect()
+}
+
+// A stand-in for the file kept from a group of duplicates, which is sent to be
+// output, so the group can still be compared with other files for near duplicates.
+pub fn representative(keep: &PossDupe) -> PossDupe {
+    let mut pd = PossDupe::new(keep.path.clone(), keep.file_len);
+    pd.archive = keep.archive.clone();
+    pd.link_target = keep.link_target.clone();
+    pd
+}
+
+// Hash the first and last `block_size` bytes of a file.
+pub fn fingerprint(pd: &PossDupe, block_size: u64) -> Result<Fingerprint> {
+    let len = block_size.min(pd.file_len);
+    let mut file = pd.open_at(0)?;
+    let mut block = vec![0; len as usize];
+
+    file.read_exact(&mut block)
+        .with_context(|| format!("couldn't read {}", pd.path.display()))?;
+    let head = Sha256::digest(&block).to_vec();
+
+    if pd.file_len > len {
+        file.seek(SeekFrom::Start(pd.base_offset() + pd.file_len - len))
+            .with_context(|| format!("couldn't seek in {}", pd.path.display()))?;
+        file.read_exact(&mut block)
+            .with_context(|| format!("couldn't read {}", pd.path.display()))?;
+    }
+
+    let tail = Sha256::digest(&block).to_vec();
+
+    Ok(Fingerprint { head, tail })
+}
+
+// Find the root of an item's cluster, shortening the path to it along the way.
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+
+    i
+}
+
+// Cluster fingerprinted files, ordered by size, which are within the tolerance of
+// each other and share their first or last block. Files in each cluster are
+// ordered by size, and clusters by their smallest file; files in no cluster are
+// dropped.
+pub fn clusters(files: Vec<(PossDupe, Fingerprint)>, tolerance: f64) -> Vec<Vec<PossDupe>> {
+    let mut parents: Vec<usize> = (0..files.len()).collect();
+
+    for i in 0..files.len() {
+        for j in i + 1..files.len() {
+            let ((a, fa), (b, fb)) = (&files[i], &files[j]);
+
+            if !within_tolerance(a.file_len, b.file_len, tolerance) {
+                break;
+            }
+
+            if fa.head == fb.head || fa.tail == fb.tail {
+                let (root_a, root_b) = (find(&mut parents, i), find(&mut parents, j));
+                parents[root_b.max(root_a)] = root_a.min(root_b);
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<PossDupe>> = BTreeMap::new();
+
+    for (i, (pd, _)) in files.into_iter().enumerate() {
+        let root = find(&mut parents, i);
+        clusters.entry(root).or_default().push(pd);
+    }
+
+    clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    fn fp(head: u8, tail: u8) -> Fingerprint {
+        Fingerprint {
+            head: vec![head],
+            tail: vec![tail],
+        }
+    }
+
+    fn paths(clusters: &[Vec<PossDupe>]) -> Vec<Vec<String>> {
+        clusters
+            .iter()
+            .map(|c| c.iter().map(|pd| pd.path.display().to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tolerance_is_relative_to_the_smaller_size() {
+        assert!(within_tolerance(100, 110, 10.0));
+        assert!(within_tolerance(110, 100, 10.0));
+        assert!(!within_tolerance(100, 111, 10.0));
+        assert!(within_tolerance(100, 100, 0.0));
+    }
+
+    #[test]
+    fn candidates_have_a_size_within_tolerance() {
+        let k = BLOCK_SIZE;
+        let files = vec![
+            PossDupe::new("e", k),
+            PossDupe::new("d", 10 * k),
+            PossDupe::new("c", 3 * k),
+            PossDupe::new("a", 2 * k),
+            PossDupe::new("b", 2 * k + 100),
+        ];
+
+        let names: Vec<_> = candidates(files, 5.0)
+            .iter()
+            .map(|pd| pd.path.display().to_string())
+            .collect();
+
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn clusters_by_head_or_tail() {
+        let files = vec![
+            (PossDupe::new("a", 100), fp(1, 1)),
+            (PossDupe::new("b", 101), fp(1, 2)),
+            (PossDupe::new("c", 102), fp(3, 2)),
+            (PossDupe::new("d", 103), fp(4, 4)),
+            (PossDupe::new("e", 104), fp(5, 5)),
+            (PossDupe::new("f", 105), fp(5, 6)),
+        ];
+
+        assert_eq!(
+            paths(&clusters(files, 10.0)),
+            vec![vec!["a", "b", "c"], vec!["e", "f"]]
+        );
+    }
+
+    #[test]
+    fn clusters_only_within_tolerance() {
+        let files = vec![
+            (PossDupe::new("a", 100), fp(1, 1)),
+            (PossDupe::new("b", 200), fp(1, 1)),
+        ];
+
+        assert!(clusters(files, 10.0).is_empty());
+    }
+
+    #[test]
+    fn fingerprints_first_and_last_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("a"), b"headmiddletail").unwrap();
+        write(root.join("b"), b"headMIDDLE!tail").unwrap();
+        write(root.join("c"), b"HEADmiddletail!").unwrap();
+
+        let fingerprint = |name: &str| {
+            let len = std::fs::metadata(root.join(name)).unwrap().len();
+            super::fingerprint(&PossDupe::new(root.join(name), len), 4).unwrap()
+        };
+
+        let (a, b, c) = (fingerprint("a"), fingerprint("b"), fingerprint("c"));
+        assert_eq!((a.head == b.head, a.tail == b.tail), (true, true));
+        assert_eq!((a.head == c.head, a.tail == c.tail), (false, false));
+
+        // A file no larger than a block has the same first and last block.
+        let small = super::fingerprint(&PossDupe::new(root.join("a"), 14), 64).unwrap();
+        assert_eq!(small.head, small.tail);
+    }
+}
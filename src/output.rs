@@ -0,0 +1,852 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use crate::cli::{Color, Options};
+use crate::possdupe::PossDupe;
+use crate::roots::Root;
+use crate::stats::{self, Stats};
+
+use ansi_term::{Colour, Style};
+use anyhow::{anyhow, Context, Result};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+// Number of groups which may be waiting to be written before sending another
+// blocks, so a slow output can't make groups pile up in memory.
+const OUTPUT_QUEUE_LEN: usize = 256;
+
+// Styles used when writing colorized output.
+struct Palette {
+    digest: Style,
+    size: Style,
+    header: Style,
+    keep: Style,
+}
+
+impl Palette {
+    fn new() -> Palette {
+        Palette {
+            digest: Style::new().dimmed(),
+            size: Colour::Yellow.bold(),
+            header: Colour::Blue.dimmed(),
+            keep: Colour::Green.bold(),
+        }
+    }
+}
+
+// Writes duplicate groups in the format selected on the command line.
+pub struct Output {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    show_size: bool,
+    show_bytes_read: bool,
+    show_file_id: bool,
+    group_id: bool,
+    field_sep: String,
+    group: bool,
+    mark_keep: bool,
+    count_only: bool,
+    size_only: bool,
+    print_redundant: bool,
+    report: Option<Vec<Vec<PossDupe>>>,
+    terminator: u8,
+    stream: bool,
+    palette: Option<Palette>,
+    groups_written: usize,
+    near_groups_written: usize,
+    near_header: String,
+    output_dir: Option<PathBuf>,
+    output_mode: Option<u32>,
+    group_files: HashSet<String>,
+    roots: Vec<Root>,
+}
+
+// Decide whether to colorize output: only groups written with `--group` are
+// colorized, and `auto` only colorizes when writing to a terminal, so redirected
+// output and `--output` files are never colorized.
+fn use_color(options: &Options) -> bool {
+    if !options.group {
+        return false;
+    }
+
+    match options.color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => options.output.is_none() && io::stdout().is_terminal(),
+    }
+}
+
+// Marker written before the file at `index` in its group with `--mark-keep`. The
+// file to keep is listed first; the others are indented to line up with it.
+fn keep_marker(index: usize) -> &'static str {
+    if index == 0 {
+        "* "
+    } else {
+        "  "
+    }
+}
+
+// Line written before each group with `--root`, listing the labels of the roots
+// the group spans.
+fn roots_line(labels: &[&str]) -> String {
+    match labels.is_empty() {
+        true => String::from("# roots: none"),
+        false => format!("# roots: {}", labels.join(", ")),
+    }
+}
+
+// Line written before the first cluster of near duplicates, which are no more than
+// similar and are listed apart from the duplicates.
+fn near_header(options: &Options) -> String {
+    format!(
+        "# near duplicates, not verified: sizes within {}% and the first or last {} bytes match",
+        options.size_tolerance.unwrap_or_default(),
+        crate::near::BLOCK_SIZE
+    )
+}
+
+// Device and inode of a file as `dev:ino`, or `-` for a file without them, such as
+// an archive member or any file on a platform which doesn't report them.
+fn file_id(pd: &PossDupe) -> String {
+    match pd.file_id {
+        Some((dev, ino)) => format!("{}:{}", dev, ino),
+        None => String::from("-"),
+    }
+}
+
+// Line written before each group with `--report`, such as
+// `### 1.2 GiB × 3 copies (reclaim 2.4 GiB)`.
+fn report_header(group: &[PossDupe]) -> Result<String> {
+    let size = group.first().map_or(0, |pd| pd.file_len);
+
+    Ok(format!(
+        "### {} × {} copies (reclaim {})",
+        stats::to_human_readable(size)?,
+        group.len(),
+        stats::to_human_readable(stats::reclaimable_bytes(group))?
+    ))
+}
+
+// Order groups for `--report`: largest files first, then by the path of the file
+// kept, so the same groups are always listed in the same order.
+fn sort_report(groups: &mut [Vec<PossDupe>]) {
+    fn key(group: &[PossDupe]) -> Option<(Reverse<u64>, &Path)> {
+        group
+            .first()
+            .map(|pd| (Reverse(pd.file_len), pd.path.as_path()))
+    }
+
+    groups.sort_by(|a, b| key(a).cmp(&key(b)));
+}
+
+// Files in a group which could be removed: all but the file to keep, listed first.
+// Links compared by their targets and archive members aren't files which could be
+// removed, and if the file to keep is one of them, the rest are the only copies on
+// disk, so none of the group is listed, as with `--emit-script`.
+fn redundant(group: &[PossDupe]) -> impl Iterator<Item = &PossDupe> {
+    let removable = |pd: &&PossDupe| pd.link_target.is_none() && pd.archive.is_none();
+    let rest = match group.split_first() {
+        Some((keep, rest)) if removable(&keep) => rest,
+        _ => &[],
+    };
+
+    rest.iter().filter(removable)
+}
+
+// Name of the file for a group in `--output-dir`. Groups can share a digest when it
+// is truncated with `--digest-bits` and `--verify` splits them, so later groups
+// with an already used digest get a numeric suffix.
+fn group_file_name(digest: &str, used: &mut HashSet<String>) -> String {
+    let mut name = format!("{}.txt", digest);
+    let mut n = 2;
+
+    while used.contains(&name) {
+        name = format!("{}-{}.txt", digest, n);
+        n += 1;
+    }
+
+    used.insert(name.clone());
+    name
+}
+
+impl Output {
+    pub fn new(mut writer: BufWriter<Box<dyn Write + Send>>, options: &Options) -> Result<Output> {
+        // Sampled results aren't certain, so say so before any of them.
+        if let Some(blocks) = options.sample {
+            writeln!(
+                writer,
+                "# likely duplicates: only {} sampled blocks of each file were compared",
+                blocks
+            )?;
+        }
+
+        // Nor are groups of files which merely share a size.
+        if options.size_only {
+            writeln!(
+                writer,
+                "# same size, not verified: files were grouped by size without reading them"
+            )?;
+        }
+
+        if let Some(dir) = &options.output_dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("couldn't create output directory {}", dir.display()))?;
+        }
+
+        Ok(Output {
+            writer,
+            show_size: options.show_size,
+            show_bytes_read: options.show_bytes_read,
+            show_file_id: options.show_file_id,
+            group_id: options.group_id,
+            field_sep: options.field_sep.clone(),
+            group: options.group,
+            mark_keep: options.mark_keep,
+            count_only: options.count_only,
+            size_only: options.size_only,
+            print_redundant: options.print_redundant,
+            report: options.report.then(Vec::new),
+            terminator: if options.print0 { b'\0' } else { b'\n' },
+            stream: options.stream,
+            palette: if use_color(options) {
+                Some(Palette::new())
+            } else {
+                None
+            },
+            groups_written: 0,
+            near_groups_written: 0,
+            near_header: near_header(options),
+            output_dir: options.output_dir.clone(),
+            output_mode: options.output_mode,
+            group_files: HashSet::new(),
+            roots: options.roots.clone(),
+        })
+    }
+
+    // Write one group of duplicates, the file to keep first.
+    pub fn write_group(&mut self, group: &[PossDupe]) -> Result<()> {
+        if self.count_only {
+            return Ok(());
+        }
+
+        if self.output_dir.is_some() {
+            return self.write_group_file(group);
+        }
+
+        if self.print_redundant {
+            return self.write_redundant(group);
+        }
+
+        if self.group && self.groups_written > 0 {
+            writeln!(self.writer)?;
+        }
+
+        if !self.roots.is_empty() {
+            let line = roots_line(&crate::roots::group_labels(group, &self.roots));
+
+            match &self.palette {
+                Some(palette) => writeln!(self.writer, "{}", palette.header.paint(line))?,
+                None => writeln!(self.writer, "{}", line)?,
+            }
+        }
+
+        for (index, duplicate) in group.iter().enumerate() {
+            if self.mark_keep {
+                self.write_keep_marker(index)?;
+            }
+
+            if self.group_id {
+                write!(self.writer, "{}{}", self.groups_written + 1, self.field_sep)?;
+            }
+
+            // Files grouped by size alone have no digest, so their size is shown instead.
+            let digest = match self.size_only {
+                true => duplicate.file_len.to_string(),
+                false => duplicate.digest_hex(),
+            };
+
+            self.write_duplicate(duplicate, digest, true)?;
+        }
+
+        if self.stream {
+            self.writer.flush()?;
+        }
+
+        self.groups_written += 1;
+        Ok(())
+    }
+
+    // Take one group of duplicates to write, holding it back with `--report` until
+    // every group is known, so they can be sorted.
+    pub fn take_group(&mut self, group: Vec<PossDupe>) -> Result<()> {
+        match &mut self.report {
+            Some(report) => {
+                report.push(group);
+                Ok(())
+            }
+            None => self.write_group(&group),
+        }
+    }
+
+    // Write one cluster of near duplicates, once every group of duplicates has been
+    // written. Clusters follow a header of their own and are always separated by a
+    // blank line, and each file's size is shown in place of a digest.
+    pub fn write_near_group(&mut self, group: &[PossDupe]) -> Result<()> {
+        self.write_report()?;
+
+        if self.near_groups_written == 0 {
+            if self.groups_written > 0 {
+                writeln!(self.writer)?;
+            }
+
+            match &self.palette {
+                Some(palette) => writeln!(
+                    self.writer,
+                    "{}",
+                    palette.header.paint(self.near_header.as_str())
+                )?,
+                None => writeln!(self.writer, "{}", self.near_header)?,
+            }
+        } else {
+            writeln!(self.writer)?;
+        }
+
+        // The size is already shown in place of the digest.
+        for near in group {
+            self.write_duplicate(near, near.file_len.to_string(), false)?;
+        }
+
+        if self.stream {
+            self.writer.flush()?;
+        }
+
+        self.near_groups_written += 1;
+        Ok(())
+    }
+
+    // Write anything which comes after all groups have been written.
+    pub fn finish(&mut self, stats: &Stats) -> Result<()> {
+        self.write_report()?;
+
+        if self.count_only {
+            writeln!(self.writer, "{}", stats.summary())?;
+        }
+
+        Ok(())
+    }
+
+    // Write the groups held back for `--report`, if they haven't been yet, each
+    // under its header and separated by a blank line.
+    fn write_report(&mut self) -> Result<()> {
+        let mut groups = match self.report.take() {
+            Some(groups) => groups,
+            None => return Ok(()),
+        };
+
+        sort_report(&mut groups);
+
+        for group in groups {
+            if self.groups_written > 0 {
+                writeln!(self.writer)?;
+            }
+
+            let mut lines = vec![report_header(&group)?];
+
+            if !self.roots.is_empty() {
+                lines.push(roots_line(&crate::roots::group_labels(&group, &self.roots)));
+            }
+
+            for line in lines {
+                match &self.palette {
+                    Some(palette) => writeln!(self.writer, "{}", palette.header.paint(line))?,
+                    None => writeln!(self.writer, "{}", line)?,
+                }
+            }
+
+            for duplicate in group.iter() {
+                self.writer
+                    .write_all(&crate::platform::path_bytes(&duplicate.path))?;
+                writeln!(self.writer)?;
+            }
+
+            self.groups_written += 1;
+        }
+
+        Ok(())
+    }
+
+    // Write the paths of one group to its own file in the output directory.
+    fn write_group_file(&mut self, group: &[PossDupe]) -> Result<()> {
+        let (dir, first) = match (&self.output_dir, group.first()) {
+            (Some(dir), Some(first)) => (dir, first),
+            _ => return Ok(()),
+        };
+
+        let name = group_file_name(&first.digest_hex(), &mut self.group_files);
+        let path = dir.join(name);
+        let mut writer = crate::cli::file_writer(&path, self.output_mode)?;
+
+        for duplicate in group {
+            writer.write_all(&crate::platform::path_bytes(&duplicate.path))?;
+            writeln!(writer)?;
+        }
+
+        writer.flush()?;
+        self.groups_written += 1;
+        Ok(())
+    }
+
+    // Write the paths of every file in a group but the one to keep, which is listed
+    // first, with nothing else, so they can be passed straight to another command.
+    fn write_redundant(&mut self, group: &[PossDupe]) -> Result<()> {
+        for duplicate in redundant(group) {
+            self.writer
+                .write_all(&crate::platform::path_bytes(&duplicate.path))?;
+            self.writer.write_all(&[self.terminator])?;
+        }
+
+        if self.stream {
+            self.writer.flush()?;
+        }
+
+        self.groups_written += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_keep_marker(&mut self, index: usize) -> Result<()> {
+        let marker = keep_marker(index);
+
+        match &self.palette {
+            Some(palette) => write!(self.writer, "{}", palette.keep.paint(marker))?,
+            None => write!(self.writer, "{}", marker)?,
+        }
+
+        Ok(())
+    }
+
+    // Write one file's line: its digest, the columns chosen on the command line
+    // unless `columns` is false, and its path.
+    fn write_duplicate(
+        &mut self,
+        duplicate: &PossDupe,
+        digest: String,
+        columns: bool,
+    ) -> Result<()> {
+        let size = duplicate.file_len.to_string();
+        let bytes_read = duplicate.bytes_read.to_string();
+
+        let (digest, size, bytes_read) = match &self.palette {
+            Some(palette) => (
+                palette.digest.paint(digest).to_string(),
+                palette.size.paint(size).to_string(),
+                palette.size.paint(bytes_read).to_string(),
+            ),
+            None => (digest, size, bytes_read),
+        };
+
+        let sep = &self.field_sep;
+        write!(self.writer, "{}{}", digest, sep)?;
+
+        if columns && self.show_size {
+            write!(self.writer, "{}{}", size, sep)?;
+        }
+
+        // How much of the file was read before it was known to be a duplicate.
+        if columns && self.show_bytes_read {
+            write!(self.writer, "{}{}", bytes_read, sep)?;
+        }
+
+        if columns && self.show_file_id {
+            write!(self.writer, "{}{}", file_id(duplicate), sep)?;
+        }
+
+        self.writer
+            .write_all(&crate::platform::path_bytes(&duplicate.path))?;
+        writeln!(self.writer)?;
+
+        Ok(())
+    }
+}
+
+// Writes groups of duplicates on a dedicated thread, so formatting and writing
+// output overlaps with reading files rather than holding up the next round.
+pub struct OutputThread {
+    sender: Option<SyncSender<Vec<PossDupe>>>,
+    thread: Option<JoinHandle<Result<Output>>>,
+}
+
+impl OutputThread {
+    pub fn spawn(mut output: Output) -> OutputThread {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<PossDupe>>(OUTPUT_QUEUE_LEN);
+        let thread = thread::spawn(move || {
+            for group in receiver {
+                output.take_group(group)?;
+            }
+
+            Ok(output)
+        });
+
+        OutputThread {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    // Queue a group to be written. If writing has failed, the thread has stopped
+    // receiving, so its error is returned instead.
+    pub fn write_group(&mut self, group: Vec<PossDupe>) -> Result<()> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(group).is_ok(),
+            None => false,
+        };
+
+        match sent {
+            true => Ok(()),
+            false => match self.wait() {
+                Ok(_) => Err(anyhow!("output thread stopped unexpectedly")),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    // Wait for every queued group to be written, returning the output so anything
+    // which follows the groups can be written.
+    pub fn join(mut self) -> Result<Output> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<Output> {
+        self.sender = None;
+
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow!("output thread panicked"))?,
+            None => Err(anyhow!("output thread already stopped")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::possdupe::ArchiveMember;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // A writer whose contents can still be read once it's been handed to an
+    // output, or which fails every write.
+    #[derive(Clone)]
+    struct SharedWriter(Option<Arc<Mutex<Vec<u8>>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match &self.0 {
+                Some(data) => data.lock().unwrap().write(buf),
+                None => Err(io::Error::other("disk full")),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn plain_output(writer: SharedWriter) -> Output {
+        Output {
+            writer: BufWriter::with_capacity(1, Box::new(writer)),
+            show_size: false,
+            show_bytes_read: false,
+            show_file_id: false,
+            group_id: false,
+            field_sep: String::from("  "),
+            group: false,
+            mark_keep: false,
+            count_only: false,
+            size_only: false,
+            print_redundant: false,
+            report: None,
+            terminator: b'\n',
+            stream: false,
+            palette: None,
+            groups_written: 0,
+            near_groups_written: 0,
+            near_header: String::from("# near"),
+            output_dir: None,
+            output_mode: None,
+            group_files: HashSet::new(),
+            roots: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn output_thread_writes_groups_in_order() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut thread = OutputThread::spawn(plain_output(SharedWriter(Some(data.clone()))));
+
+        thread
+            .write_group(vec![PossDupe::new("a", 1), PossDupe::new("b", 1)])
+            .unwrap();
+        thread.write_group(vec![PossDupe::new("c", 2)]).unwrap();
+        thread.join().unwrap().flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        let paths: Vec<_> = text
+            .lines()
+            .map(|line| line.rsplit("  ").next().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn output_thread_returns_write_errors() {
+        let mut thread = OutputThread::spawn(plain_output(SharedWriter(None)));
+
+        // The first group may be queued before the thread fails to write it, but
+        // the error is returned by a later write or when joining.
+        let result = (0..OUTPUT_QUEUE_LEN + 2)
+            .map(|_| thread.write_group(vec![PossDupe::new("a", 1)]))
+            .find(|r| r.is_err())
+            .unwrap_or_else(|| thread.join().map(|_| ()));
+
+        assert_eq!(result.unwrap_err().to_string(), "disk full");
+    }
+
+    #[test]
+    fn keep_markers() {
+        assert_eq!(keep_marker(0), "* ");
+        assert_eq!(keep_marker(1), "  ");
+        assert_eq!(keep_marker(2), "  ");
+    }
+
+    #[test]
+    fn redundant_files_leave_out_the_first() {
+        let group = [
+            PossDupe::new("keep", 1),
+            PossDupe::new("a", 1),
+            PossDupe::new("b", 1),
+        ];
+        let paths: Vec<_> = redundant(&group).map(|pd| pd.path.clone()).collect();
+
+        assert_eq!(paths, vec![PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(redundant(&group[..1]).count(), 0);
+        assert_eq!(redundant(&[]).count(), 0);
+    }
+
+    #[test]
+    fn redundant_files_are_only_files_on_disk() {
+        let member = || {
+            let mut pd = PossDupe::new("a.tar/member", 1);
+            pd.archive = Some(Box::new(ArchiveMember {
+                archive: PathBuf::from("a.tar"),
+                offset: 512,
+            }));
+            pd
+        };
+        let link = || {
+            let mut pd = PossDupe::new("link", 1);
+            pd.link_target = Some(Path::new("target").into());
+            pd
+        };
+
+        let group = [
+            PossDupe::new("keep", 1),
+            member(),
+            link(),
+            PossDupe::new("b", 1),
+        ];
+        let paths: Vec<_> = redundant(&group).map(|pd| pd.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("b")]);
+
+        // Only the copy in the archive would be left.
+        let group = [member(), PossDupe::new("a", 1)];
+        assert_eq!(redundant(&group).count(), 0);
+
+        let group = [link(), PossDupe::new("a", 1)];
+        assert_eq!(redundant(&group).count(), 0);
+    }
+
+    #[test]
+    fn output_thread_writes_redundant_paths() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut output = plain_output(SharedWriter(Some(data.clone())));
+        output.print_redundant = true;
+        output.terminator = b'\0';
+
+        let mut thread = OutputThread::spawn(output);
+        thread
+            .write_group(vec![PossDupe::new("keep", 1), PossDupe::new("a b", 1)])
+            .unwrap();
+        thread
+            .write_group(vec![PossDupe::new("keep2", 2), PossDupe::new("c", 2)])
+            .unwrap();
+        thread.join().unwrap().flush().unwrap();
+
+        assert_eq!(*data.lock().unwrap(), b"a b\0c\0".to_vec());
+    }
+
+    #[test]
+    fn near_groups_follow_duplicates() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut thread = OutputThread::spawn(plain_output(SharedWriter(Some(data.clone()))));
+        thread
+            .write_group(vec![PossDupe::new("a", 1), PossDupe::new("b", 1)])
+            .unwrap();
+
+        let mut output = thread.join().unwrap();
+        output
+            .write_near_group(&[PossDupe::new("c", 100), PossDupe::new("d", 104)])
+            .unwrap();
+        output
+            .write_near_group(&[PossDupe::new("e", 200), PossDupe::new("f", 201)])
+            .unwrap();
+        output.flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        let digest = PossDupe::new("a", 1).digest_hex();
+        assert_eq!(
+            text,
+            format!(
+                "{0}  a\n{0}  b\n\n# near\n100  c\n104  d\n\n200  e\n201  f\n",
+                digest
+            )
+        );
+    }
+
+    #[test]
+    fn reports_list_the_largest_files_first() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut output = plain_output(SharedWriter(Some(data.clone())));
+        output.report = Some(Vec::new());
+
+        let mut thread = OutputThread::spawn(output);
+        thread
+            .write_group(vec![PossDupe::new("a", 10), PossDupe::new("b", 10)])
+            .unwrap();
+        thread
+            .write_group(vec![
+                PossDupe::new("c", 3 << 20),
+                PossDupe::new("d", 3 << 20),
+                PossDupe::new("e", 3 << 20),
+            ])
+            .unwrap();
+
+        let mut output = thread.join().unwrap();
+        output
+            .write_near_group(&[PossDupe::new("f", 100), PossDupe::new("g", 104)])
+            .unwrap();
+        output.finish(&Stats::new()).unwrap();
+        output.flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "### 3 MiB × 3 copies (reclaim 6 MiB)\nc\nd\ne\n\n\
+             ### 10 B × 2 copies (reclaim 10 B)\na\nb\n\n\
+             # near\n100  f\n104  g\n"
+        );
+    }
+
+    #[test]
+    fn bytes_read_follow_the_size() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut output = plain_output(SharedWriter(Some(data.clone())));
+        output.show_size = true;
+        output.show_bytes_read = true;
+
+        let mut partial = PossDupe::new("a", 8192);
+        partial.bytes_read = 4096;
+        output.write_group(&[partial]).unwrap();
+        output.show_size = false;
+        output.write_group(&[PossDupe::new("b", 10)]).unwrap();
+        output.flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        let digest = PossDupe::new("a", 1).digest_hex();
+        assert_eq!(text, format!("{0}  8192  4096  a\n{0}  0  b\n", digest));
+    }
+
+    #[test]
+    fn file_ids_follow_bytes_read() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut output = plain_output(SharedWriter(Some(data.clone())));
+        output.show_bytes_read = true;
+        output.show_file_id = true;
+
+        let mut linked = PossDupe::new("a", 4);
+        linked.file_id = Some((2049, 131));
+        output
+            .write_group(&[linked, PossDupe::new("member", 4)])
+            .unwrap();
+        output.flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        let digest = PossDupe::new("a", 1).digest_hex();
+        assert_eq!(
+            text,
+            format!("{0}  0  2049:131  a\n{0}  0  -  member\n", digest)
+        );
+    }
+
+    #[test]
+    fn colored_groups_keep_blank_separators() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut output = plain_output(SharedWriter(Some(data.clone())));
+        output.group = true;
+        output.palette = Some(Palette::new());
+
+        output.write_group(&[PossDupe::new("a", 1)]).unwrap();
+        output.write_group(&[PossDupe::new("b", 1)]).unwrap();
+        output.flush().unwrap();
+
+        let text = String::from_utf8(data.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("  a"));
+        assert_eq!(lines[1], "");
+        assert!(lines[2].ends_with("  b"));
+    }
+
+    #[test]
+    fn roots_lines() {
+        assert_eq!(roots_line(&["backup", "live"]), "# roots: backup, live");
+        assert_eq!(roots_line(&[]), "# roots: none");
+    }
+
+    #[test]
+    fn group_file_names_are_unique() {
+        let mut used = HashSet::new();
+
+        assert_eq!(group_file_name("ab", &mut used), "ab.txt");
+        assert_eq!(group_file_name("cd", &mut used), "cd.txt");
+        assert_eq!(group_file_name("ab", &mut used), "ab-2.txt");
+        assert_eq!(group_file_name("ab", &mut used), "ab-3.txt");
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Platform differences in file metadata and path handling live here so the rest
+// of `fddup` doesn't need to care which OS it's running on.
+
+use anyhow::Result;
+use std::borrow::Cow;
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+
+// Return true if the metadata (from `symlink_metadata`) describes a link which
+// `fddup` shouldn't read through. On Windows, this includes junctions and any
+// other directory reparse point, which std doesn't always report as symlinks.
+// Reparse points on regular files which aren't symlinks (e.g., deduplicated or
+// cloud placeholder files) are read like any other file.
+pub fn is_link(attr: &Metadata) -> bool {
+    attr.file_type().is_symlink() || is_directory_reparse_point(attr)
+}
+
+#[cfg(windows)]
+fn is_directory_reparse_point(attr: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+    let attributes = attr.file_attributes();
+    attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 && attributes & FILE_ATTRIBUTE_DIRECTORY != 0
+}
+
+#[cfg(not(windows))]
+fn is_directory_reparse_point(_attr: &Metadata) -> bool {
+    false
+}
+
+// Return true if a file or directory is hidden: on Windows, if it has the hidden
+// attribute, and elsewhere, if its name starts with `.`.
+#[cfg(windows)]
+pub fn is_hidden(_path: &Path, attr: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    attr.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+#[cfg(not(windows))]
+pub fn is_hidden(path: &Path, _attr: &Metadata) -> bool {
+    has_hidden_name(path)
+}
+
+// Return true if a file's name starts with `.`, which hides it except on Windows.
+pub fn has_hidden_name(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+// Paths this long (in UTF-16 code units) or longer exceed Windows' MAX_PATH limit
+// unless given in extended-length form.
+#[cfg(any(windows, test))]
+const MAX_PATH: usize = 260;
+
+// Rewrite a long absolute Windows path in extended-length form, `\\?\C:\...` or
+// `\\?\UNC\server\share\...`, which isn't subject to MAX_PATH. Windows doesn't
+// normalize extended-length paths, so `/` becomes `\` and `.` and `..` components
+// are resolved here. Returns None for short, relative, or already extended paths.
+#[cfg(any(windows, test))]
+fn extended_length_path(path: &str) -> Option<String> {
+    if path.encode_utf16().count() < MAX_PATH || path.starts_with(r"\\?\") {
+        return None;
+    }
+
+    let path = path.replace('/', "\\");
+    let bytes = path.as_bytes();
+
+    // `..` never goes above a drive's root or a UNC path's server and share.
+    let (mut result, rest, fixed) = if let Some(unc) = path.strip_prefix(r"\\") {
+        if unc.starts_with(r".\") {
+            return None;
+        }
+
+        (String::from(r"\\?\UNC"), unc, 2)
+    } else if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1..3] == *b":\\" {
+        (format!(r"\\?\{}", &path[..2]), &path[3..], 0)
+    } else {
+        return None;
+    };
+
+    let mut components: Vec<&str> = Vec::new();
+
+    for component in rest.split('\\') {
+        match component {
+            "" | "." => {}
+            ".." if components.len() > fixed => {
+                components.pop();
+            }
+            ".." => {}
+            _ => components.push(component),
+        }
+    }
+
+    for component in components {
+        result.push('\\');
+        result.push_str(component);
+    }
+
+    Some(result)
+}
+
+// Return the path to use when accessing a file, in extended-length form if it's
+// too long for Windows to accept otherwise.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    match path.to_str().and_then(extended_length_path) {
+        Some(extended) => Cow::Owned(PathBuf::from(extended)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+// Return the ID of the device (filesystem) holding a file, if the platform
+// reports one.
+#[cfg(unix)]
+pub fn device_id(attr: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(attr.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device_id(_attr: &Metadata) -> Option<u64> {
+    None
+}
+
+// Return an ID identifying the physical file, the same for every path which
+// refers to it, if the platform reports one.
+#[cfg(unix)]
+pub fn file_id(attr: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((attr.dev(), attr.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_attr: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Return the space allocated on disk for a file, if the platform reports it.
+// Unix reports allocation in 512-byte blocks regardless of the filesystem's
+// block size.
+#[cfg(unix)]
+pub fn allocated_len(attr: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(attr.blocks().saturating_mul(512))
+}
+
+#[cfg(not(unix))]
+pub fn allocated_len(_attr: &Metadata) -> Option<u64> {
+    None
+}
+
+// Create (or truncate) a file for writing. On Unix, a new file is created with
+// permissions `mode`, less the umask, when given; otherwise, and elsewhere, the
+// usual permissions apply. An existing file keeps its permissions.
+#[cfg(unix)]
+pub fn create_file(path: &Path, mode: Option<u32>) -> std::io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+
+    options.open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create_file(path: &Path, _mode: Option<u32>) -> std::io::Result<File> {
+    File::create(path)
+}
+
+// Open a file for appending, creating it if need be with permissions as for
+// `create_file`.
+#[cfg(unix)]
+pub fn append_file(path: &Path, mode: Option<u32>) -> std::io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+
+    options.open(path)
+}
+
+#[cfg(not(unix))]
+pub fn append_file(path: &Path, _mode: Option<u32>) -> std::io::Result<File> {
+    std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+// Return a path as it should be displayed in output. Paths read from input may
+// use either separator on Windows while paths found by walking directories use
+// `\`, so Windows output is normalized to `\` throughout.
+#[cfg(windows)]
+pub fn display_path(path: &Path) -> Cow<'_, str> {
+    Cow::Owned(path.to_string_lossy().replace('/', "\\"))
+}
+
+#[cfg(not(windows))]
+pub fn display_path(path: &Path) -> Cow<'_, str> {
+    path.to_string_lossy()
+}
+
+// Return a path as the bytes written to output. On Unix, paths are arbitrary
+// bytes and are written as is, so names which aren't valid UTF-8 are reproduced
+// faithfully; elsewhere, they're written as UTF-8.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match display_path(path) {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+// Return the path named by a line of input. On Unix, any bytes are accepted;
+// elsewhere, the line must be valid UTF-8.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use anyhow::Context;
+
+    let filename =
+        String::from_utf8(bytes).with_context(|| "an input line isn't a valid unicode string")?;
+
+    Ok(PathBuf::from(filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_files_with_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+
+        create_file(&dir.join("private"), Some(0o600)).unwrap();
+        let mode = std::fs::metadata(dir.join("private"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn names_starting_with_a_dot_are_hidden() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join(".cache")).unwrap();
+        std::fs::write(dir.join(".profile"), "").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let hidden = |name: &str| {
+            let path = dir.join(name);
+            is_hidden(&path, &std::fs::symlink_metadata(&path).unwrap())
+        };
+
+        assert!(hidden(".profile"));
+        assert!(hidden(".cache"));
+        assert!(!hidden("notes.txt"));
+        assert!(!is_hidden(
+            Path::new("."),
+            &std::fs::symlink_metadata(".").unwrap()
+        ));
+    }
+
+    #[test]
+    fn appends_to_files() {
+        use std::io::Write;
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.join("out");
+
+        append_file(&path, None).unwrap().write_all(b"a\n").unwrap();
+        append_file(&path, None).unwrap().write_all(b"b\n").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"a\nb\n");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn display_path_uses_backslashes() {
+        assert_eq!(display_path(Path::new("C:/a/b\\c.txt")), "C:\\a\\b\\c.txt");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn display_path_is_unchanged() {
+        assert_eq!(display_path(Path::new("/a/b\\c.txt")), "/a/b\\c.txt");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn junctions_are_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let target = root.join("target");
+        let junction = root.join("junction");
+        std::fs::create_dir_all(&target).unwrap();
+
+        let status = std::process::Command::new("cmd")
+            .args(&["/C", "mklink", "/J"])
+            .arg(&junction)
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        assert!(is_link(&std::fs::symlink_metadata(&junction).unwrap()));
+        assert!(!is_link(&std::fs::symlink_metadata(&target).unwrap()));
+
+        std::fs::remove_dir(&junction).unwrap();
+    }
+
+    #[test]
+    fn long_paths_use_extended_length_form() {
+        let long = "d".repeat(MAX_PATH);
+
+        assert_eq!(
+            extended_length_path(&format!(r"C:\a\.\b\..\{}", long)),
+            Some(format!(r"\\?\C:\a\{}", long))
+        );
+        assert_eq!(
+            extended_length_path(&format!("C:/a/{}", long)),
+            Some(format!(r"\\?\C:\a\{}", long))
+        );
+        assert_eq!(
+            extended_length_path(&format!(r"\\server\share\..\..\{}", long)),
+            Some(format!(r"\\?\UNC\server\share\{}", long))
+        );
+
+        // Already extended, relative, and short paths are left alone.
+        assert_eq!(extended_length_path(&format!(r"\\?\C:\{}", long)), None);
+        assert_eq!(extended_length_path(&format!(r"a\{}", long)), None);
+        assert_eq!(extended_length_path(&format!(r"C:{}", long)), None);
+        assert_eq!(extended_length_path(r"C:\a\b.txt"), None);
+
+        // A multi-byte character where a drive's colon would be isn't split.
+        assert_eq!(extended_length_path(&format!("a€{}", long)), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn opens_files_beyond_max_path() {
+        let root = tempfile::tempdir().unwrap();
+        let mut dir = root.path().to_path_buf();
+
+        while dir.as_os_str().len() < MAX_PATH {
+            dir.push("d".repeat(50));
+        }
+
+        std::fs::create_dir_all(long_path(&dir)).unwrap();
+        let file = dir.join("a.txt");
+        std::fs::write(long_path(&file), "contents").unwrap();
+
+        let mut pd = crate::possdupe::PossDupe::new(&file, 8);
+        pd.open().unwrap();
+
+        // Removing the directory needs the extended-length form too.
+        std::fs::remove_dir_all(long_path(root.path())).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_kept_as_bytes() {
+        let path = path_from_bytes(b"/a/\xffb.txt".to_vec()).unwrap();
+
+        assert_eq!(path_bytes(&path), &b"/a/\xffb.txt"[..]);
+        assert_eq!(display_path(&path), "/a/\u{fffd}b.txt");
+    }
+}
@@ -22,33 +22,126 @@
 use crate::algo::GetKey;
 
 use anyhow::{Context, Result};
-use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::path::PathBuf;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{symlink_metadata, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-// Key used for sorting possible duplicate files consisting of the file's length
-// and its hash (digest) of data read thus far.
+// Where the contents of a file stored in an archive are found: the archive's path
+// and the offset within it at which the file's contents start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveMember {
+    pub archive: PathBuf,
+    pub offset: u64,
+}
+
+// Number of bytes in a full digest
+pub const DIGEST_LEN: usize = 32;
+
+// Hashed before a link's target path with `--symlink-mode target`
+const LINK_TARGET_TAG: &[u8] = b"symlink\0";
+
+// A digest computed incrementally over a file's contents. SHA-256 is used unless
+// another is given with `PossDupe::with_hasher`. Digests longer than DIGEST_LEN
+// bytes are truncated to fit in a Key.
+pub trait Hasher: Send + fmt::Debug {
+    // Add more of the file's contents to the digest.
+    fn update(&mut self, data: &[u8]);
+
+    // Number of bytes in the digest.
+    fn len(&self) -> usize;
+
+    // Write the first `out.len()` bytes of the digest of all data added so far
+    // to `out`, which is no longer than the digest; more data may be added
+    // afterwards. This is done after every read, so it shouldn't allocate.
+    fn snapshot(&self, out: &mut [u8]);
+}
+
+macro_rules! sha2_hasher {
+    ($($digest:ty),*) => {$(
+        impl Hasher for $digest {
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(self, data);
+            }
+
+            fn len(&self) -> usize {
+                <$digest as Digest>::output_size()
+            }
+
+            fn snapshot(&self, out: &mut [u8]) {
+                out.copy_from_slice(&self.clone().finalize()[..out.len()]);
+            }
+        }
+    )*};
+}
+
+sha2_hasher!(Sha224, Sha256, Sha384, Sha512);
+
+// Key used for sorting possible duplicate files consisting of the file's length,
+// optionally its name, and its hash (digest) of data read thus far. Files of the
+// same length are read in lockstep, so equal keys mean equal contents up to the
+// bytes read so far; see `find_work`.
 #[derive(Debug, Clone)]
 pub struct Key {
     /// Length (in bytes) of this file
     pub len: u64,
 
-    /// Digest computed thus far; starts out as all 0s
-    pub digest_snapshot: [u8; 32],
+    /// File name, with `--same-name`, so only files with the same name group.
+    /// Shared so cloning a key, as `find_work` does, doesn't copy the name.
+    pub name: Option<Arc<OsStr>>,
+
+    /// Digest computed thus far; starts out as all 0s. When digests are truncated,
+    /// only a prefix is filled in and the remainder stays 0.
+    pub digest_snapshot: [u8; DIGEST_LEN],
 }
 
 impl Key {
     pub fn new(len: u64) -> Key {
         Key {
             len,
-            digest_snapshot: [0; 32],
+            name: None,
+            digest_snapshot: [0; DIGEST_LEN],
         }
     }
+
+    // The part of the key known before anything is read. Files can only be
+    // duplicates if this matches, so files which share it with no other file are
+    // unique without being read.
+    pub fn bucket(&self) -> (u64, Option<&OsStr>) {
+        (self.len, self.name.as_deref())
+    }
 }
 
 impl PartialEq for Key {
     fn eq(&self, other: &Self) -> bool {
-        self.len == other.len && self.digest_snapshot == other.digest_snapshot
+        self.len == other.len
+            && self.name == other.name
+            && self.digest_snapshot == other.digest_snapshot
+    }
+}
+
+// When sampling, only `blocks` evenly spaced blocks of `block_size` bytes are read
+// from a file rather than its entire contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampling {
+    pub blocks: u64,
+    pub block_size: u64,
+}
+
+impl Sampling {
+    // Offset of the given block within a file of `file_len` bytes. The first block
+    // starts at the beginning of the file and the last block ends at its end.
+    fn block_offset(&self, block: u64, file_len: u64) -> u64 {
+        if self.blocks <= 1 {
+            return 0;
+        }
+
+        let span = file_len - self.block_size;
+        (u128::from(span) * u128::from(block) / u128::from(self.blocks - 1)) as u64
     }
 }
 
@@ -60,10 +153,50 @@ pub struct PossDupe {
     pub file_len: u64,
     pub bytes_read: u64,
 
+    // Number of bytes at the start of the file which are ignored when comparing
+    pub header_len: u64,
+
+    // Number of digest bytes kept in the key and compared when grouping
+    pub digest_len: u8,
+
+    // Set if only a sample of this file's contents is read
+    pub sampling: Option<Sampling>,
+
+    // Set if the file's normalized text was hashed rather than its contents
+    pub normalized: bool,
+
+    // Set if the file's decompressed contents were hashed rather than its contents
+    pub decompressed: bool,
+
+    // Set if this is a link whose target path is compared rather than contents.
+    // This and `archive` are rarely set, so they're boxed to keep every other
+    // file's PossDupe small.
+    pub link_target: Option<Box<Path>>,
+
+    // Set if this is a member of an archive, whose `path` is only for display
+    pub archive: Option<Box<ArchiveMember>>,
+
+    // Index of the `--root` containing the file, if any
+    pub root: Option<usize>,
+
+    // Modification time, if the platform reports one
+    pub mtime: Option<SystemTime>,
+
+    // Space allocated on disk, if being accounted for and the platform reports it;
+    // less than `file_len` for sparse files
+    pub allocated_len: Option<u64>,
+
+    // Device and inode of the file, if the platform reports them
+    pub file_id: Option<(u64, u64)>,
+
+    // Set if the file's size came from a size index rather than the file itself,
+    // so it's checked once the file is opened
+    pub indexed: bool,
+
     // File will be lazily opened if and when we need to read from it
     pub file: Option<File>,
 
-    digest: Sha256,
+    digest: Box<dyn Hasher>,
 }
 
 impl GetKey<Key> for PossDupe {
@@ -77,35 +210,459 @@ impl GetKey<Key> for PossDupe {
 }
 
 impl PossDupe {
-    pub fn new(path: &str, file_len: u64) -> PossDupe {
+    pub fn new<P: Into<PathBuf>>(path: P, file_len: u64) -> PossDupe {
+        PossDupe::with_hasher(path, file_len, Box::new(Sha256::new()))
+    }
+
+    // Create a possible duplicate whose digest is computed by the given hasher
+    // rather than SHA-256.
+    pub fn with_hasher<P: Into<PathBuf>>(
+        path: P,
+        file_len: u64,
+        digest: Box<dyn Hasher>,
+    ) -> PossDupe {
         PossDupe {
-            path: PathBuf::from(path),
+            path: path.into(),
             key: Key::new(file_len),
             file_len,
             bytes_read: 0,
+            header_len: 0,
+            digest_len: digest.len().min(DIGEST_LEN) as u8,
+            sampling: None,
+            normalized: false,
+            decompressed: false,
+            link_target: None,
+            archive: None,
+            root: None,
+            mtime: None,
+            allocated_len: None,
+            file_id: None,
+            indexed: false,
             file: None,
-            digest: Sha256::new(),
+            digest,
         }
     }
 
+    // Open the file, positioned past any header being ignored and anything
+    // already read, so a file which was closed part way through carries on where
+    // it left off.
     pub fn open(&mut self) -> Result<()> {
         if self.file.is_none() {
-            self.file = Some(File::open(&self.path).with_context(|| {
-                format!("couldn't open {} for reading", self.path.to_str().unwrap())
-            })?);
+            self.file = Some(self.open_at(self.content_offset() + self.bytes_read)?);
+        }
+
+        Ok(())
+    }
+
+    // Open the file holding this file's contents, which is the archive for archive
+    // members, positioned `offset` bytes into this file's contents.
+    pub fn open_at(&self, offset: u64) -> Result<File> {
+        let source = match &self.archive {
+            Some(member) => member.archive.as_path(),
+            None => self.path.as_path(),
+        };
+
+        if self.indexed {
+            self.check_indexed()?;
+        }
+
+        let mut file = File::open(crate::platform::long_path(source))
+            .with_context(|| format!("couldn't open {} for reading", self.path.display()))?;
+
+        if self.base_offset() + offset > 0 {
+            file.seek(SeekFrom::Start(self.base_offset() + offset))
+                .with_context(|| format!("couldn't seek in {}", self.path.display()))?;
+        }
+
+        Ok(file)
+    }
+
+    // Fail with an invalid data error if a file whose size came from a size index
+    // isn't as the index said: a regular file of that size, with the same file id if
+    // the index had one. This is checked before opening the file, so a link isn't
+    // followed and a named pipe can't block, just as when files are stat'ed.
+    fn check_indexed(&self) -> Result<()> {
+        let attr = symlink_metadata(crate::platform::long_path(&self.path))
+            .with_context(|| format!("couldn't read attributes of {}", self.path.display()))?;
+        let file_id = crate::platform::file_id(&attr);
+
+        if !attr.file_type().is_file()
+            || attr.len() != self.file_len
+            || (self.file_id.is_some() && file_id.is_some() && file_id != self.file_id)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has changed since the size index was written",
+                    self.path.display()
+                ),
+            )
+            .into());
         }
 
         Ok(())
     }
 
+    // Offset at which this file's contents start within the file holding them; 0
+    // except for archive members.
+    pub fn base_offset(&self) -> u64 {
+        self.archive.as_ref().map_or(0, |member| member.offset)
+    }
+
+    // Offset at which the compared contents start, past any header being ignored.
+    // A file shorter than the header has no contents to compare.
+    pub fn content_offset(&self) -> u64 {
+        self.header_len.min(self.key.len)
+    }
+
+    // Length of the contents compared, excluding any header being ignored.
+    pub fn content_len(&self) -> u64 {
+        self.key.len - self.content_offset()
+    }
+
+    // Sample this file rather than reading all of it, unless the samples would
+    // cover the whole file anyway.
+    pub fn sample(&mut self, sampling: Sampling) {
+        if sampling.blocks.saturating_mul(sampling.block_size) < self.content_len() {
+            self.sampling = Some(sampling);
+        }
+    }
+
+    // Read the entire file now and hash its normalized text. The key's length
+    // becomes the normalized length so files group by their normalized contents,
+    // and nothing remains to be read afterwards.
+    pub fn normalize_text(&mut self) -> Result<()> {
+        let mut data = Vec::new();
+        self.open_at(0)?
+            .take(self.file_len)
+            .read_to_end(&mut data)
+            .with_context(|| format!("couldn't read {}", self.path.display()))?;
+        let header_len = (self.content_offset() as usize).min(data.len());
+        let normalized = crate::text::normalize(&data[header_len..]);
+
+        // The normalized text is compared in its entirety; the header is already gone.
+        self.key.len = normalized.len() as u64;
+        self.header_len = 0;
+        self.update_digest(&normalized);
+        self.bytes_read = data.len() as u64;
+        self.normalized = true;
+
+        Ok(())
+    }
+
+    // Hash the file's decompressed contents, read from `reader` to the end, rather
+    // than its contents. They're streamed rather than held in memory. As with
+    // normalized text, the key's length becomes the decompressed length so files
+    // group by their decompressed contents, and nothing remains to be read; all
+    // of the decompressed length counts as read.
+    pub fn decompress<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        let mut len = 0;
+
+        loop {
+            let n = reader
+                .read(&mut buffer)
+                .with_context(|| format!("couldn't read {}", self.path.display()))?;
+
+            if n == 0 {
+                break;
+            }
+
+            self.digest.update(&buffer[..n]);
+            len += n as u64;
+        }
+
+        // The whole decompressed stream is compared; any header only applies to
+        // the compressed file.
+        self.key.len = len;
+        self.header_len = 0;
+        self.snapshot_digest();
+        self.bytes_read = len;
+        self.decompressed = true;
+
+        Ok(())
+    }
+
+    // Hash the target path of a link now rather than any contents. The key's length
+    // becomes the target's length, and the digest is of a tag followed by the
+    // target, so a link is never grouped with a file which merely contains the
+    // same path. Nothing remains to be read afterwards.
+    pub fn compare_link_target(&mut self, target: PathBuf) {
+        let bytes = crate::platform::path_bytes(&target).into_owned();
+
+        self.key.len = bytes.len() as u64;
+        self.file_len = self.key.len;
+        self.update_digest(LINK_TARGET_TAG);
+        self.update_digest(&bytes);
+        self.bytes_read = self.key.len;
+        self.link_target = Some(target.into_boxed_path());
+    }
+
+    // Total number of bytes which will be read from this file.
+    pub fn bytes_to_read(&self) -> u64 {
+        match self.sampling {
+            Some(s) => s.blocks * s.block_size,
+            None => self.content_len(),
+        }
+    }
+
     pub fn bytes_remaining(&self) -> u64 {
-        self.key.len.saturating_sub(self.bytes_read)
+        self.bytes_to_read().saturating_sub(self.bytes_read)
+    }
+
+    // Return the offset within the file and length of the next read, given the
+    // largest read allowed.
+    pub fn next_read(&self, read_size: u64) -> (u64, u64) {
+        let start = self.content_offset();
+
+        match self.sampling {
+            Some(s) => {
+                let block = self.bytes_read / s.block_size;
+                let within = self.bytes_read % s.block_size;
+                let offset = start + s.block_offset(block, self.content_len()) + within;
+                (offset, read_size.min(s.block_size - within))
+            }
+            None => (
+                start + self.bytes_read,
+                read_size.min(self.bytes_remaining()),
+            ),
+        }
+    }
+
+    // Read the next chunk of the file into the start of `buffer`, growing it if it's
+    // too small, and return the number of bytes read, which is 0 unless it's open.
+    pub fn read_chunk(&mut self, read_size: usize, buffer: &mut Vec<u8>) -> Result<usize> {
+        let (offset, to_read) = self.next_read(read_size as u64);
+        let to_read = to_read as usize;
+        let sampling = self.sampling.is_some();
+        let base_offset = self.base_offset();
+
+        if buffer.len() < to_read {
+            buffer.resize(to_read, 0);
+        }
+
+        let path = &self.path;
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(0),
+        };
+
+        if sampling {
+            file.seek(SeekFrom::Start(base_offset + offset))
+                .with_context(|| format!("couldn't seek in {}", path.display()))?;
+        }
+
+        file.read_exact(&mut buffer[0..to_read])
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        self.bytes_read += to_read as u64;
+
+        Ok(to_read)
     }
 
     pub fn update_digest(&mut self, buffer: &[u8]) {
-        self.digest.update(&buffer);
-        self.key
-            .digest_snapshot
-            .clone_from_slice(self.digest.clone().finalize().as_slice())
+        self.update_digest_in_blocks(buffer, buffer.len());
+    }
+
+    // Hash `buffer` in updates of at most `block_size` bytes. The digest is only
+    // snapshotted once, after the last update, since that's the costly part.
+    pub fn update_digest_in_blocks(&mut self, buffer: &[u8], block_size: usize) {
+        for block in buffer.chunks(block_size.max(1)) {
+            self.digest.update(block);
+        }
+
+        self.snapshot_digest();
+    }
+
+    // Copy the digest of everything hashed so far into the key, truncated if
+    // digests are being truncated.
+    fn snapshot_digest(&mut self) {
+        let len = usize::from(self.digest_len);
+        self.digest.snapshot(&mut self.key.digest_snapshot[..len]);
+    }
+
+    // Hex encoding of the digest, truncated if digests are being truncated.
+    pub fn digest_hex(&self) -> String {
+        hex::encode(&self.key.digest_snapshot[..usize::from(self.digest_len)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reads(pd: &mut PossDupe, read_size: u64) -> Vec<(u64, u64)> {
+        let mut result = Vec::new();
+
+        while pd.bytes_remaining() > 0 {
+            let (offset, len) = pd.next_read(read_size);
+            result.push((offset, len));
+            pd.bytes_read += len;
+        }
+
+        result
+    }
+
+    // One of these is kept per file, so growing it costs memory on enormous file
+    // sets; box anything rarely set rather than adding it inline.
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn per_file_state_stays_small() {
+        assert!(std::mem::size_of::<Key>() <= 56);
+        assert!(std::mem::size_of::<PossDupe>() <= 344);
+    }
+
+    // Sum of all bytes, to show a hasher other than SHA-256 can be plugged in.
+    #[derive(Debug, Default)]
+    struct SumHasher(u32);
+
+    impl Hasher for SumHasher {
+        fn update(&mut self, data: &[u8]) {
+            self.0 = data
+                .iter()
+                .fold(self.0, |sum, b| sum.wrapping_add(u32::from(*b)));
+        }
+
+        fn len(&self) -> usize {
+            4
+        }
+
+        fn snapshot(&self, out: &mut [u8]) {
+            out.copy_from_slice(&self.0.to_be_bytes()[..out.len()]);
+        }
+    }
+
+    #[test]
+    fn custom_hasher() {
+        let mut pd = PossDupe::with_hasher("a", 3, Box::new(SumHasher::default()));
+        pd.update_digest(b"\x01\x02");
+        pd.update_digest(b"\x03");
+
+        assert_eq!(pd.digest_len, 4);
+        assert_eq!(pd.digest_hex(), "00000006");
+        assert_eq!(pd.key.digest_snapshot[4..], [0; DIGEST_LEN - 4]);
+    }
+
+    #[test]
+    fn built_in_hashers() {
+        assert_eq!(Hasher::len(&Sha256::new()), DIGEST_LEN);
+
+        // Longer digests are truncated to fit in a key, and shorter ones leave the
+        // rest of it zeroed.
+        let mut long = PossDupe::with_hasher("a", 3, Box::new(Sha512::new()));
+        long.update_digest(b"abc");
+        assert_eq!(usize::from(long.digest_len), DIGEST_LEN);
+        assert_eq!(
+            long.digest_hex(),
+            hex::encode(&Sha512::digest(b"abc")[..DIGEST_LEN])
+        );
+
+        let mut short = PossDupe::with_hasher("a", 3, Box::new(Sha224::new()));
+        short.update_digest(b"abc");
+        assert_eq!(short.digest_hex(), hex::encode(Sha224::digest(b"abc")));
+        assert_eq!(short.key.digest_snapshot[28..], [0; DIGEST_LEN - 28]);
+    }
+
+    #[test]
+    fn digests_are_the_same_whatever_the_block_size() {
+        let mut whole = PossDupe::new("whole", 10);
+        whole.update_digest(b"0123456789");
+
+        for block_size in [0, 1, 3, 10, 64].iter() {
+            let mut blocks = PossDupe::new("blocks", 10);
+            blocks.update_digest_in_blocks(b"0123456789", *block_size);
+            assert_eq!(blocks.digest_hex(), whole.digest_hex(), "{}", block_size);
+        }
+    }
+
+    #[test]
+    fn sequential_reads() {
+        let mut pd = PossDupe::new("a", 10);
+        assert_eq!(reads(&mut pd, 4), vec![(0, 4), (4, 4), (8, 2)]);
+    }
+
+    #[test]
+    fn sampled_reads_are_evenly_spaced() {
+        let mut pd = PossDupe::new("a", 100);
+        pd.sample(Sampling {
+            blocks: 3,
+            block_size: 10,
+        });
+
+        assert_eq!(pd.bytes_to_read(), 30);
+        assert_eq!(reads(&mut pd, 10), vec![(0, 10), (45, 10), (90, 10)]);
+    }
+
+    #[test]
+    fn sampled_reads_split_blocks_larger_than_read_size() {
+        let mut pd = PossDupe::new("a", 100);
+        pd.sample(Sampling {
+            blocks: 2,
+            block_size: 10,
+        });
+
+        assert_eq!(reads(&mut pd, 6), vec![(0, 6), (6, 4), (90, 6), (96, 4)]);
+    }
+
+    #[test]
+    fn small_files_are_read_entirely() {
+        let mut pd = PossDupe::new("a", 25);
+        pd.sample(Sampling {
+            blocks: 3,
+            block_size: 10,
+        });
+
+        assert_eq!(pd.sampling, None);
+        assert_eq!(pd.bytes_to_read(), 25);
+    }
+
+    #[test]
+    fn link_targets_differ_from_contents() {
+        let mut link = PossDupe::new("link", 0);
+        link.compare_link_target(PathBuf::from("target"));
+
+        let mut file = PossDupe::new("file", 6);
+        file.update_digest(b"target");
+
+        assert_eq!(link.key.len, 6);
+        assert_eq!(link.bytes_remaining(), 0);
+        assert!(link.key != file.key);
+    }
+
+    #[test]
+    fn reads_skip_the_header() {
+        let mut pd = PossDupe::new("a", 10);
+        pd.header_len = 3;
+
+        assert_eq!(pd.bytes_to_read(), 7);
+        assert_eq!(reads(&mut pd, 4), vec![(3, 4), (7, 3)]);
+
+        let mut pd = PossDupe::new("a", 100);
+        pd.header_len = 10;
+        pd.sample(Sampling {
+            blocks: 2,
+            block_size: 10,
+        });
+
+        assert_eq!(reads(&mut pd, 10), vec![(10, 10), (90, 10)]);
+
+        // A file no longer than the header has nothing to compare.
+        let mut pd = PossDupe::new("a", 2);
+        pd.header_len = 3;
+
+        assert_eq!(pd.bytes_to_read(), 0);
+    }
+
+    #[test]
+    fn names_split_keys() {
+        let key = |name: Option<&str>| {
+            let mut key = Key::new(4);
+            key.name = name.map(|name| Arc::from(OsStr::new(name)));
+            key
+        };
+
+        assert!(key(Some("a.jpg")) == key(Some("a.jpg")));
+        assert!(key(Some("a.jpg")) != key(Some("b.jpg")));
+        assert!(key(None) != key(Some("a.jpg")));
+        assert!(key(Some("a.jpg")).bucket() < key(Some("b.jpg")).bucket());
     }
 }
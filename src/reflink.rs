@@ -0,0 +1,260 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Deduplicating files in place on copy-on-write filesystems such as Btrfs and XFS.
+// Rather than removing a duplicate, its contents are made to share storage with
+// the file kept, so every path keeps working and space is reclaimed. The kernel
+// compares both files itself before sharing anything, so a file which changed
+// after it was hashed is left alone rather than overwritten.
+
+use crate::possdupe::PossDupe;
+
+use humansize::{file_size_opts, FileSize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+// Whether deduplication is supported on this platform at all.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+// Result of deduplicating one file against another.
+#[derive(Debug, PartialEq)]
+pub enum Dedupe {
+    // The contents now share storage; this many bytes were deduplicated
+    Shared(u64),
+
+    // The contents differ, so nothing was shared
+    Differs,
+}
+
+// Counts of files deduplicated with `--reflink`, for a summary at the end.
+#[derive(Default)]
+pub struct Reflinker {
+    files_shared: usize,
+    bytes_shared: u64,
+    files_differed: usize,
+    files_failed: usize,
+}
+
+impl Reflinker {
+    // Share the contents of every file in a group with the file to keep, listed
+    // first. Failures, such as a filesystem without copy-on-write support, are
+    // reported and counted, and the remaining files are still tried. Links and
+    // archive members have no contents of their own to share.
+    pub fn dedupe_group(&mut self, group: &[PossDupe]) {
+        let keep = match group.first() {
+            Some(keep) if keep.link_target.is_none() && keep.archive.is_none() => keep,
+            _ => return,
+        };
+
+        for pd in group[1..].iter() {
+            if pd.link_target.is_some() || pd.archive.is_some() {
+                continue;
+            }
+
+            match dedupe_file(&keep.path, &pd.path, pd.file_len) {
+                Ok(Dedupe::Shared(bytes)) => {
+                    self.files_shared += 1;
+                    self.bytes_shared += bytes;
+                }
+                Ok(Dedupe::Differs) => {
+                    self.files_differed += 1;
+                    eprintln!(
+                        "warning: not reflinked, contents changed since being read: {}",
+                        pd.path.display()
+                    );
+                }
+                Err(e) => {
+                    self.files_failed += 1;
+                    eprintln!("warning: couldn't reflink {}: {}", pd.path.display(), e);
+                }
+            }
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let bytes = self
+            .bytes_shared
+            .file_size(file_size_opts::BINARY)
+            .unwrap_or_else(|_| format!("{} B", self.bytes_shared));
+
+        format!(
+            "reflinked {} files sharing {}; {} changed, {} failed",
+            self.files_shared, bytes, self.files_differed, self.files_failed
+        )
+    }
+}
+
+// Share the first `len` bytes of `dest` with `src`, if their contents are the same.
+pub fn dedupe_file(src: &Path, dest: &Path, len: u64) -> io::Result<Dedupe> {
+    let src = File::open(crate::platform::long_path(src))?;
+
+    // Sharing storage changes how the destination is stored, though not its
+    // contents, so it must be opened for writing.
+    let dest = OpenOptions::new()
+        .write(true)
+        .open(crate::platform::long_path(dest))?;
+
+    dedupe_range(&src, &dest, len)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    // `struct file_dedupe_range` from linux/fs.h, with room for one destination.
+    #[repr(C)]
+    pub struct FileDedupeRange {
+        pub src_offset: u64,
+        pub src_length: u64,
+        pub dest_count: u16,
+        pub reserved1: u16,
+        pub reserved2: u32,
+        pub info: [FileDedupeRangeInfo; 1],
+    }
+
+    // `struct file_dedupe_range_info` from linux/fs.h.
+    #[repr(C)]
+    pub struct FileDedupeRangeInfo {
+        pub dest_fd: i64,
+        pub dest_offset: u64,
+        pub bytes_deduped: u64,
+        pub status: i32,
+        pub reserved: u32,
+    }
+
+    // `_IOWR(0x94, 54, struct file_dedupe_range)`, which the header (without the
+    // flexible array of destinations) makes 24 bytes; the same on every architecture.
+    pub const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+
+    // `status` when the ranges' contents differ.
+    pub const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+    // Largest range deduplicated at once. Filesystems may do less per call, such as
+    // Btrfs's 16 MiB, so ranges are deduplicated in a loop regardless.
+    pub const MAX_DEDUPE_LEN: u64 = 16 * 1024 * 1024;
+}
+
+#[cfg(target_os = "linux")]
+fn dedupe_range(src: &File, dest: &File, len: u64) -> io::Result<Dedupe> {
+    use linux::*;
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = 0;
+
+    while offset < len {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: (len - offset).min(MAX_DEDUPE_LEN),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: i64::from(dest.as_raw_fd()),
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+
+        let result = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let info = &range.info[0];
+
+        if info.status < 0 {
+            return Err(io::Error::from_raw_os_error(-info.status));
+        }
+
+        if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return Ok(Dedupe::Differs);
+        }
+
+        // Nothing shared means no progress is possible; don't loop forever.
+        if info.bytes_deduped == 0 {
+            break;
+        }
+
+        offset += info.bytes_deduped;
+    }
+
+    Ok(Dedupe::Shared(offset))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn dedupe_range(_src: &File, _dest: &File, _len: u64) -> io::Result<Dedupe> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reflinking is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dedupe_range_layout() {
+        assert_eq!(std::mem::size_of::<linux::FileDedupeRangeInfo>(), 32);
+        assert_eq!(std::mem::size_of::<linux::FileDedupeRange>(), 24 + 32);
+    }
+
+    // Whether or not the temporary directory's filesystem supports sharing, files
+    // must never end up with each other's contents.
+    #[test]
+    fn never_changes_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("a"), vec![1; 10000]).unwrap();
+        write(root.join("b"), vec![1; 10000]).unwrap();
+        write(root.join("c"), vec![2; 10000]).unwrap();
+
+        if let Ok(result) = dedupe_file(&root.join("a"), &root.join("b"), 10000) {
+            assert_eq!(result, Dedupe::Shared(10000));
+        }
+
+        if let Ok(result) = dedupe_file(&root.join("a"), &root.join("c"), 10000) {
+            assert_eq!(result, Dedupe::Differs);
+        }
+
+        assert_eq!(read(root.join("b")).unwrap(), vec![1; 10000]);
+        assert_eq!(read(root.join("c")).unwrap(), vec![2; 10000]);
+    }
+
+    #[test]
+    fn summary() {
+        let reflinker = Reflinker {
+            files_shared: 3,
+            bytes_shared: 3 * 1024 * 1024,
+            files_differed: 1,
+            files_failed: 2,
+        };
+
+        assert_eq!(
+            reflinker.summary(),
+            "reflinked 3 files sharing 3 MiB; 1 changed, 2 failed"
+        );
+    }
+}
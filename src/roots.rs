@@ -0,0 +1,131 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use crate::possdupe::PossDupe;
+use std::path::{Path, PathBuf};
+
+// A labeled root given with `--root LABEL:PATH`. Files under the root's path are
+// tagged with its label so groups of duplicates can be reported by the sets of
+// files they span, e.g., which backups hold a copy of a live file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Root {
+    pub label: String,
+    pub path: PathBuf,
+}
+
+// Parse `LABEL:PATH`. The label is everything before the first colon, so paths
+// may contain colons, such as Windows drive letters.
+pub fn parse(arg: &str) -> Result<Root, String> {
+    match arg.split_once(':') {
+        Some((label, path)) if !label.is_empty() && !path.is_empty() => Ok(Root {
+            label: label.to_string(),
+            path: PathBuf::from(path),
+        }),
+        _ => Err(format!("expected LABEL:PATH, got '{}'", arg)),
+    }
+}
+
+// Return the index of the root containing `path`, or `None` if no root does. When
+// roots are nested, the innermost one wins. Paths are compared component by
+// component as given, so roots must be written the same way as the list of files
+// (both relative or both absolute).
+pub fn root_of(path: &Path, roots: &[Root]) -> Option<usize> {
+    roots
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| path.starts_with(&root.path))
+        .max_by_key(|(_, root)| root.path.components().count())
+        .map(|(index, _)| index)
+}
+
+// Labels of the roots which a group of duplicates spans, in the order the roots
+// were given. A label given for several roots is only listed once.
+pub fn group_labels<'a>(group: &[PossDupe], roots: &'a [Root]) -> Vec<&'a str> {
+    let mut labels: Vec<&str> = Vec::new();
+
+    for root in roots {
+        let label = root.label.as_str();
+        let present = group
+            .iter()
+            .filter_map(|pd| pd.root)
+            .any(|index| roots[index].label == label);
+
+        if present && !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots() -> Vec<Root> {
+        vec![
+            parse("backup:/mnt/a").unwrap(),
+            parse("live:/mnt/b").unwrap(),
+            parse("old:/mnt/a/old").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn parses_labels_and_paths() {
+        assert_eq!(
+            parse("c:C:\\data").unwrap(),
+            Root {
+                label: "c".to_string(),
+                path: PathBuf::from("C:\\data"),
+            }
+        );
+        assert!(parse("nolabel").is_err());
+        assert!(parse(":/mnt/a").is_err());
+        assert!(parse("label:").is_err());
+    }
+
+    #[test]
+    fn innermost_root_contains_a_path() {
+        let roots = roots();
+
+        assert_eq!(root_of(Path::new("/mnt/a/x"), &roots), Some(0));
+        assert_eq!(root_of(Path::new("/mnt/b/x"), &roots), Some(1));
+        assert_eq!(root_of(Path::new("/mnt/a/old/x"), &roots), Some(2));
+        assert_eq!(root_of(Path::new("/mnt/ab/x"), &roots), None);
+        assert_eq!(root_of(Path::new("/home/x"), &roots), None);
+    }
+
+    #[test]
+    fn labels_spanned_by_a_group() {
+        let roots = roots();
+        let tagged = |path: &str| {
+            let mut pd = PossDupe::new(path, 1);
+            pd.root = root_of(&pd.path, &roots);
+            pd
+        };
+
+        let group = [tagged("/mnt/b/x"), tagged("/mnt/a/x"), tagged("/home/x")];
+        assert_eq!(group_labels(&group, &roots), vec!["backup", "live"]);
+
+        let group = [tagged("/home/x"), tagged("/home/y")];
+        assert!(group_labels(&group, &roots).is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Writing a shell script with `--emit-script`, which deletes or links every
+// duplicate but the file kept when it's run, so the changes can be reviewed
+// before anything happens. Nothing is touched by `fddup` itself.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::Result;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// What the script does with each duplicate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptAction {
+    Hardlink,
+    Delete,
+    Symlink,
+}
+
+impl ScriptAction {
+    pub fn parse(s: &str) -> Option<ScriptAction> {
+        match s {
+            "hardlink" => Some(ScriptAction::Hardlink),
+            "delete" => Some(ScriptAction::Delete),
+            "symlink" => Some(ScriptAction::Symlink),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScriptAction::Hardlink => "hardlink",
+            ScriptAction::Delete => "delete",
+            ScriptAction::Symlink => "symlink",
+        }
+    }
+}
+
+pub struct Script {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    action: ScriptAction,
+}
+
+// Quote a path for a POSIX shell. Everything inside single quotes is literal, so
+// only single quotes themselves need to be ended, escaped, and quoted again.
+fn quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+
+    for &b in crate::platform::path_bytes(path).iter() {
+        match b {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(b),
+        }
+    }
+
+    quoted.push(b'\'');
+    quoted
+}
+
+// Quote a path for a comment, which ends at the first newline, so anything after
+// one in a file name would run as a command. Control characters and backslashes
+// are escaped instead, as `\n` or `\xHH`, keeping the comment on one line.
+fn comment_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = Vec::new();
+
+    for b in quote(path) {
+        match b {
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            b'\r' => quoted.extend_from_slice(b"\\r"),
+            b'\t' => quoted.extend_from_slice(b"\\t"),
+            b if b < 0x20 || b == 0x7f => {
+                quoted.extend_from_slice(format!("\\x{:02x}", b).as_bytes())
+            }
+            b => quoted.push(b),
+        }
+    }
+
+    quoted
+}
+
+// Path for a symlink to point at. A relative target is resolved from the link's
+// directory rather than where the script is run, so the target is made absolute.
+fn link_target(keep: &Path) -> PathBuf {
+    std::fs::canonicalize(keep)
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(keep)))
+        .unwrap_or_else(|_| keep.to_path_buf())
+}
+
+impl Script {
+    pub fn new(filename: &str, mode: Option<u32>, action: ScriptAction) -> Result<Script> {
+        let mut writer = crate::cli::file_writer(filename, mode)?;
+
+        writeln!(writer, "#!/bin/sh")?;
+        writeln!(
+            writer,
+            "# written by fddup: {} every duplicate but the file kept from each group",
+            action.name()
+        )?;
+        writeln!(
+            writer,
+            "# relative paths are as listed, so run this from the same directory"
+        )?;
+
+        Ok(Script { writer, action })
+    }
+
+    // Write the commands for one group, the file to keep first. Links and archive
+    // members have no contents of their own, so they're left out, as is any group
+    // whose file to keep is one.
+    pub fn write_group(&mut self, group: &[PossDupe]) -> Result<()> {
+        let keep = match group.first() {
+            Some(keep) if keep.link_target.is_none() && keep.archive.is_none() => keep,
+            _ => return Ok(()),
+        };
+
+        let target = match self.action {
+            ScriptAction::Symlink => link_target(&keep.path),
+            _ => keep.path.clone(),
+        };
+
+        writeln!(self.writer)?;
+        self.writer.write_all(b"# keep ")?;
+        self.writer.write_all(&comment_quote(&keep.path))?;
+        writeln!(self.writer)?;
+
+        for pd in group[1..].iter() {
+            if pd.link_target.is_some() || pd.archive.is_some() {
+                continue;
+            }
+
+            match self.action {
+                ScriptAction::Delete => self.writer.write_all(b"rm -f -- ")?,
+                ScriptAction::Hardlink => {
+                    self.writer.write_all(b"ln -f -- ")?;
+                    self.writer.write_all(&quote(&target))?;
+                    self.writer.write_all(b" ")?;
+                }
+                ScriptAction::Symlink => {
+                    self.writer.write_all(b"ln -sf -- ")?;
+                    self.writer.write_all(&quote(&target))?;
+                    self.writer.write_all(b" ")?;
+                }
+            }
+
+            self.writer.write_all(&quote(&pd.path))?;
+            writeln!(self.writer)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_quoted() {
+        assert_eq!(quote(Path::new("a b")), b"'a b'".to_vec());
+        assert_eq!(quote(Path::new("it's")), b"'it'\\''s'".to_vec());
+        assert_eq!(quote(Path::new("$HOME/`x`")), b"'$HOME/`x`'".to_vec());
+        assert_eq!(quote(Path::new("-n")), b"'-n'".to_vec());
+    }
+
+    #[test]
+    fn comments_stay_on_one_line() {
+        assert_eq!(comment_quote(Path::new("a b")), b"'a b'".to_vec());
+        assert_eq!(
+            comment_quote(Path::new("a\ntouch PWNED #")),
+            b"'a\\ntouch PWNED #'".to_vec()
+        );
+        assert_eq!(
+            comment_quote(Path::new("a\\b\x1b\t")),
+            b"'a\\\\b\\x1b\\t'".to_vec()
+        );
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(ScriptAction::parse("delete"), Some(ScriptAction::Delete));
+        assert_eq!(
+            ScriptAction::parse("hardlink"),
+            Some(ScriptAction::Hardlink)
+        );
+        assert_eq!(ScriptAction::parse("symlink"), Some(ScriptAction::Symlink));
+        assert_eq!(ScriptAction::parse("move"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scripts_keep_the_first_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let script_path = root.join("dedupe.sh");
+
+        let mut script =
+            Script::new(script_path.to_str().unwrap(), None, ScriptAction::Hardlink).unwrap();
+        let mut member = PossDupe::new("archive.tar/b", 4);
+        member.archive = Some(Box::new(crate::possdupe::ArchiveMember {
+            archive: PathBuf::from("archive.tar"),
+            offset: 512,
+        }));
+        script
+            .write_group(&[PossDupe::new("keep", 4), PossDupe::new("it's", 4), member])
+            .unwrap();
+        script.flush().unwrap();
+
+        let text = std::fs::read_to_string(&script_path).unwrap();
+        let lines: Vec<_> = text.lines().skip(3).collect();
+        assert_eq!(
+            lines,
+            vec!["", "# keep 'keep'", "ln -f -- 'keep' 'it'\\''s'"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn newlines_in_the_kept_path_stay_quoted() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let script_path = root.join("dedupe.sh");
+
+        let mut script =
+            Script::new(script_path.to_str().unwrap(), None, ScriptAction::Delete).unwrap();
+        script
+            .write_group(&[PossDupe::new("a\ntouch PWNED #", 4), PossDupe::new("b", 4)])
+            .unwrap();
+        script.flush().unwrap();
+
+        let text = std::fs::read_to_string(&script_path).unwrap();
+        let lines: Vec<_> = text.lines().skip(3).collect();
+        assert_eq!(
+            lines,
+            vec!["", "# keep 'a\\ntouch PWNED #'", "rm -f -- 'b'"]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A size index, for `--size-index FILE`: the sizes of files listed ahead of time,
+// so files in it needn't be stat'ed, which is slow on network filesystems. Each
+// line is `SIZE<TAB>MTIME<TAB>DEV:INO<TAB>PATH`, as written by
+// `find -type f -printf '%s\t%T@\t%D:%i\t%p\n'`, where MTIME is in seconds since
+// the epoch and may have a fraction. MTIME and DEV:INO may be `-` when not known.
+// Blank lines and lines starting with `#` are ignored.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What the index says about a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub len: u64,
+    pub mtime: Option<SystemTime>,
+    pub file_id: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Default)]
+pub struct SizeIndex(HashMap<PathBuf, Entry>);
+
+// Parse a modification time in seconds since the epoch, with up to nanoseconds of
+// fraction; `find` writes more digits than that, which are dropped.
+fn parse_mtime(s: &str) -> Option<SystemTime> {
+    let (secs, fraction) = s.split_once('.').unwrap_or((s, ""));
+
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let digits: String = fraction
+        .chars()
+        .chain("000000000".chars())
+        .take(9)
+        .collect();
+    let since = Duration::new(secs.parse().ok()?, digits.parse().ok()?);
+    UNIX_EPOCH.checked_add(since)
+}
+
+// Parse a file id as `DEV:INO`.
+fn parse_file_id(s: &str) -> Option<(u64, u64)> {
+    let (dev, ino) = s.split_once(':')?;
+    Some((dev.parse().ok()?, ino.parse().ok()?))
+}
+
+// Parse one line of an index, returning None for lines to ignore.
+fn parse_line(line: &[u8]) -> Result<Option<(PathBuf, Entry)>> {
+    if line.is_empty() || line[0] == b'#' {
+        return Ok(None);
+    }
+
+    let mut fields = line.splitn(4, |&b| b == b'\t');
+    let mut field = || {
+        fields
+            .next()
+            .ok_or_else(|| anyhow!("expected SIZE, MTIME, DEV:INO and PATH separated by tabs"))
+    };
+    let (len, mtime, file_id, path) = (field()?, field()?, field()?, field()?);
+    let text = |field: &[u8]| String::from_utf8_lossy(field).into_owned();
+
+    let len = text(len)
+        .parse()
+        .map_err(|_| anyhow!("invalid size '{}'", text(len)))?;
+    let mtime = match text(mtime).as_str() {
+        "-" => None,
+        s => Some(parse_mtime(s).ok_or_else(|| anyhow!("invalid time '{}'", s))?),
+    };
+    let file_id = match text(file_id).as_str() {
+        "-" => None,
+        s => Some(parse_file_id(s).ok_or_else(|| anyhow!("invalid file id '{}'", s))?),
+    };
+
+    if path.is_empty() {
+        return Err(anyhow!("missing path"));
+    }
+
+    let path = crate::platform::path_from_bytes(path.to_vec())?;
+    Ok(Some((
+        path,
+        Entry {
+            len,
+            mtime,
+            file_id,
+        },
+    )))
+}
+
+impl SizeIndex {
+    pub fn load(path: &Path) -> Result<SizeIndex> {
+        let file = File::open(path)
+            .with_context(|| format!("couldn't open size index {}", path.display()))?;
+        let mut index = SizeIndex::default();
+
+        for (number, line) in BufReader::new(file).split(b'\n').enumerate() {
+            let mut line =
+                line.with_context(|| format!("couldn't read size index {}", path.display()))?;
+
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            let parsed = parse_line(&line).map_err(|e| {
+                anyhow!(
+                    "line {} of size index {} is invalid: {}",
+                    number + 1,
+                    path.display(),
+                    e
+                )
+            })?;
+
+            if let Some((path, entry)) = parsed {
+                index.0.insert(path, entry);
+            }
+        }
+
+        Ok(index)
+    }
+
+    // What the index says about a file, listed under exactly this path.
+    pub fn get(&self, path: &Path) -> Option<Entry> {
+        self.0.get(path).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_from_find() {
+        let (path, entry) = parse_line(b"1234\t1697040000.1234567890\t2049:131\t/mnt/a b\tc")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(path, PathBuf::from("/mnt/a b\tc"));
+        assert_eq!(entry.len, 1234);
+        assert_eq!(
+            entry.mtime,
+            Some(UNIX_EPOCH + Duration::new(1697040000, 123456789))
+        );
+        assert_eq!(entry.file_id, Some((2049, 131)));
+
+        let (_, entry) = parse_line(b"5\t-\t-\tb").unwrap().unwrap();
+        assert_eq!(
+            entry,
+            Entry {
+                len: 5,
+                mtime: None,
+                file_id: None
+            }
+        );
+        assert_eq!(
+            parse_line(b"5\t1697040000\t-\tb").unwrap().unwrap().1.mtime,
+            Some(UNIX_EPOCH + Duration::from_secs(1697040000))
+        );
+    }
+
+    #[test]
+    fn comments_and_invalid_lines() {
+        assert!(parse_line(b"").unwrap().is_none());
+        assert!(parse_line(b"# size index").unwrap().is_none());
+        assert!(parse_line(b"12\t-\t/mnt/a").is_err());
+        assert!(parse_line(b"x\t-\t-\t/mnt/a").is_err());
+        assert!(parse_line(b"12\tyesterday\t-\t/mnt/a").is_err());
+        assert!(parse_line(b"12\t-\t2049\t/mnt/a").is_err());
+        assert!(parse_line(b"12\t-\t-\t").is_err());
+    }
+}
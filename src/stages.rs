@@ -0,0 +1,248 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Reading and hashing as a pipeline of two stages, each with its own threads, for
+// `--threads-io` and `--threads-hash`. Chunks are read on the runtime's threads, as
+// they are otherwise, but each is then sent to a separate pool of hashing threads,
+// and the task which read it waits for it to be hashed without holding on to its
+// thread. An I/O thread can then go on to read another file while the chunk it
+// read is hashed, so the storage can be kept busy by more threads than there are
+// cores to hash on.
+//
+// Reads only get so far ahead of hashing: a read must first take one of a fixed
+// number of buffers, which is only given back once its chunk has been hashed.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+// A chunk read from a file, waiting to be hashed.
+struct Chunk {
+    poss_dupe: PossDupe,
+    buffer: Vec<u8>,
+    len: usize,
+    block_size: usize,
+    hashed: oneshot::Sender<(PossDupe, Vec<u8>)>,
+}
+
+#[derive(Clone)]
+pub struct Stages {
+    pool: Arc<HashPool>,
+}
+
+struct HashPool {
+    sender: Option<mpsc::Sender<Chunk>>,
+    threads: Vec<JoinHandle<()>>,
+
+    // One permit for each buffer a read can take.
+    permits: Semaphore,
+
+    // Buffers from finished reads, to be re-used by later ones. A buffer can't
+    // stay with a thread as it does otherwise, since the thread which hashes the
+    // data usually isn't the one which read it.
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Stages {
+    // Start `hash_threads` hashing threads for reads made on `io_threads` threads.
+    // There's a buffer for each thread reading or hashing, and as many again for
+    // chunks queued to be hashed, so a hashing thread never waits on a read while
+    // there's a chunk ready.
+    pub fn new(io_threads: usize, hash_threads: usize) -> Stages {
+        let (sender, receiver) = mpsc::channel::<Chunk>(hash_threads);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..hash_threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+
+                thread::Builder::new()
+                    .name(String::from("fddup-hash"))
+                    .spawn(move || hash_chunks(&receiver))
+                    .unwrap()
+            })
+            .collect();
+
+        Stages {
+            pool: Arc::new(HashPool {
+                sender: Some(sender),
+                threads,
+                permits: Semaphore::new(io_threads + 2 * hash_threads),
+                buffers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    // Read the next chunk of a file with `read`, which fills the start of the
+    // buffer it's given and returns how many bytes it read, and hash it on one of
+    // the hashing threads, in updates of `hash_block_size` bytes if given, or all at
+    // once. The file is handed back whether or not the read succeeded.
+    pub async fn read_next<F>(
+        &self,
+        mut poss_dupe: PossDupe,
+        hash_block_size: Option<usize>,
+        read: F,
+    ) -> (PossDupe, Result<()>)
+    where
+        F: FnOnce(&mut PossDupe, &mut Vec<u8>) -> Result<usize>,
+    {
+        let pool = &self.pool;
+        let _permit = pool.permits.acquire().await.unwrap();
+        let mut buffer = pool.buffers.lock().unwrap().pop().unwrap_or_default();
+
+        let len = match read(&mut poss_dupe, &mut buffer) {
+            Ok(len) => len,
+            Err(e) => {
+                pool.buffers.lock().unwrap().push(buffer);
+                return (poss_dupe, Err(e));
+            }
+        };
+
+        let (hashed, receiver) = oneshot::channel();
+        let chunk = Chunk {
+            poss_dupe,
+            buffer,
+            len,
+            block_size: hash_block_size.unwrap_or(len),
+            hashed,
+        };
+
+        if let Err(unsent) = pool.sender.as_ref().unwrap().send(chunk).await {
+            return (unsent.0.poss_dupe, Err(anyhow!("hashing threads stopped")));
+        }
+
+        let (poss_dupe, buffer) = receiver.await.expect("hashing thread panicked");
+        pool.buffers.lock().unwrap().push(buffer);
+
+        (poss_dupe, Ok(()))
+    }
+}
+
+// Hash chunks as they arrive until every sender has gone.
+fn hash_chunks(receiver: &Mutex<mpsc::Receiver<Chunk>>) {
+    loop {
+        let chunk = match receiver.lock().unwrap().blocking_recv() {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        let Chunk {
+            mut poss_dupe,
+            buffer,
+            len,
+            block_size,
+            hashed,
+        } = chunk;
+
+        poss_dupe.update_digest_in_blocks(&buffer[0..len], block_size);
+
+        // The reading task only goes away if its runtime is shutting down, in which
+        // case there's nobody left to give the file back to.
+        let _ = hashed.send((poss_dupe, buffer));
+    }
+}
+
+impl Drop for HashPool {
+    fn drop(&mut self) {
+        self.sender = None;
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(stages: &Stages, mut pd: PossDupe, read_size: usize) -> PossDupe {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()
+            .unwrap();
+
+        while pd.bytes_remaining() > 0 {
+            let read = |pd: &mut PossDupe, buffer: &mut Vec<u8>| {
+                pd.open()?;
+                pd.read_chunk(read_size, buffer)
+            };
+            let (next, result) = runtime.block_on(stages.read_next(pd, None, read));
+            result.unwrap();
+            pd = next;
+        }
+
+        pd
+    }
+
+    #[test]
+    fn reads_hash_the_same_as_one_stage() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a");
+        std::fs::write(&path, "abcdefghij").unwrap();
+
+        let stages = Stages::new(2, 1);
+        let pd = read_all(&stages, PossDupe::new(&path, 10), 4);
+
+        let mut expected = PossDupe::new(&path, 10);
+        expected.update_digest(b"abcdefghij");
+        assert_eq!(pd.digest_hex(), expected.digest_hex());
+        assert_eq!(stages.pool.buffers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failed_reads_give_the_file_back() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("missing");
+        let stages = Stages::new(1, 1);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let read = |pd: &mut PossDupe, buffer: &mut Vec<u8>| {
+            pd.open()?;
+            pd.read_chunk(4, buffer)
+        };
+        let (pd, result) = runtime.block_on(stages.read_next(PossDupe::new(&path, 10), None, read));
+
+        assert!(result.is_err());
+        assert_eq!(pd.path, path);
+        assert_eq!(pd.bytes_remaining(), 10);
+        assert_eq!(stages.pool.permits.available_permits(), 3);
+    }
+
+    #[test]
+    fn hashing_threads_stop_once_dropped() {
+        let stages = Stages::new(1, 2);
+        let threads: Vec<_> = stages
+            .pool
+            .threads
+            .iter()
+            .map(|t| t.thread().id())
+            .collect();
+        assert_eq!(threads.len(), 2);
+
+        // Dropping the last handle joins every hashing thread, which would hang if
+        // they didn't stop.
+        drop(stages);
+    }
+}
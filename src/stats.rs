@@ -24,22 +24,65 @@ use crate::possdupe::PossDupe;
 use anyhow::{anyhow, Result};
 use humansize::{file_size_opts, FileSize};
 use num_traits::cast::ToPrimitive;
+use std::io;
+use std::time::{Duration, Instant};
 
 // Return human-readable string representing a number of bytes.
-fn to_human_readable<T: FileSize>(size: T) -> Result<String> {
+pub fn to_human_readable<T: FileSize>(size: T) -> Result<String> {
     size.file_size(file_size_opts::BINARY)
         .map_err(|e| anyhow!(e))
 }
 
-// Return human-readable string representing a percentage.
+// Bytes which could be reclaimed from a group of identical files by removing every
+// file but the one kept, listed first.
+pub fn reclaimable_bytes(group: &[PossDupe]) -> u64 {
+    group
+        .iter()
+        .skip(1)
+        .map(|pd| pd.allocated_len.unwrap_or(pd.file_len))
+        .sum()
+}
+
+// Return a human-readable elapsed time, such as `4.2s`, `3m 05s` or `1h 02m 09s`.
+fn to_human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+// Return a rate per second over an elapsed time; nothing over no time is 0.
+fn per_second(count: f64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => count / secs,
+        _ => 0.0,
+    }
+}
+
+// Return human-readable string representing a percentage. Nothing out of nothing,
+// such as with no files at all, is shown as 0% rather than NaN.
 fn to_percentage<T: ToPrimitive>(numerator: T, denominator: T) -> String {
     let n = ToPrimitive::to_f64(&numerator).unwrap();
     let d = ToPrimitive::to_f64(&denominator).unwrap();
 
+    if d == 0.0 {
+        return String::from("0.0%");
+    }
+
     format!("{:.1}%", (n / d) * 100.0)
 }
 
+// Called with each file once it's found to be unique or a duplicate, when its
+// digest and the number of bytes read from it are final.
+pub type OnFile = Box<dyn FnMut(&PossDupe) -> Result<()> + Send>;
+
 pub struct Stats {
+    // When the run started, for overall throughput
+    started: Instant,
+
     // Size of all files we might read
     total_bytes_considered: u64,
 
@@ -54,6 +97,40 @@ pub struct Stats {
     // counted here as two
     num_duplicate_files: usize,
 
+    // Number of groups of identical files
+    num_duplicate_groups: usize,
+
+    // Bytes which could be reclaimed by keeping only one file of each group; this
+    // is space allocated on disk rather than file length when it's known
+    reclaimable_bytes: u64,
+
+    // Number of files in groups of duplicates which weren't reported because they
+    // had fewer files than `--min-copies`
+    num_too_few_copies: usize,
+
+    // Number of files in groups of duplicates which weren't reported because they
+    // were all in the same directory, with `--exclude-same-dir`
+    num_same_dir: usize,
+
+    // Number of files skipped because they couldn't be read for lack of permission
+    num_permission_denied: usize,
+
+    // Number of files skipped because they didn't exist, such as files removed
+    // since being listed
+    num_not_found: usize,
+
+    // Number of files skipped because their contents weren't what they should be,
+    // such as compressed files which failed to decompress
+    num_invalid: usize,
+
+    // Number of files which could have been duplicates but couldn't be told either
+    // way: they failed to be read, or were left over when a run stopped early
+    num_incomplete: usize,
+
+    // Number of groups whose digests matched but whose contents differed when
+    // verified; this should never happen with a full-length digest
+    num_collisions: usize,
+
     // Number of unique files; this includes files which had a unique size or files
     // which had a non-unique size, but differing hash
     num_unique_files: usize,
@@ -66,39 +143,194 @@ pub struct Stats {
 
     // Number of files which had 0 bytes read because they had a unique size
     num_files_not_read: usize,
+
+    // Number of files to be processed, known once all input has been read
+    num_files_total: usize,
+
+    // Number of files found to be either unique or a duplicate so far
+    num_files_processed: usize,
+
+    // Callbacks given every file as it's tracked, callbacks given only unique
+    // files, and the first error one returned
+    on_file: Vec<OnFile>,
+    on_unique: Vec<OnFile>,
+    on_file_error: Option<anyhow::Error>,
+}
+
+// Call each callback with a file, until one returns an error, which is kept in
+// `error`. No callbacks are called once one has failed.
+fn call(callbacks: &mut [OnFile], error: &mut Option<anyhow::Error>, pd: &PossDupe) {
+    if error.is_some() {
+        return;
+    }
+
+    for f in callbacks.iter_mut() {
+        if let Err(e) = f(pd) {
+            *error = Some(e);
+            break;
+        }
+    }
 }
 
 impl Stats {
     pub fn new() -> Stats {
         Stats {
+            started: Instant::now(),
             total_bytes_considered: 0,
             total_bytes_read: 0,
             total_bytes_skipped: 0,
             num_duplicate_files: 0,
+            num_duplicate_groups: 0,
+            reclaimable_bytes: 0,
+            num_too_few_copies: 0,
+            num_same_dir: 0,
+            num_permission_denied: 0,
+            num_not_found: 0,
+            num_invalid: 0,
+            num_incomplete: 0,
+            num_collisions: 0,
             num_unique_files: 0,
             num_files_partially_read: 0,
             num_files_fully_read: 0,
             num_files_not_read: 0,
+            num_files_total: 0,
+            num_files_processed: 0,
+            on_file: Vec::new(),
+            on_unique: Vec::new(),
+            on_file_error: None,
+        }
+    }
+
+    // Call `f` with every file tracked from now on.
+    pub fn on_file(&mut self, f: OnFile) {
+        self.on_file.push(f);
+    }
+
+    // Call `f` with every file found to be unique from now on, after the
+    // callbacks given to `on_file`.
+    pub fn on_unique(&mut self, f: OnFile) {
+        self.on_unique.push(f);
+    }
+
+    // Return the first error returned by a callback given to `on_file` or
+    // `on_unique`. No more callbacks are called once one has failed.
+    pub fn on_file_result(&mut self) -> Result<()> {
+        match self.on_file_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
 
     pub fn unique(&mut self, pd: &PossDupe) {
         self.num_unique_files += 1;
         self.track(pd);
+        call(&mut self.on_unique, &mut self.on_file_error, pd);
     }
 
-    pub fn duplicate(&mut self, pd: &PossDupe) {
-        self.num_duplicate_files += 1;
-        self.track(pd);
+    // Track a group of identical files.
+    pub fn duplicates(&mut self, group: &[PossDupe]) {
+        self.num_duplicate_groups += 1;
+
+        for pd in group.iter() {
+            self.num_duplicate_files += 1;
+            self.track(pd);
+        }
+
+        self.reclaimable_bytes += reclaimable_bytes(group);
+    }
+
+    // Track a group of identical files which isn't reported because it has fewer
+    // files than `--min-copies`. Its files are neither duplicates nor unique.
+    pub fn too_few_copies(&mut self, group: &[PossDupe]) {
+        for pd in group.iter() {
+            self.num_too_few_copies += 1;
+            self.track(pd);
+        }
+    }
+
+    // Track a group of identical files which isn't reported because they're all in
+    // the same directory. Like groups with too few copies, they're neither
+    // duplicates nor unique.
+    pub fn same_dir(&mut self, group: &[PossDupe]) {
+        for pd in group.iter() {
+            self.num_same_dir += 1;
+            self.track(pd);
+        }
+    }
+
+    // Count a file skipped after an error while the list of files was being read.
+    pub fn skipped_after_error(&mut self, kind: io::ErrorKind) {
+        match kind {
+            io::ErrorKind::PermissionDenied => self.num_permission_denied += 1,
+            io::ErrorKind::NotFound => self.num_not_found += 1,
+            io::ErrorKind::InvalidData => self.num_invalid += 1,
+            _ => {}
+        }
+    }
+
+    // Count a file skipped after an error while reading it. It was already counted
+    // in the total, so it's also processed, but it's neither unique nor a duplicate.
+    pub fn skipped_after_read_error(&mut self, kind: io::ErrorKind) {
+        self.skipped_after_error(kind);
+        self.num_files_processed += 1;
+        self.num_incomplete += 1;
+    }
+
+    // Count files left over when a run stops before telling whether they're
+    // duplicates.
+    pub fn undetermined(&mut self, count: usize) {
+        self.num_incomplete += count;
+    }
+
+    pub fn set_total_files(&mut self, total: usize) {
+        self.num_files_total = total;
+    }
+
+    pub fn collision(&mut self) {
+        self.num_collisions += 1;
+    }
+
+    pub fn num_collisions(&self) -> usize {
+        self.num_collisions
+    }
+
+    // Single-line summary of duplicates found, suitable for parsing by scripts.
+    pub fn summary(&self) -> String {
+        format!(
+            "groups={} files={} reclaimable_bytes={}",
+            self.num_duplicate_groups, self.num_duplicate_files, self.reclaimable_bytes
+        )
+    }
+
+    // Current counts as a JSON object for `--status-file`, along with the state of
+    // the run.
+    pub fn status_json(&self, state: &str) -> String {
+        format!(
+            concat!(
+                "{{\"state\": \"{}\", \"files_total\": {}, \"files_processed\": {}, ",
+                "\"duplicate_groups\": {}, \"duplicate_files\": {}, \"unique_files\": {}, ",
+                "\"bytes_read\": {}, \"reclaimable_bytes\": {}}}\n"
+            ),
+            state,
+            self.num_files_total,
+            self.num_files_processed,
+            self.num_duplicate_groups,
+            self.num_duplicate_files,
+            self.num_unique_files,
+            self.total_bytes_read,
+            self.reclaimable_bytes
+        )
     }
 
     fn track(&mut self, pd: &PossDupe) {
+        self.num_files_processed += 1;
         self.total_bytes_considered += pd.file_len;
         self.total_bytes_read += pd.bytes_read;
-        self.total_bytes_skipped += pd.bytes_remaining();
+        self.total_bytes_skipped += pd.file_len.saturating_sub(pd.bytes_read);
 
+        // A file is fully read once everything past any ignored header has been.
         if pd.bytes_read > 0 {
-            if pd.bytes_remaining() == 0 {
+            if pd.content_offset() + pd.bytes_read >= pd.file_len {
                 self.num_files_fully_read += 1;
             } else {
                 self.num_files_partially_read += 1;
@@ -106,19 +338,54 @@ impl Stats {
         } else {
             self.num_files_not_read += 1;
         }
+
+        call(&mut self.on_file, &mut self.on_file_error, pd);
     }
 
-    pub fn display(&self) -> Result<()> {
-        let total_files = self.num_duplicate_files + self.num_unique_files;
+    // Files which were found to be duplicates or unique, or were in groups which
+    // weren't reported.
+    fn total_files(&self) -> usize {
+        self.num_duplicate_files
+            + self.num_unique_files
+            + self.num_too_few_copies
+            + self.num_same_dir
+    }
 
-        eprintln!(
-            "{} files: {} duplicate ({}), {} unique ({})",
-            self.num_duplicate_files + self.num_unique_files,
+    // How many files were duplicates, in how many groups, and how many unique.
+    fn files_line(&self) -> String {
+        let total_files = self.total_files();
+
+        format!(
+            "{} files: {} duplicate ({}) in {} groups, {} unique ({})",
+            total_files,
             self.num_duplicate_files,
             to_percentage(self.num_duplicate_files, total_files),
+            self.num_duplicate_groups,
             self.num_unique_files,
             to_percentage(self.num_unique_files, total_files)
-        );
+        )
+    }
+
+    pub fn display(&self) -> Result<()> {
+        let total_files = self.total_files();
+
+        eprintln!("{}", self.files_line());
+
+        if self.num_too_few_copies > 0 {
+            eprintln!(
+                "{} files in groups with too few copies to report ({})",
+                self.num_too_few_copies,
+                to_percentage(self.num_too_few_copies, total_files)
+            );
+        }
+
+        if self.num_same_dir > 0 {
+            eprintln!(
+                "{} files in groups within a single directory ({})",
+                self.num_same_dir,
+                to_percentage(self.num_same_dir, total_files)
+            );
+        }
 
         eprintln!(
             "{} bytes: {} read ({}), {} skipped ({})",
@@ -139,6 +406,506 @@ impl Stats {
             to_percentage(self.num_files_not_read, total_files)
         );
 
+        eprintln!(
+            "processed {}/{} files ({})",
+            self.num_files_processed,
+            self.num_files_total,
+            to_percentage(self.num_files_processed, self.num_files_total)
+        );
+
+        if self.num_permission_denied + self.num_not_found + self.num_invalid > 0 {
+            eprintln!(
+                "{} files skipped after errors: {} permission denied, {} not found, {} invalid",
+                self.num_permission_denied + self.num_not_found + self.num_invalid,
+                self.num_permission_denied,
+                self.num_not_found,
+                self.num_invalid
+            );
+        }
+
+        if self.num_incomplete > 0 {
+            eprintln!(
+                "{} files incomplete: neither unique nor duplicate",
+                self.num_incomplete
+            );
+        }
+
+        if self.num_collisions > 0 {
+            eprintln!("{} hash collisions", self.num_collisions);
+        }
+
+        eprintln!("{}", self.throughput(self.started.elapsed()));
+
         Ok(())
     }
+
+    // Overall speed of the run after `elapsed`, from listing files to the end.
+    fn throughput(&self, elapsed: Duration) -> String {
+        format!(
+            "elapsed {}: {:.1} MiB/s read, {:.1} files/s",
+            to_human_duration(elapsed),
+            per_second(self.total_bytes_read as f64, elapsed) / (1024.0 * 1024.0),
+            per_second(self.num_files_processed as f64, elapsed)
+        )
+    }
+}
+
+// What a run would do, based only on file sizes: how many files could be skipped
+// because their size is unique and how much would be read in the worst case.
+pub struct Plan {
+    // Number of files with a unique size, which will never be read
+    num_unique_size_files: usize,
+
+    // Number of files which share their size with at least one other file
+    num_shared_size_files: usize,
+
+    // Number of distinct sizes shared by more than one file
+    num_shared_sizes: usize,
+
+    // Size of all files considered
+    total_bytes: u64,
+
+    // Bytes read if every file sharing its size was read in its entirety
+    worst_case_bytes: u64,
+}
+
+impl Plan {
+    // Create a plan from files with a unique size and files sharing their size,
+    // the latter ordered by size.
+    pub fn new(uniques: &[PossDupe], shared: &[PossDupe]) -> Plan {
+        let unique_bytes: u64 = uniques.iter().map(|pd| pd.file_len).sum();
+        let worst_case_bytes: u64 = shared.iter().map(|pd| pd.file_len).sum();
+        let num_shared_sizes = shared
+            .windows(2)
+            .filter(|w| w[0].key.bucket() != w[1].key.bucket())
+            .count()
+            + usize::from(!shared.is_empty());
+
+        Plan {
+            num_unique_size_files: uniques.len(),
+            num_shared_size_files: shared.len(),
+            num_shared_sizes,
+            total_bytes: unique_bytes + worst_case_bytes,
+            worst_case_bytes,
+        }
+    }
+
+    pub fn display(&self) -> Result<()> {
+        let total_files = self.num_unique_size_files + self.num_shared_size_files;
+
+        eprintln!(
+            "{} files: {} unique by size ({}), {} sharing {} sizes ({})",
+            total_files,
+            self.num_unique_size_files,
+            to_percentage(self.num_unique_size_files, total_files),
+            self.num_shared_size_files,
+            self.num_shared_sizes,
+            to_percentage(self.num_shared_size_files, total_files)
+        );
+
+        eprintln!(
+            "{} bytes: at most {} would be read ({})",
+            to_human_readable(self.total_bytes)?,
+            to_human_readable(self.worst_case_bytes)?,
+            to_percentage(self.worst_case_bytes, self.total_bytes)
+        );
+
+        Ok(())
+    }
+
+    // One line saying how many files have a unique size, shown with -v before any
+    // reads. They won't be read, so the saving is seen up front, unless every file
+    // is read to be hashed in full with `--full-hash`.
+    pub fn unique_size_summary(&self, full_hash: bool) -> Result<String> {
+        let total_files = self.num_unique_size_files + self.num_shared_size_files;
+
+        if full_hash {
+            return Ok(format!(
+                "{} of {} files had a unique size, but will be read to be hashed in full",
+                self.num_unique_size_files, total_files
+            ));
+        }
+
+        Ok(format!(
+            "{} of {} files had a unique size and won't be read, skipping {} of {}",
+            self.num_unique_size_files,
+            total_files,
+            to_human_readable(self.total_bytes - self.worst_case_bytes)?,
+            to_human_readable(self.total_bytes)?
+        ))
+    }
+}
+
+// Approximate chance that any two different files sharing a size also share a
+// digest, by the birthday bound. Only files of the same size are ever compared,
+// so the pairs which could collide are counted within each size. Empty files are
+// all identical, so they can't collide.
+pub struct CollisionEstimate {
+    // Number of files which share their size with at least one other file
+    num_files: usize,
+
+    // Number of pairs of files of the same size
+    num_pairs: f64,
+
+    // Number of digest bits compared when grouping
+    digest_bits: usize,
+}
+
+impl CollisionEstimate {
+    // Estimate the risk for files sharing their size, ordered by size, when
+    // `digest_bits` bits of their digests are compared.
+    pub fn new(shared: &[PossDupe], digest_bits: usize) -> CollisionEstimate {
+        let mut num_pairs = 0.0;
+        let mut start = 0;
+
+        for end in 1..=shared.len() {
+            if end == shared.len() || shared[end].key.bucket() != shared[start].key.bucket() {
+                if shared[start].key.len > 0 {
+                    let n = (end - start) as f64;
+                    num_pairs += n * (n - 1.0) / 2.0;
+                }
+
+                start = end;
+            }
+        }
+
+        CollisionEstimate {
+            num_files: shared.len(),
+            num_pairs,
+            digest_bits,
+        }
+    }
+
+    // Probability of at least one collision: 1 - e^(-pairs / 2^bits).
+    fn probability(&self) -> f64 {
+        -(-self.num_pairs / 2f64.powi(self.digest_bits as i32)).exp_m1()
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "collision estimate: {} files sharing sizes, {}-bit digests: probability {:.1e}",
+            self.num_files,
+            self.digest_bits,
+            self.probability()
+        )
+    }
+
+    pub fn display(&self) {
+        eprintln!("{}", self.summary());
+    }
+}
+
+// Time spent in each phase of a run. Phases are timed as a whole, or once per
+// round, so timing costs nothing noticeable even when it isn't displayed.
+pub struct Timing {
+    start: Instant,
+
+    // Reading the list of files and finding each file's size
+    stat: Duration,
+
+    // Reading and hashing files
+    read: Duration,
+
+    // Writing duplicates and anything after them
+    output: Duration,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            start: Instant::now(),
+            stat: Duration::ZERO,
+            read: Duration::ZERO,
+            output: Duration::ZERO,
+        }
+    }
+
+    pub fn stat(&mut self, since: Instant) {
+        self.stat += since.elapsed();
+    }
+
+    pub fn read(&mut self, since: Instant) {
+        self.read += since.elapsed();
+    }
+
+    pub fn output(&mut self, since: Instant) {
+        self.output += since.elapsed();
+    }
+
+    fn summary(&self, total: Duration) -> String {
+        format!(
+            "time: {:.3?} stat, {:.3?} read, {:.3?} output, {:.3?} total",
+            self.stat, self.read, self.output, total
+        )
+    }
+
+    pub fn display(&self) {
+        eprintln!("{}", self.summary(self.start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_json() {
+        let mut stats = Stats::new();
+        stats.set_total_files(3);
+        stats.duplicates(&[PossDupe::new("a", 10), PossDupe::new("b", 10)]);
+
+        assert_eq!(
+            stats.status_json("running"),
+            concat!(
+                "{\"state\": \"running\", \"files_total\": 3, \"files_processed\": 2, ",
+                "\"duplicate_groups\": 1, \"duplicate_files\": 2, \"unique_files\": 0, ",
+                "\"bytes_read\": 0, \"reclaimable_bytes\": 10}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn timing_summary() {
+        let mut timing = Timing::new();
+        timing.stat = Duration::from_millis(1500);
+        timing.read = Duration::from_millis(250);
+        timing.output = Duration::from_micros(40);
+
+        assert_eq!(
+            timing.summary(Duration::from_secs(2)),
+            "time: 1.500s stat, 250.000ms read, 40.000µs output, 2.000s total"
+        );
+    }
+
+    #[test]
+    fn plan_counts_shared_sizes() {
+        let uniques = vec![PossDupe::new("a", 5)];
+        let shared = vec![
+            PossDupe::new("b", 10),
+            PossDupe::new("c", 10),
+            PossDupe::new("d", 20),
+            PossDupe::new("e", 20),
+            PossDupe::new("f", 20),
+        ];
+
+        let plan = Plan::new(&uniques, &shared);
+
+        assert_eq!(plan.num_unique_size_files, 1);
+        assert_eq!(plan.num_shared_size_files, 5);
+        assert_eq!(plan.num_shared_sizes, 2);
+        assert_eq!(plan.total_bytes, 85);
+        assert_eq!(plan.worst_case_bytes, 80);
+        assert_eq!(Plan::new(&uniques, &[]).num_shared_sizes, 0);
+        assert_eq!(
+            plan.unique_size_summary(false).unwrap(),
+            "1 of 6 files had a unique size and won't be read, skipping 5 B of 85 B"
+        );
+        assert_eq!(
+            plan.unique_size_summary(true).unwrap(),
+            "1 of 6 files had a unique size, but will be read to be hashed in full"
+        );
+    }
+
+    #[test]
+    fn collision_estimate() {
+        let shared: Vec<_> = [0, 0, 10, 10, 10, 20, 20]
+            .iter()
+            .map(|len| PossDupe::new("a", *len))
+            .collect();
+
+        // Three pairs of size 10 and one of size 20; empty files don't count.
+        let estimate = CollisionEstimate::new(&shared, 8);
+        assert_eq!(estimate.num_pairs, 4.0);
+        assert!((estimate.probability() - (1.0 - (-4.0f64 / 256.0).exp())).abs() < 1e-12);
+
+        assert_eq!(
+            CollisionEstimate::new(&shared, 256).summary(),
+            "collision estimate: 7 files sharing sizes, 256-bit digests: probability 3.5e-77"
+        );
+        assert_eq!(CollisionEstimate::new(&[], 32).probability(), 0.0);
+    }
+
+    #[test]
+    fn groups_with_too_few_copies_are_not_duplicates() {
+        let mut stats = Stats::new();
+        stats.too_few_copies(&[PossDupe::new("a", 10), PossDupe::new("b", 10)]);
+        stats.duplicates(&[
+            PossDupe::new("c", 20),
+            PossDupe::new("d", 20),
+            PossDupe::new("e", 20),
+            PossDupe::new("f", 20),
+        ]);
+
+        assert_eq!(stats.summary(), "groups=1 files=4 reclaimable_bytes=60");
+        assert_eq!(stats.num_too_few_copies, 2);
+        assert_eq!(stats.num_files_processed, 6);
+    }
+
+    #[test]
+    fn counts_duplicate_groups() {
+        let mut stats = Stats::new();
+        stats.duplicates(&[PossDupe::new("a", 10), PossDupe::new("b", 10)]);
+        stats.duplicates(&[
+            PossDupe::new("c", 20),
+            PossDupe::new("d", 20),
+            PossDupe::new("e", 20),
+        ]);
+        stats.too_few_copies(&[PossDupe::new("f", 30), PossDupe::new("g", 30)]);
+        stats.unique(&PossDupe::new("h", 40));
+
+        assert_eq!(stats.num_duplicate_groups, 2);
+        assert_eq!(
+            stats.files_line(),
+            "8 files: 5 duplicate (62.5%) in 2 groups, 1 unique (12.5%)"
+        );
+        assert!(stats
+            .status_json("complete")
+            .contains("\"duplicate_groups\": 2, \"duplicate_files\": 5"));
+    }
+
+    #[test]
+    fn counts_errors_by_kind() {
+        let mut stats = Stats::new();
+        stats.set_total_files(2);
+        stats.skipped_after_error(io::ErrorKind::PermissionDenied);
+        stats.skipped_after_error(io::ErrorKind::NotFound);
+        stats.skipped_after_read_error(io::ErrorKind::NotFound);
+
+        assert_eq!(stats.num_permission_denied, 1);
+        assert_eq!(stats.num_not_found, 2);
+        assert_eq!(stats.num_files_processed, 1);
+    }
+
+    #[test]
+    fn counts_incomplete_files() {
+        let mut stats = Stats::new();
+        stats.skipped_after_error(io::ErrorKind::NotFound);
+        stats.skipped_after_read_error(io::ErrorKind::UnexpectedEof);
+        stats.undetermined(3);
+
+        assert_eq!(stats.num_incomplete, 4);
+        assert_eq!(stats.num_not_found, 1);
+        assert_eq!(stats.num_files_processed, 1);
+    }
+
+    #[test]
+    fn same_dir_groups_are_not_duplicates() {
+        let mut stats = Stats::new();
+        stats.same_dir(&[PossDupe::new("d/a", 10), PossDupe::new("d/b", 10)]);
+
+        assert_eq!(stats.num_same_dir, 2);
+        assert_eq!(stats.num_duplicate_files, 0);
+        assert_eq!(stats.num_files_processed, 2);
+    }
+
+    #[test]
+    fn human_durations() {
+        assert_eq!(to_human_duration(Duration::from_millis(4200)), "4.2s");
+        assert_eq!(to_human_duration(Duration::from_secs(185)), "3m 05s");
+        assert_eq!(to_human_duration(Duration::from_secs(3729)), "1h 02m 09s");
+    }
+
+    #[test]
+    fn throughput() {
+        let mut stats = Stats::new();
+        let mut pd = PossDupe::new("a", 20 * 1024 * 1024);
+        pd.bytes_read = pd.file_len;
+        stats.unique(&pd);
+        stats.unique(&PossDupe::new("b", 7));
+
+        assert_eq!(
+            stats.throughput(Duration::from_secs(4)),
+            "elapsed 4.0s: 5.0 MiB/s read, 0.5 files/s"
+        );
+        assert_eq!(
+            stats.throughput(Duration::ZERO),
+            "elapsed 0.0s: 0.0 MiB/s read, 0.0 files/s"
+        );
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(to_percentage(1, 4), "25.0%");
+        assert_eq!(to_percentage(3u64, 3u64), "100.0%");
+        assert_eq!(to_percentage(0, 0), "0.0%");
+        assert_eq!(to_percentage(0u64, 0u64), "0.0%");
+    }
+
+    #[test]
+    fn calls_back_with_every_file() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut stats = Stats::new();
+        let callback_seen = seen.clone();
+        stats.on_file(Box::new(move |pd| {
+            callback_seen
+                .lock()
+                .unwrap()
+                .push((pd.path.clone(), pd.bytes_read));
+            Ok(())
+        }));
+
+        let mut read = PossDupe::new("b", 10);
+        read.bytes_read = 10;
+        stats.unique(&PossDupe::new("a", 5));
+        stats.duplicates(&[read, PossDupe::new("c", 10)]);
+        stats.too_few_copies(&[PossDupe::new("d", 3)]);
+
+        let paths: Vec<_> = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, bytes_read)| (path.display().to_string(), *bytes_read))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                (String::from("a"), 0),
+                (String::from("b"), 10),
+                (String::from("c"), 0),
+                (String::from("d"), 0)
+            ]
+        );
+        assert!(stats.on_file_result().is_ok());
+    }
+
+    #[test]
+    fn stops_calling_back_after_an_error() {
+        let mut stats = Stats::new();
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let callback_calls = calls.clone();
+        stats.on_file(Box::new(move |_| {
+            callback_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(anyhow!("disk full"))
+        }));
+
+        stats.unique(&PossDupe::new("a", 5));
+        stats.unique(&PossDupe::new("b", 6));
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(stats.on_file_result().unwrap_err().to_string(), "disk full");
+        assert_eq!(stats.num_unique_files, 2);
+    }
+
+    #[test]
+    fn tracks_files_processed() {
+        let mut stats = Stats::new();
+        stats.set_total_files(4);
+
+        stats.unique(&PossDupe::new("a", 5));
+        stats.duplicates(&[PossDupe::new("b", 10), PossDupe::new("c", 10)]);
+
+        assert_eq!(stats.num_files_processed, 3);
+        assert_eq!(stats.num_files_total, 4);
+    }
+
+    #[test]
+    fn reclaimable_bytes_use_allocated_space() {
+        let mut sparse = PossDupe::new("b", 1000);
+        sparse.allocated_len = Some(8);
+
+        let mut stats = Stats::new();
+        stats.duplicates(&[PossDupe::new("a", 1000), sparse, PossDupe::new("c", 1000)]);
+
+        assert_eq!(stats.reclaimable_bytes, 1008);
+    }
 }
@@ -0,0 +1,114 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// A status file which external tools can poll for progress rather than parsing
+// standard error. It's rewritten as work is confirmed, but at most once per
+// STATUS_INTERVAL, and atomically, so readers never see a partial file.
+
+use crate::stats::Stats;
+
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Shortest time between rewrites of the status file while running
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct StatusFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    last_written: Option<Instant>,
+}
+
+impl StatusFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> StatusFile {
+        let path = path.into();
+
+        // The temporary file is alongside the status file, so renaming it over the
+        // status file never crosses filesystems.
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+
+        StatusFile {
+            path,
+            temp_path: PathBuf::from(temp_path),
+            last_written: None,
+        }
+    }
+
+    // Rewrite the status file with the run still going, unless it was rewritten
+    // too recently.
+    pub fn update(&mut self, stats: &Stats) -> Result<()> {
+        if self
+            .last_written
+            .is_some_and(|t| t.elapsed() < STATUS_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        self.write(&stats.status_json("running"))
+    }
+
+    // Rewrite the status file with the final state of the run.
+    pub fn finish(&mut self, stats: &Stats, state: &str) -> Result<()> {
+        self.write(&stats.status_json(state))
+    }
+
+    fn write(&mut self, contents: &str) -> Result<()> {
+        fs::write(&self.temp_path, contents)
+            .and_then(|_| fs::rename(&self.temp_path, &self.path))
+            .with_context(|| format!("couldn't write status file {}", self.path.display()))?;
+
+        self.last_written = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_are_throttled() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let path = root.join("status.json");
+
+        let mut status = StatusFile::new(&path);
+        let mut stats = Stats::new();
+        status.update(&stats).unwrap();
+
+        // Too soon after the last write for an update, but not for the final state.
+        stats.set_total_files(5);
+        status.update(&stats).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("\"files_total\": 0"));
+
+        status.finish(&stats, "complete").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("\"state\": \"complete\""));
+        assert!(contents.contains("\"files_total\": 5"));
+        assert!(!root.join("status.json.tmp").exists());
+    }
+}
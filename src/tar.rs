@@ -0,0 +1,140 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use tar::{Archive, EntryType};
+
+// A regular file stored in a tar archive. Its contents are stored uncompressed and
+// contiguously, so they can be read in place without extracting them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: Vec<u8>,
+    pub offset: u64,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+// Return the regular file members of the tar archive at `path`, in the order they
+// appear. Only headers are read; members' contents are skipped over by seeking,
+// so listing a large archive reads little of it. Ustar and GNU archives are
+// supported, including long names and pax headers giving a path or size. When a
+// name appears more than once, as when an archive has been appended to, every
+// copy is listed, since each is stored separately.
+pub fn members(path: &Path) -> Result<Vec<Member>> {
+    let file = File::open(crate::platform::long_path(path))
+        .with_context(|| format!("couldn't open archive {}", path.display()))?;
+
+    read_members(BufReader::new(file))
+        .with_context(|| format!("couldn't read archive {}", path.display()))
+}
+
+fn read_members<R: Read + Seek>(reader: R) -> Result<Vec<Member>> {
+    let mut archive = Archive::new(reader);
+    let mut members = Vec::new();
+
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let header = entry.header();
+
+        // Anything else, such as a directory or link, has no contents of its own
+        // to compare. Sparse files aren't stored contiguously, so they can't be
+        // read in place.
+        if let EntryType::Regular | EntryType::Continuous = header.entry_type() {
+            members.push(Member {
+                name: entry.path_bytes().into_owned(),
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+                mtime: header.mtime()?,
+            });
+        }
+    }
+
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tar::{Builder, Header};
+
+    // Append a member with the given contents.
+    fn append(archive: &mut Builder<Vec<u8>>, name: &str, kind: EntryType, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mtime(1_600_000_000);
+        header.set_mode(0o644);
+        archive.append_data(&mut header, name, data).unwrap();
+    }
+
+    #[test]
+    fn lists_regular_files_in_place() {
+        let mut builder = Builder::new(Vec::new());
+        append(&mut builder, "dir/", EntryType::Directory, b"");
+        append(&mut builder, "dir/a.txt", EntryType::Regular, b"hello");
+        append(&mut builder, "dir/b.txt", EntryType::Regular, &[7; 600]);
+        let archive = builder.into_inner().unwrap();
+
+        let members = read_members(Cursor::new(&archive)).unwrap();
+        let names: Vec<_> = members.iter().map(|m| m.name.as_slice()).collect();
+        assert_eq!(names, vec![&b"dir/a.txt"[..], &b"dir/b.txt"[..]]);
+
+        let a = &members[0];
+        assert_eq!(a.size, 5);
+        assert_eq!(a.mtime, 1_600_000_000);
+        assert_eq!(&archive[a.offset as usize..][..5], b"hello");
+
+        let b = &members[1];
+        assert_eq!(b.size, 600);
+        assert_eq!(&archive[b.offset as usize..][..600], &[7; 600][..]);
+    }
+
+    #[test]
+    fn long_names() {
+        let long = "d/".repeat(80) + "file";
+
+        let mut builder = Builder::new(Vec::new());
+        append(&mut builder, &long, EntryType::Regular, b"a");
+        builder
+            .append_pax_extensions(vec![("path", long.as_bytes())])
+            .unwrap();
+        append(&mut builder, "truncated", EntryType::Regular, b"b");
+        append(&mut builder, "short", EntryType::Regular, b"c");
+        let archive = builder.into_inner().unwrap();
+
+        let names: Vec<_> = read_members(Cursor::new(archive))
+            .unwrap()
+            .into_iter()
+            .map(|m| String::from_utf8(m.name).unwrap())
+            .collect();
+        assert_eq!(names, vec![long.clone(), long, String::from("short")]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let not_tar = vec![b'x'; 1024];
+        assert!(read_members(Cursor::new(not_tar)).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Normalize text so that files differing only in line endings or trailing
+// whitespace compare equal: CRLF line endings become LF, spaces and tabs at the
+// end of each line are removed, and trailing newlines at the end of the text are
+// removed.
+pub fn normalize(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+
+    for line in data.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let end = line
+            .iter()
+            .rposition(|b| *b != b' ' && *b != b'\t')
+            .map_or(0, |i| i + 1);
+
+        result.extend_from_slice(&line[..end]);
+        result.push(b'\n');
+    }
+
+    while result.last() == Some(&b'\n') {
+        result.pop();
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_newlines_and_whitespace_are_ignored() {
+        assert_eq!(normalize(b"a\nb"), b"a\nb");
+        assert_eq!(normalize(b"a\nb\n"), b"a\nb");
+        assert_eq!(normalize(b"a \t\nb\n\n\n"), b"a\nb");
+    }
+
+    #[test]
+    fn line_endings_are_normalized() {
+        assert_eq!(normalize(b"a\r\nb\r\n"), b"a\nb");
+    }
+
+    #[test]
+    fn leading_and_inner_whitespace_is_kept() {
+        assert_eq!(normalize(b"  a  b\n\n c"), b"  a  b\n\n c");
+    }
+
+    #[test]
+    fn blank_text() {
+        assert_eq!(normalize(b""), b"");
+        assert_eq!(normalize(b" \n\r\n"), b"");
+    }
+}
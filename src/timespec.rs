@@ -0,0 +1,202 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+// Points in time given on the command line, either relative to now, such as
+// `7d`, or as an absolute UTC date and optional time, such as `2021-09-30` or
+// `2021-09-30T12:00:00`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Parse a duration such as `30s`, `15m`, `12h`, `7d` or `2w`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (digits, unit) = s.split_at(s.len().checked_sub(1)?);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(Duration::from_secs(
+        digits.parse::<u64>().ok()?.checked_mul(seconds)?,
+    ))
+}
+
+// Number of days from 1970-01-01 to the given date in the proleptic Gregorian
+// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+// Parse a fixed-width field of digits.
+fn field(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+    let digits = s.get(range)?;
+
+    if digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+// Parse `YYYY-MM-DD`, optionally followed by `THH:MM` or `THH:MM:SS` (a space
+// may be used instead of `T` and a trailing `Z` is allowed), as UTC.
+fn parse_date(s: &str) -> Option<SystemTime> {
+    let s = s.strip_suffix('Z').unwrap_or(s);
+
+    if s.len() < 10 || s.get(4..5)? != "-" || s.get(7..8)? != "-" {
+        return None;
+    }
+
+    let year = field(s, 0..4)?;
+    let month = field(s, 5..7)?;
+    let day = field(s, 8..10)?;
+
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => return None,
+    };
+
+    if day < 1 || day > days_in_month {
+        return None;
+    }
+
+    let (hour, minute, second) = match s.len() {
+        10 => (0, 0, 0),
+        16 | 19 => {
+            if !matches!(s.get(10..11)?, "T" | " ") || s.get(13..14)? != ":" {
+                return None;
+            }
+
+            let second = if s.len() == 19 {
+                if s.get(16..17)? != ":" {
+                    return None;
+                }
+                field(s, 17..19)?
+            } else {
+                0
+            };
+
+            (field(s, 11..13)?, field(s, 14..16)?, second)
+        }
+        _ => return None,
+    };
+
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+
+    if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+    }
+}
+
+// Parse a point in time, either relative to `now` or absolute.
+pub fn parse(s: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if let Some(duration) = parse_duration(s) {
+        return now
+            .checked_sub(duration)
+            .ok_or_else(|| format!("{} is too far in the past", s));
+    }
+
+    parse_date(s).ok_or_else(|| {
+        format!(
+            "{} isn't a duration such as 7d or a date such as 2021-09-30 or 2021-09-30T12:00:00",
+            s
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(t: SystemTime) -> u64 {
+        t.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn relative() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert_eq!(secs(parse("30s", now).unwrap()), 1_000_000 - 30);
+        assert_eq!(secs(parse("15m", now).unwrap()), 1_000_000 - 15 * 60);
+        assert_eq!(secs(parse("2h", now).unwrap()), 1_000_000 - 2 * 3600);
+        assert_eq!(secs(parse("7d", now).unwrap()), 1_000_000 - 7 * 86400);
+        assert_eq!(secs(parse("1w", now).unwrap()), 1_000_000 - 7 * 86400);
+        assert_eq!(secs(parse("0d", now).unwrap()), 1_000_000);
+        assert!(parse("d", now).is_err());
+        assert!(parse("7y", now).is_err());
+        assert!(parse("-7d", now).is_err());
+    }
+
+    #[test]
+    fn absolute() {
+        let now = SystemTime::now();
+
+        assert_eq!(secs(parse("1970-01-01", now).unwrap()), 0);
+        assert_eq!(secs(parse("2021-09-30", now).unwrap()), 1_632_960_000);
+        assert_eq!(
+            secs(parse("2021-09-30T12:34:56", now).unwrap()),
+            1_632_960_000 + 12 * 3600 + 34 * 60 + 56
+        );
+        assert_eq!(
+            secs(parse("2021-09-30 12:34Z", now).unwrap()),
+            1_632_960_000 + 12 * 3600 + 34 * 60
+        );
+        assert_eq!(secs(parse("2000-02-29", now).unwrap()), 951_782_400);
+    }
+
+    #[test]
+    fn invalid_dates() {
+        let now = SystemTime::now();
+
+        assert!(parse("2021-02-29", now).is_err());
+        assert!(parse("2021-13-01", now).is_err());
+        assert!(parse("2021-9-30", now).is_err());
+        assert!(parse("2021-09-30T24:00", now).is_err());
+        assert!(parse("2021-09-30T12", now).is_err());
+        assert!(parse("yesterday", now).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use crate::possdupe::PossDupe;
+
+use anyhow::{Context, Result};
+use std::io::{BufReader, Read};
+
+// Compare the contents of two files byte for byte, ignoring any header being
+// ignored. Only each file's own contents are read, so archive members are compared
+// without reading past their ends into the rest of the archive.
+fn same_contents(a: &PossDupe, b: &PossDupe, read_size: usize) -> Result<bool> {
+    let open = |pd: &PossDupe| -> Result<_> {
+        let len = pd.file_len.saturating_sub(pd.content_offset());
+        let file = pd
+            .open_at(pd.content_offset())
+            .with_context(|| format!("couldn't open {} for verification", pd.path.display()))?;
+
+        Ok(BufReader::with_capacity(read_size, file.take(len)))
+    };
+
+    let mut a_reader = open(a)?;
+    let mut b_reader = open(b)?;
+    let mut a_buffer = vec![0; read_size];
+    let mut b_buffer = vec![0; read_size];
+
+    loop {
+        let a_len = read_full(&mut a_reader, &mut a_buffer)
+            .with_context(|| format!("couldn't read {} for verification", a.path.display()))?;
+        let b_len = read_full(&mut b_reader, &mut b_buffer)
+            .with_context(|| format!("couldn't read {} for verification", b.path.display()))?;
+
+        if a_buffer[..a_len] != b_buffer[..b_len] {
+            return Ok(false);
+        }
+
+        if a_len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+// Fill as much of `buffer` as possible, returning fewer bytes only at end of file.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+
+    Ok(filled)
+}
+
+// Verify a group of files which were found to be duplicates by digest by comparing
+// their contents byte for byte. The group is split into subgroups of files with
+// identical contents; any subgroup with a single member isn't a duplicate after all.
+pub fn verify_group(group: Vec<PossDupe>, read_size: usize) -> Result<Vec<Vec<PossDupe>>> {
+    let mut verified: Vec<Vec<PossDupe>> = Vec::new();
+
+    for pd in group {
+        let mut matched = None;
+
+        for (i, subgroup) in verified.iter().enumerate() {
+            // Links compared by target have no contents of their own to compare.
+            if pd.link_target.is_some() || subgroup[0].link_target.is_some() {
+                if pd.link_target == subgroup[0].link_target {
+                    matched = Some(i);
+                    break;
+                }
+
+                continue;
+            }
+
+            if same_contents(&subgroup[0], &pd, read_size)? {
+                matched = Some(i);
+                break;
+            }
+        }
+
+        match matched {
+            Some(i) => verified[i].push(pd),
+            None => verified.push(vec![pd]),
+        }
+    }
+
+    Ok(verified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::possdupe::ArchiveMember;
+    use std::fs::write;
+
+    #[test]
+    fn splits_groups_by_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        let contents = [("a", "same"), ("b", "diff"), ("c", "same"), ("d", "")];
+        let group = contents
+            .iter()
+            .map(|(name, data)| {
+                write(root.join(name), data).unwrap();
+                PossDupe::new(root.join(name), data.len() as u64)
+            })
+            .collect();
+
+        let verified = verify_group(group, 3).unwrap();
+        let names: Vec<Vec<&str>> = verified
+            .iter()
+            .map(|g| {
+                g.iter()
+                    .map(|pd| pd.path.file_name().unwrap().to_str().unwrap())
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(names, vec![vec!["a", "c"], vec!["b"], vec!["d"]]);
+    }
+
+    #[test]
+    fn ignores_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("a"), "1:same").unwrap();
+        write(root.join("b"), "2:same").unwrap();
+
+        let mut a = PossDupe::new(root.join("a"), 6);
+        let mut b = PossDupe::new(root.join("b"), 6);
+        assert!(!same_contents(&a, &b, 4).unwrap());
+
+        a.header_len = 2;
+        b.header_len = 2;
+        assert!(same_contents(&a, &b, 4).unwrap());
+    }
+
+    #[test]
+    fn compares_only_archive_members_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("archive"), "xxsamexxsameyy").unwrap();
+        write(root.join("file"), "same").unwrap();
+
+        let member = |offset| {
+            let mut pd = PossDupe::new(format!("archive!{}", offset), 4);
+            pd.archive = Some(Box::new(ArchiveMember {
+                archive: root.join("archive"),
+                offset,
+            }));
+            pd
+        };
+        let file = PossDupe::new(root.join("file"), 4);
+
+        assert!(same_contents(&member(2), &member(8), 4).unwrap());
+        assert!(same_contents(&member(2), &file, 3).unwrap());
+        assert!(!same_contents(&member(0), &file, 4).unwrap());
+    }
+}
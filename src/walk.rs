@@ -0,0 +1,302 @@
+// Copyright (c) 2021 Hammock Sunburn
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{metadata, read_dir};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// Options controlling how directories are walked.
+pub struct WalkOptions {
+    /// Basenames of directories which are never descended into
+    pub prune_dirs: Vec<OsString>,
+
+    /// Don't descend into directories on a different filesystem than the one
+    /// the walk started on
+    pub one_filesystem: bool,
+
+    /// Don't descend into hidden directories; hidden files are still visited
+    pub skip_hidden: bool,
+
+    /// Stop walking, visiting nothing more, once this time has passed
+    pub deadline: Option<Instant>,
+
+    /// Stop walking, visiting nothing more, once this returns true, as
+    /// `crate::interrupt::interrupted` does after Ctrl-C
+    pub interrupted: fn() -> bool,
+}
+
+// Context for an error walking a directory, keeping the path it happened on so
+// it can be recovered from the error.
+#[derive(Debug)]
+struct WalkError {
+    action: &'static str,
+    path: PathBuf,
+}
+
+impl WalkError {
+    fn new(action: &'static str, path: &Path) -> WalkError {
+        WalkError {
+            action,
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.action, self.path.display())
+    }
+}
+
+// The path an error walking a directory happened on, if `e` is such an error.
+pub fn error_path(e: &anyhow::Error) -> Option<&Path> {
+    e.downcast_ref::<WalkError>().map(|e| e.path.as_path())
+}
+
+// Recursively walk a directory, calling `visit` with the path of every entry
+// which isn't a directory. Entries are visited in sorted order so walks are
+// repeatable. Links are passed to `visit` rather than followed. Errors reading a
+// directory or entry are passed to `visit` too, which may return them to stop the
+// walk or return `Ok` to carry on without that directory or entry.
+pub fn walk(
+    dir: &Path,
+    options: &WalkOptions,
+    visit: &mut dyn FnMut(Result<PathBuf>) -> Result<()>,
+) -> Result<()> {
+    let device = if options.one_filesystem {
+        // The walk may start from a link to a directory, which is followed.
+        let attr = metadata(crate::platform::long_path(dir))
+            .with_context(|| WalkError::new("couldn't read attributes", dir))?;
+        crate::platform::device_id(&attr)
+    } else {
+        None
+    };
+
+    walk_dir(dir, options, device, visit)
+}
+
+// Walk a directory, skipping subdirectories on a device other than `device`, if
+// given. Only directories are checked since filesystems are mounted on them.
+fn walk_dir(
+    dir: &Path,
+    options: &WalkOptions,
+    device: Option<u64>,
+    visit: &mut dyn FnMut(Result<PathBuf>) -> Result<()>,
+) -> Result<()> {
+    let entries = read_dir(crate::platform::long_path(dir))
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .with_context(|| WalkError::new("couldn't read directory", dir));
+
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => return visit(Err(e)),
+    };
+
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        if (options.interrupted)()
+            || options
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Ok(());
+        }
+
+        // Build the path from `dir` rather than using `entry.path()`, so a long
+        // directory read in extended-length form isn't shown in that form.
+        let path = dir.join(entry.file_name());
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                visit(Err(e).with_context(|| WalkError::new("couldn't read attributes", &path)))?;
+                continue;
+            }
+        };
+
+        if !file_type.is_dir() {
+            visit(Ok(path))?;
+            continue;
+        }
+
+        let attr = match entry.metadata() {
+            Ok(attr) => attr,
+            Err(e) => {
+                visit(Err(e).with_context(|| WalkError::new("couldn't read attributes", &path)))?;
+                continue;
+            }
+        };
+
+        // Directory reparse points on Windows (e.g., junctions) look like directories,
+        // so check for links before recursing.
+        if crate::platform::is_link(&attr) {
+            visit(Ok(path))?;
+            continue;
+        }
+
+        let other_filesystem = device.is_some() && crate::platform::device_id(&attr) != device;
+        let hidden = options.skip_hidden && crate::platform::is_hidden(&path, &attr);
+
+        if !other_filesystem && !hidden && !options.prune_dirs.contains(&entry.file_name()) {
+            walk_dir(&path, options, device, visit)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    // A tree of files, each holding its own path, in a directory which is removed
+    // once it's dropped.
+    fn temp_tree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        for file in files {
+            let path = dir.path().join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, file).unwrap();
+        }
+
+        dir
+    }
+
+    fn walk_names(root: &Path, prune_dirs: &[&str]) -> Vec<String> {
+        walk_names_with(
+            root,
+            WalkOptions {
+                prune_dirs: prune_dirs.iter().map(OsString::from).collect(),
+                one_filesystem: false,
+                skip_hidden: false,
+                deadline: None,
+                interrupted: || false,
+            },
+        )
+    }
+
+    fn walk_names_with(root: &Path, options: WalkOptions) -> Vec<String> {
+        let mut seen = Vec::new();
+
+        walk(root, &options, &mut |path| {
+            let path = path?;
+            let relative = path.strip_prefix(root).unwrap().to_str().unwrap();
+            seen.push(relative.replace('\\', "/"));
+            Ok(())
+        })
+        .unwrap();
+
+        seen
+    }
+
+    #[test]
+    fn walks_recursively_in_sorted_order() {
+        let dir = temp_tree(&["b", "a/y", "a/x/z"]);
+        let root = dir.path();
+        assert_eq!(walk_names(root, &[]), vec!["a/x/z", "a/y", "b"]);
+    }
+
+    #[test]
+    fn pruned_directories_are_skipped_anywhere() {
+        let dir = temp_tree(&[
+            "keep",
+            ".git/config",
+            "src/.git/head",
+            "src/main",
+            "target/x",
+        ]);
+        let root = dir.path();
+        assert_eq!(
+            walk_names(root, &[".git", "target"]),
+            vec!["keep", "src/main"]
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn hidden_directories_are_skipped() {
+        let dir = temp_tree(&["a", ".b", ".c/d", "e/.f/g", "e/h"]);
+        let root = dir.path();
+        let options = WalkOptions {
+            prune_dirs: Vec::new(),
+            one_filesystem: false,
+            skip_hidden: true,
+            deadline: None,
+            interrupted: || false,
+        };
+
+        // Hidden files are left to the visitor, which has their attributes.
+        assert_eq!(walk_names_with(root, options), vec![".b", "a", "e/h"]);
+        assert_eq!(walk_names(root, &[]).len(), 5);
+    }
+
+    #[test]
+    fn one_filesystem_walks_the_starting_filesystem() {
+        let dir = temp_tree(&["b", "a/y", "a/x/z"]);
+        let root = dir.path();
+        let options = WalkOptions {
+            prune_dirs: Vec::new(),
+            one_filesystem: true,
+            skip_hidden: false,
+            deadline: None,
+            interrupted: || false,
+        };
+        let mut count = 0;
+
+        walk(root, &options, &mut |path| {
+            path?;
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn errors_keep_the_path_they_happened_on() {
+        let missing = std::env::temp_dir().join("fddup-walk-missing/nested");
+        let options = WalkOptions {
+            prune_dirs: Vec::new(),
+            one_filesystem: false,
+            skip_hidden: false,
+            deadline: None,
+            interrupted: || false,
+        };
+        let mut paths = Vec::new();
+
+        walk(&missing, &options, &mut |path| {
+            let e = path.unwrap_err();
+            assert!(e.to_string().starts_with("couldn't read directory: "));
+            paths.push(error_path(&e).map(Path::to_path_buf));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(paths, vec![Some(missing)]);
+        assert_eq!(error_path(&anyhow::anyhow!("other")), None);
+    }
+}